@@ -0,0 +1,121 @@
+use criterion::{criterion_group, criterion_main, Criterion};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use raytracer::aabb::AxisAlignedBoundingBox;
+use raytracer::hittable::HitRecord;
+use raytracer::ray::Ray;
+use raytracer::scenes::random_scene;
+use raytracer::{BvhSplit, CameraSettings, Hittable, Sampler, SamplerKind};
+
+/// Camera rays through random points of the image, like the first bounce of a render.
+fn camera_rays(count: usize) -> Vec<Ray> {
+    let camera = CameraSettings::default().build(1.5);
+    let mut rng = StdRng::seed_from_u64(0);
+    let mut sampler = Sampler::new(SamplerKind::Random, 1, 0);
+    (0..count)
+        .map(|i| {
+            sampler.start_pixel_sample(i as u32, 0, 0);
+            camera.get_ray(rng.gen(), rng.gen(), &mut sampler)
+        })
+        .collect()
+}
+
+/// The pointer based, recursively traversed BVH that the flat node array replaced,
+/// kept as the baseline for the speedup of flattening.
+enum RecursiveBvh {
+    Leaf(Hittable),
+    Node {
+        bounding_box: AxisAlignedBoundingBox,
+        left: Box<RecursiveBvh>,
+        right: Box<RecursiveBvh>,
+    },
+}
+
+impl RecursiveBvh {
+    /// Splits at the median along the longest axis, like the flat median BVH without an rng.
+    fn new(mut primitives: Vec<(Hittable, AxisAlignedBoundingBox)>) -> Self {
+        if primitives.len() == 1 {
+            return Self::Leaf(primitives.pop().unwrap().0);
+        }
+        let bounding_box = primitives
+            .iter()
+            .skip(1)
+            .fold(primitives[0].1.clone(), |acc, (_, aabb)| {
+                acc.surrounding_box(aabb)
+            });
+        let extent = bounding_box.maximum - bounding_box.minimum;
+        let axis = if extent[0] > extent[1] && extent[0] > extent[2] {
+            0
+        } else if extent[1] > extent[2] {
+            1
+        } else {
+            2
+        };
+        primitives.sort_by(|(_, a), (_, b)| a.compare(b, axis));
+        let right = primitives.split_off(primitives.len() / 2);
+        Self::Node {
+            bounding_box,
+            left: Box::new(Self::new(primitives)),
+            right: Box::new(Self::new(right)),
+        }
+    }
+
+    fn hit<R: Rng>(&self, r: &Ray, t_min: f64, t_max: f64, rng: &mut R) -> Option<HitRecord<'_>> {
+        match *self {
+            Self::Leaf(ref primitive) => primitive.hit(r, t_min, t_max, rng),
+            Self::Node {
+                ref bounding_box,
+                ref left,
+                ref right,
+            } => {
+                if !bounding_box.hit(r, t_min, t_max) {
+                    return None;
+                }
+                let left = left.hit(r, t_min, t_max, rng);
+                let t_max = left.as_ref().map_or(t_max, |hit| hit.t);
+                right.hit(r, t_min, t_max, rng).or(left)
+            }
+        }
+    }
+}
+
+fn bench_random_scene(c: &mut Criterion) {
+    let rays = camera_rays(10_000);
+    let mut group = c.benchmark_group("random_scene");
+
+    let (world, _) = random_scene();
+    let recursive = RecursiveBvh::new(
+        world
+            .into_iter()
+            .map(|h| {
+                let aabb = h.bounding_box(0.0, 1.0).unwrap();
+                (h, aabb)
+            })
+            .collect(),
+    );
+    group.bench_function("recursive median", |b| {
+        let mut rng = StdRng::seed_from_u64(0);
+        b.iter(|| {
+            rays.iter()
+                .filter(|r| recursive.hit(r, 0.001, f64::INFINITY, &mut rng).is_some())
+                .count()
+        })
+    });
+
+    for split in [BvhSplit::Median, BvhSplit::Sah] {
+        let (mut world, _) = random_scene();
+        let bvh = Hittable::new_bvh_with_split(&mut world, 0.0, 1.0, split, None);
+        group.bench_function(format!("flat {}", split), |b| {
+            let mut rng = StdRng::seed_from_u64(0);
+            b.iter(|| {
+                rays.iter()
+                    .filter(|r| bvh.hit(r, 0.001, f64::INFINITY, &mut rng).is_some())
+                    .count()
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_random_scene);
+criterion_main!(benches);
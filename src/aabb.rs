@@ -14,23 +14,51 @@ impl AxisAlignedBoundingBox {
         Self { minimum, maximum }
     }
 
-    pub fn hit(&self, r: &Ray, mut t_min: f64, mut t_max: f64) -> bool {
+    pub fn hit(&self, r: &Ray, t_min: f64, t_max: f64) -> bool {
+        self.hit_inverse(&r.origin, &r.direction.map(|d| 1.0 / d), t_min, t_max)
+    }
+
+    /// Like `hit`, with the reciprocal ray direction computed once for many boxes.
+    pub fn hit_inverse(
+        &self,
+        origin: &Point,
+        inverse_direction: &Vector,
+        t_min: f64,
+        t_max: f64,
+    ) -> bool {
+        self.clip_inverse(origin, inverse_direction, t_min, t_max)
+            .is_some()
+    }
+
+    /// The part of the ray between `t_min` and `t_max` that lies within the box, if any.
+    pub fn clip(&self, r: &Ray, t_min: f64, t_max: f64) -> Option<(f64, f64)> {
+        self.clip_inverse(&r.origin, &r.direction.map(|d| 1.0 / d), t_min, t_max)
+    }
+
+    /// Like `clip`, with the reciprocal ray direction computed once for many boxes.
+    pub fn clip_inverse(
+        &self,
+        origin: &Point,
+        inverse_direction: &Vector,
+        mut t_min: f64,
+        mut t_max: f64,
+    ) -> Option<(f64, f64)> {
         for i in 0..3 {
-            let inv_d = 1.0 / r.direction[i];
+            let inv_d = inverse_direction[i];
             // Calculate the time when the ray is in the region for this axis.
-            let mut t0 = (self.minimum[i] - r.origin[i]) * inv_d;
-            let mut t1 = (self.maximum[i] - r.origin[i]) * inv_d;
+            let mut t0 = (self.minimum[i] - origin[i]) * inv_d;
+            let mut t1 = (self.maximum[i] - origin[i]) * inv_d;
             if inv_d < 0.0 {
                 mem::swap(&mut t0, &mut t1);
             }
             t_min = t0.max(t_min);
             t_max = t1.min(t_max);
             // if t_max ever gets smaller then t_min we do not have a hit.
-            if t_max <= t_min {
-                return false;
+            if t_max < t_min {
+                return None;
             }
         }
-        true
+        Some((t_min, t_max))
     }
 
     pub fn surrounding_box(&self, other: &Self) -> Self {
@@ -44,6 +72,65 @@ impl AxisAlignedBoundingBox {
         Self::new(minimum, maximum)
     }
 
+    /// The box shared by both boxes, which is empty when they don't overlap.
+    pub fn overlap(&self, other: &Self) -> Self {
+        let minimum = Point::from_map(|i| self.minimum[i].max(other.minimum[i]));
+        let maximum = Point::from_map(|i| self.maximum[i].min(other.maximum[i]).max(minimum[i]));
+        Self::new(minimum, maximum)
+    }
+
+    /// Returns a box that is at least `delta` thick along every axis.
+    /// Flat primitives like triangles otherwise produce boxes that no ray can hit.
+    pub fn pad(&self, delta: f64) -> Self {
+        let mut minimum = self.minimum;
+        let mut maximum = self.maximum;
+
+        for i in 0..3 {
+            if maximum[i] - minimum[i] < delta {
+                minimum[i] -= delta / 2.0;
+                maximum[i] += delta / 2.0;
+            }
+        }
+        Self::new(minimum, maximum)
+    }
+
+    /// The smallest box that contains all `points`.
+    pub fn from_points<I>(points: I) -> Option<Self>
+    where
+        I: IntoIterator<Item = Point>,
+    {
+        points.into_iter().fold(None, |acc, p| {
+            Some(match acc {
+                Some(aabb) => Self::new(
+                    Point::from_map(|i| aabb.minimum[i].min(p[i])),
+                    Point::from_map(|i| aabb.maximum[i].max(p[i])),
+                ),
+                None => Self::new(p, p),
+            })
+        })
+    }
+
+    pub fn corners(&self) -> [Point; 8] {
+        [0, 1, 2, 3, 4, 5, 6, 7].map(|i: usize| {
+            Point::from_map(|axis| {
+                if i & (1 << axis) == 0 {
+                    self.minimum[axis]
+                } else {
+                    self.maximum[axis]
+                }
+            })
+        })
+    }
+
+    pub fn surface_area(&self) -> f64 {
+        let extent = self.maximum - self.minimum;
+        2.0 * (extent[0] * extent[1] + extent[1] * extent[2] + extent[2] * extent[0])
+    }
+
+    pub fn centroid(&self) -> Point {
+        (self.minimum + self.maximum) / 2.0
+    }
+
     pub fn compare(&self, other: &Self, axis: usize) -> Ordering {
         self.minimum[axis]
             .partial_cmp(&other.minimum[axis])
@@ -1,10 +1,13 @@
 use rand::Rng;
+pub use vecmat::mat::*;
 pub use vecmat::vec::*;
 
 pub type Color = Vec3<f64>;
 pub type Point = Vec3<f64>;
 pub type Vector = Vec3<f64>;
 pub type Quaternion = Vec4<f64>;
+/// An affine transform in homogeneous coordinates, applied to column vectors.
+pub type Matrix = Mat4<f64>;
 
 pub trait Vec2Ext<T>
 where
@@ -24,12 +27,20 @@ pub trait Vec3Ext<T>
 where
     T: Copy + Default,
 {
-    fn random_vector() -> Vec3<T>;
-    fn random_in_unit_sphere() -> Vec3<T>;
-    fn random_unit_vector() -> Vec3<T>;
-    fn random_in_unit_disk() -> Vec3<T>;
-    fn random_cosine_direction() -> Vec3<T>;
-    fn random_to_sphere(radius: f64, distance_squared: f64) -> Vec3<T>;
+    fn random_vector<R: Rng + ?Sized>(rng: &mut R) -> Vec3<T>;
+    fn random_in_unit_sphere<R: Rng + ?Sized>(rng: &mut R) -> Vec3<T>;
+    fn random_unit_vector<R: Rng + ?Sized>(rng: &mut R) -> Vec3<T>;
+    fn random_in_unit_disk<R: Rng + ?Sized>(rng: &mut R) -> Vec3<T>;
+    fn random_cosine_direction<R: Rng + ?Sized>(rng: &mut R) -> Vec3<T>;
+    fn random_to_sphere<R: Rng + ?Sized>(
+        radius: f64,
+        distance_squared: f64,
+        rng: &mut R,
+    ) -> Vec3<T>;
+    fn sample_in_unit_sphere(u: Vec2<f64>, radius: f64) -> Vec3<T>;
+    fn sample_in_unit_disk(u: Vec2<f64>) -> Vec3<T>;
+    fn sample_cosine_direction(u: Vec2<f64>) -> Vec3<T>;
+    fn sample_to_sphere(radius: f64, distance_squared: f64, u: Vec2<f64>) -> Vec3<T>;
     fn near_zero(&self) -> bool;
     fn reflect(&self, n: &Vector) -> Vector;
     fn refract(&self, n: &Vector, etai_over_etat: f64) -> Vector;
@@ -37,12 +48,11 @@ where
 }
 
 impl Vec3Ext<f64> for Vec3<f64> {
-    fn random_vector() -> Vector {
-        Self::from(rand::random(), rand::random(), rand::random())
+    fn random_vector<R: Rng + ?Sized>(rng: &mut R) -> Vector {
+        Self::from(rng.gen(), rng.gen(), rng.gen())
     }
 
-    fn random_in_unit_sphere() -> Point {
-        let mut rng = rand::thread_rng();
+    fn random_in_unit_sphere<R: Rng + ?Sized>(rng: &mut R) -> Point {
         loop {
             let p = Point::from(
                 rng.gen_range(-1.0..1.0),
@@ -55,12 +65,11 @@ impl Vec3Ext<f64> for Vec3<f64> {
         }
     }
 
-    fn random_unit_vector() -> Vector {
-        Self::random_in_unit_sphere().normalize()
+    fn random_unit_vector<R: Rng + ?Sized>(rng: &mut R) -> Vector {
+        Self::random_in_unit_sphere(rng).normalize()
     }
 
-    fn random_in_unit_disk() -> Point {
-        let mut rng = rand::thread_rng();
+    fn random_in_unit_disk<R: Rng + ?Sized>(rng: &mut R) -> Point {
         loop {
             let p = Point::from(rng.gen_range(-1.0..1.0), rng.gen_range(-1.0..1.0), 0.0);
             if p.sqrlen() < 1.0 {
@@ -69,10 +78,46 @@ impl Vec3Ext<f64> for Vec3<f64> {
         }
     }
 
-    fn random_cosine_direction() -> Vector {
-        let mut rng = rand::thread_rng();
-        let r1 = rng.gen::<f64>();
-        let r2 = rng.gen::<f64>();
+    fn random_cosine_direction<R: Rng + ?Sized>(rng: &mut R) -> Vector {
+        Self::sample_cosine_direction(Vec2::from(rng.gen(), rng.gen()))
+    }
+
+    fn random_to_sphere<R: Rng + ?Sized>(
+        radius: f64,
+        distance_squared: f64,
+        rng: &mut R,
+    ) -> Vector {
+        Self::sample_to_sphere(radius, distance_squared, Vec2::from(rng.gen(), rng.gen()))
+    }
+
+    /// Maps a sample in [0,1)^2 and a radius sample to a point inside the unit sphere.
+    fn sample_in_unit_sphere(u: Vec2<f64>, radius: f64) -> Point {
+        let z = 1.0 - 2.0 * u[0];
+        let r = (1.0 - z * z).max(0.0).sqrt();
+        let phi = 2.0 * std::f64::consts::PI * u[1];
+        radius.cbrt() * Point::from(r * phi.cos(), r * phi.sin(), z)
+    }
+
+    /// Shirley's concentric mapping of a sample in [0,1)^2 to the unit disk, keeps strata intact.
+    fn sample_in_unit_disk(u: Vec2<f64>) -> Point {
+        let a = 2.0 * u[0] - 1.0;
+        let b = 2.0 * u[1] - 1.0;
+        if a == 0.0 && b == 0.0 {
+            return Point::new();
+        }
+        let (r, theta) = if a.abs() > b.abs() {
+            (a, std::f64::consts::FRAC_PI_4 * (b / a))
+        } else {
+            (
+                b,
+                std::f64::consts::FRAC_PI_2 - std::f64::consts::FRAC_PI_4 * (a / b),
+            )
+        };
+        Point::from(r * theta.cos(), r * theta.sin(), 0.0)
+    }
+
+    fn sample_cosine_direction(u: Vec2<f64>) -> Vector {
+        let (r1, r2) = (u[0], u[1]);
         let z = (1.0 - r2).sqrt();
 
         let phi = 2.0 * std::f64::consts::PI * r1;
@@ -81,10 +126,8 @@ impl Vec3Ext<f64> for Vec3<f64> {
         Vector::from(x, y, z)
     }
 
-    fn random_to_sphere(radius: f64, distance_squared: f64) -> Vector {
-        let mut rng = rand::thread_rng();
-        let r1 = rng.gen::<f64>();
-        let r2 = rng.gen::<f64>();
+    fn sample_to_sphere(radius: f64, distance_squared: f64, u: Vec2<f64>) -> Vector {
+        let (r1, r2) = (u[0], u[1]);
         let z = 1.0 + r2 * ((1.0 - radius * radius / distance_squared).sqrt() - 1.0);
 
         let phi = 2.0 * std::f64::consts::PI * r1;
@@ -146,7 +189,94 @@ impl Vec4Ext<f64> for Vec4<f64> {
     }
 }
 
+pub trait Mat4Ext {
+    fn translation(offset: Vector) -> Matrix;
+    fn scaling(scale: Vector) -> Matrix;
+    fn rotation(angle: f64, axis: Vector) -> Matrix;
+    fn shearing(xy: f64, xz: f64, yx: f64, yz: f64, zx: f64, zy: f64) -> Matrix;
+    fn from_trs(translation: Vector, angle: f64, axis: Vector, scale: Vector) -> Matrix;
+    fn from_rows(rows: [[f64; 4]; 4]) -> Matrix;
+    fn transform_point(&self, p: &Point) -> Point;
+    fn transform_vector(&self, v: &Vector) -> Vector;
+}
+
+impl Mat4Ext for Matrix {
+    #[rustfmt::skip]
+    fn translation(offset: Vector) -> Matrix {
+        Matrix::from(
+            1.0, 0.0, 0.0, offset[0],
+            0.0, 1.0, 0.0, offset[1],
+            0.0, 0.0, 1.0, offset[2],
+            0.0, 0.0, 0.0, 1.0,
+        )
+    }
+
+    #[rustfmt::skip]
+    fn scaling(scale: Vector) -> Matrix {
+        Matrix::from(
+            scale[0], 0.0, 0.0, 0.0,
+            0.0, scale[1], 0.0, 0.0,
+            0.0, 0.0, scale[2], 0.0,
+            0.0, 0.0, 0.0, 1.0,
+        )
+    }
+
+    /// Rotates counterclockwise by `angle` radians around `axis`, seen from the tip of `axis`.
+    #[rustfmt::skip]
+    fn rotation(angle: f64, axis: Vector) -> Matrix {
+        let k = axis.normalize();
+        let (s, c) = angle.sin_cos();
+        let t = 1.0 - c;
+        Matrix::from(
+            c + k[0] * k[0] * t, k[0] * k[1] * t - k[2] * s, k[0] * k[2] * t + k[1] * s, 0.0,
+            k[1] * k[0] * t + k[2] * s, c + k[1] * k[1] * t, k[1] * k[2] * t - k[0] * s, 0.0,
+            k[2] * k[0] * t - k[1] * s, k[2] * k[1] * t + k[0] * s, c + k[2] * k[2] * t, 0.0,
+            0.0, 0.0, 0.0, 1.0,
+        )
+    }
+
+    /// Shifts every coordinate in proportion to the others, `xy` moves x by `xy * y`.
+    #[rustfmt::skip]
+    fn shearing(xy: f64, xz: f64, yx: f64, yz: f64, zx: f64, zy: f64) -> Matrix {
+        Matrix::from(
+            1.0, xy, xz, 0.0,
+            yx, 1.0, yz, 0.0,
+            zx, zy, 1.0, 0.0,
+            0.0, 0.0, 0.0, 1.0,
+        )
+    }
+
+    /// Scales first, then rotates and finally translates.
+    fn from_trs(translation: Vector, angle: f64, axis: Vector, scale: Vector) -> Matrix {
+        Matrix::translation(translation)
+            .dot(Matrix::rotation(angle, axis))
+            .dot(Matrix::scaling(scale))
+    }
+
+    #[rustfmt::skip]
+    fn from_rows(rows: [[f64; 4]; 4]) -> Matrix {
+        let [a, b, c, d] = rows;
+        Matrix::from(
+            a[0], a[1], a[2], a[3],
+            b[0], b[1], b[2], b[3],
+            c[0], c[1], c[2], c[3],
+            d[0], d[1], d[2], d[3],
+        )
+    }
+
+    fn transform_point(&self, p: &Point) -> Point {
+        let p = self.dot(Vec4::from(p[0], p[1], p[2], 1.0));
+        Point::from(p[0], p[1], p[2]) / p[3]
+    }
+
+    fn transform_vector(&self, v: &Vector) -> Vector {
+        let v = self.dot(Vec4::from(v[0], v[1], v[2], 0.0));
+        Vector::from(v[0], v[1], v[2])
+    }
+}
+
 /// Orthonormal Bases
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Clone)]
 pub struct ONB([Vector; 3]);
 
@@ -195,13 +325,37 @@ mod tests {
     #[test_case( 90.0, Vector::from(0.0, 1.0, 0.0), Vector::from(0.0, 0.0, -1.0))]
     #[test_case( 180.0, Vector::from(0.0, 1.0, 0.0), Vector::from(-1.0, 0.0, 0.0))]
     #[test_case(270.0, Vector::from(0.0, 1.0, 0.0), Vector::from(0.0, 0.0, 1.0))]
-    #[test_case( 45.0, Vector::from(0.0, 1.0, 0.0), Vector::from(0.7071067811865475, 0.0, -0.7071067811865476))]
+    #[test_case( 45.0, Vector::from(0.0, 1.0, 0.0), Vector::from(std::f64::consts::FRAC_1_SQRT_2, 0.0, -std::f64::consts::FRAC_1_SQRT_2))]
     fn test_rotations(angle: f64, axis: Vector, result: Vector) {
         let q = Quaternion::new_quaternion(angle.to_radians(), axis);
         let p = Vector::from(1.0, 0.0, 0.0);
         assert!((p.rotate(&q) - result).near_zero());
     }
 
+    #[test_case(Vector::from(1.0, 0.0, 0.0), Vector::from(0.0, 0.0, -1.0))]
+    #[test_case(Vector::from(0.0, 0.0, 1.0), Vector::from(1.0, 0.0, 0.0))]
+    #[test_case(Vector::from(0.0, 1.0, 0.0), Vector::from(0.0, 1.0, 0.0))]
+    fn test_rotation_matrix(v: Vector, result: Vector) {
+        let m = Matrix::rotation(90.0f64.to_radians(), Vector::from(0.0, 1.0, 0.0));
+        assert!((m.transform_vector(&v) - result).near_zero());
+    }
+
+    #[test]
+    fn test_trs_applies_scale_rotation_translation() {
+        let m = Matrix::from_trs(
+            Vector::from(1.0, 2.0, 3.0),
+            90.0f64.to_radians(),
+            Vector::from(0.0, 0.0, 1.0),
+            Vector::from(2.0, 1.0, 1.0),
+        );
+        let p = m.transform_point(&Point::from(1.0, 0.0, 0.0));
+        assert!((p - Point::from(1.0, 4.0, 3.0)).near_zero());
+        assert!((m.inverse().transform_point(&p) - Point::from(1.0, 0.0, 0.0)).near_zero());
+        // Vectors ignore the translation.
+        let v = m.transform_vector(&Vector::from(1.0, 0.0, 0.0));
+        assert!((v - Vector::from(0.0, 2.0, 0.0)).near_zero());
+    }
+
     use proptest::prelude::*;
     proptest! {
 
@@ -212,5 +366,11 @@ mod tests {
             let p = Vector::from(0.0, 1.0, 0.0);
             prop_assert!((p.rotate(&q) - p).near_zero());
         }
+
+        #[test]
+        fn test_sample_in_unit_disk(u in 0.0f64..1.0, v in 0.0f64..1.0) {
+            let p = Point::sample_in_unit_disk(Vec2::from(u, v));
+            prop_assert!(p.sqrlen() <= 1.0 + 1e-12 && p[2] == 0.0);
+        }
     }
 }
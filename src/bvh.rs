@@ -0,0 +1,842 @@
+use crate::aabb::AxisAlignedBoundingBox;
+use crate::alias::*;
+use crate::hittable::*;
+use crate::ray::*;
+use rand::{Rng, RngCore};
+use std::error::Error;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Cost of visiting a BVH node, relative to intersecting a primitive.
+const TRAVERSAL_COST: f64 = 0.5;
+const INTERSECTION_COST: f64 = 1.0;
+
+/// Number of buckets the centroids are sorted into when looking for a split.
+const BINS: usize = 16;
+
+/// Maximum number of primitives in a leaf built with the surface area heuristic.
+const MAX_LEAF_SIZE: usize = 4;
+
+/// Size of the traversal stack, no BVH is deeper than this.
+pub(crate) const MAX_DEPTH: usize = 64;
+
+/// Past this depth the builder halves every node, which bounds the total depth
+/// by `BALANCED_DEPTH` plus the logarithm of the number of primitives.
+const BALANCED_DEPTH: usize = 32;
+
+/// How the BVH builder splits a set of primitives in two.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BvhSplit {
+    /// Sorts along a random axis, or the longest one without a random number generator,
+    /// and splits at the median.
+    Median,
+    /// Binned surface area heuristic, minimizes the expected cost of tracing a ray.
+    Sah,
+}
+
+impl FromStr for BvhSplit {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "median" => Ok(Self::Median),
+            "sah" => Ok(Self::Sah),
+            _ => Err(format!("unknown BVH split '{}'", s)),
+        }
+    }
+}
+
+impl fmt::Display for BvhSplit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            Self::Median => "median",
+            Self::Sah => "sah",
+        })
+    }
+}
+
+/// Shape and quality of a built BVH.
+#[derive(Clone, Debug, Default)]
+pub struct BvhStats {
+    pub build_time: Duration,
+    /// Number of interior nodes.
+    pub node_count: usize,
+    pub leaf_count: usize,
+    pub depth: usize,
+    /// Expected cost of tracing a ray through the tree, according to the surface area heuristic.
+    pub cost: f64,
+}
+
+impl fmt::Display for BvhStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "built in {:.2} ms, {} nodes, {} leaves, depth {}, estimated cost {:.2}",
+            self.build_time.as_secs_f64() * 1000.0,
+            self.node_count,
+            self.leaf_count,
+            self.depth,
+            self.cost
+        )
+    }
+}
+
+/// Builds a BVH over `hittables` and measures the result.
+pub fn build_bvh(
+    hittables: &mut [Hittable],
+    time_start: f64,
+    time_end: f64,
+    split: BvhSplit,
+    rng: Option<&mut dyn RngCore>,
+) -> (Hittable, BvhStats) {
+    let now = Instant::now();
+    let bvh = Hittable::new_bvh_with_split(hittables, time_start, time_end, split, rng);
+    let build_time = now.elapsed();
+    let stats = stats_of(&bvh);
+    (
+        bvh,
+        BvhStats {
+            build_time,
+            ..stats
+        },
+    )
+}
+
+fn stats_of(hittable: &Hittable) -> BvhStats {
+    match *hittable {
+        Hittable::Bvh(ref bvh) => bvh.stats(),
+        Hittable::Instances(ref instances) => instances.stats(),
+        Hittable::Empty => BvhStats::default(),
+        // Unbounded primitives are listed next to the BVH over the others.
+        Hittable::List(ref list) => {
+            list.iter()
+                .map(stats_of)
+                .fold(BvhStats::default(), |a, b| BvhStats {
+                    node_count: a.node_count + b.node_count,
+                    leaf_count: a.leaf_count + b.leaf_count,
+                    depth: a.depth.max(b.depth),
+                    cost: a.cost + b.cost,
+                    ..a
+                })
+        }
+        // A single primitive isn't wrapped in a BVH.
+        _ => BvhStats {
+            leaf_count: 1,
+            depth: 1,
+            cost: INTERSECTION_COST,
+            ..BvhStats::default()
+        },
+    }
+}
+
+#[derive(Clone)]
+enum BvhNode {
+    /// Primitives `start..end` of the (reordered) primitive list.
+    Leaf {
+        bounding_box: AxisAlignedBoundingBox,
+        start: usize,
+        end: usize,
+    },
+    /// The first child directly follows its parent, `second` is the index of the other one.
+    /// The first child holds the primitives with the smaller coordinates along `axis`.
+    Interior {
+        bounding_box: AxisAlignedBoundingBox,
+        second: usize,
+        axis: usize,
+    },
+}
+
+impl BvhNode {
+    fn bounding_box(&self) -> &AxisAlignedBoundingBox {
+        match *self {
+            Self::Leaf {
+                ref bounding_box, ..
+            } => bounding_box,
+            Self::Interior {
+                ref bounding_box, ..
+            } => bounding_box,
+        }
+    }
+}
+
+/// The nodes of a bounding volume hierarchy flattened into a contiguous array in depth-first
+/// order, traversed with an explicit stack instead of recursion. Leaves refer to ranges of the
+/// primitives in the order returned by `new`. Shared by the scene BVH, instances and meshes.
+#[derive(Clone)]
+pub(crate) struct BvhNodes {
+    nodes: Vec<BvhNode>,
+}
+
+impl BvhNodes {
+    /// Builds the nodes over primitives with the given bounding boxes, which must not be empty.
+    /// Also returns the primitive indices in the order the leaves refer to them.
+    pub(crate) fn new(
+        boxes: Vec<AxisAlignedBoundingBox>,
+        split: BvhSplit,
+        mut rng: Option<&mut dyn RngCore>,
+    ) -> (Self, Vec<usize>) {
+        let mut items = boxes.into_iter().enumerate().collect::<Vec<_>>();
+        let mut nodes = Self {
+            nodes: Vec::with_capacity(2 * items.len()),
+        };
+        nodes.build(&mut items, 0, 1, split, &mut rng);
+        (nodes, items.into_iter().map(|(i, _)| i).collect())
+    }
+
+    /// Recursively builds the nodes over `items`, which start at `offset` in the final primitive list.
+    fn build(
+        &mut self,
+        items: &mut [(usize, AxisAlignedBoundingBox)],
+        offset: usize,
+        depth: usize,
+        split: BvhSplit,
+        rng: &mut Option<&mut dyn RngCore>,
+    ) {
+        let bounding_box = items
+            .iter()
+            .skip(1)
+            .fold(items[0].1.clone(), |acc, (_, aabb)| {
+                acc.surrounding_box(aabb)
+            });
+        let leaf = BvhNode::Leaf {
+            bounding_box: bounding_box.clone(),
+            start: offset,
+            end: offset + items.len(),
+        };
+        if items.len() == 1 {
+            self.nodes.push(leaf);
+            return;
+        }
+
+        let (mid, axis) = if depth >= BALANCED_DEPTH {
+            // Deep subtrees are halved, so the depth never exceeds the traversal stack.
+            let axis = longest_axis(&bounding_box);
+            let mid = items.len() / 2;
+            items.select_nth_unstable_by(mid, |(_, a), (_, b)| {
+                a.centroid()[axis].total_cmp(&b.centroid()[axis])
+            });
+            (mid, axis)
+        } else {
+            match split {
+                BvhSplit::Median => {
+                    let axis = match rng {
+                        Some(ref mut rng) => rng.gen_range(0..3),
+                        None => longest_axis(&bounding_box),
+                    };
+                    items.sort_by(|(_, a), (_, b)| a.compare(b, axis));
+                    (items.len() / 2, axis)
+                }
+                BvhSplit::Sah => {
+                    match partition_sah(items, |(_, aabb)| aabb.clone(), MAX_LEAF_SIZE) {
+                        Some(split) => split,
+                        // Primitives with identical centroids can't be told apart, split them anywhere.
+                        None if items.len() > MAX_LEAF_SIZE => {
+                            (items.len() / 2, longest_axis(&bounding_box))
+                        }
+                        None => {
+                            self.nodes.push(leaf);
+                            return;
+                        }
+                    }
+                }
+            }
+        };
+
+        let index = self.nodes.len();
+        self.nodes.push(BvhNode::Interior {
+            bounding_box,
+            second: 0,
+            axis,
+        });
+        let (first, second) = items.split_at_mut(mid);
+        self.build(first, offset, depth + 1, split, rng);
+        let second_index = self.nodes.len();
+        self.build(second, offset + mid, depth + 1, split, rng);
+        if let BvhNode::Interior { ref mut second, .. } = self.nodes[index] {
+            *second = second_index;
+        }
+    }
+
+    pub(crate) fn bounding_box(&self) -> &AxisAlignedBoundingBox {
+        self.nodes[0].bounding_box()
+    }
+
+    /// Finds the closest hit, `hit_primitive` intersects the primitive at a position in the
+    /// leaf order up to the closest hit so far.
+    pub(crate) fn hit<'a, F>(
+        &self,
+        r: &Ray,
+        t_min: f64,
+        t_max: f64,
+        mut hit_primitive: F,
+    ) -> Option<HitRecord<'a>>
+    where
+        F: FnMut(usize, f64) -> Option<HitRecord<'a>>,
+    {
+        let inverse_direction = r.direction.map(|d| 1.0 / d);
+        let mut stack = [0; MAX_DEPTH];
+        let mut stack_size = 0;
+        let mut index = 0;
+        let mut closest_so_far = t_max;
+        let mut record = None;
+        loop {
+            let node = &self.nodes[index];
+            if node
+                .bounding_box()
+                .hit_inverse(&r.origin, &inverse_direction, t_min, closest_so_far)
+            {
+                match *node {
+                    BvhNode::Leaf { start, end, .. } => {
+                        for i in start..end {
+                            if let Some(hit) = hit_primitive(i, closest_so_far) {
+                                closest_so_far = hit.t;
+                                record = Some(hit);
+                            }
+                        }
+                    }
+                    BvhNode::Interior { second, axis, .. } => {
+                        // Visit the nearer child first, so a hit in it can cull the farther one.
+                        let (near, far) = if inverse_direction[axis] < 0.0 {
+                            (second, index + 1)
+                        } else {
+                            (index + 1, second)
+                        };
+                        stack[stack_size] = far;
+                        stack_size += 1;
+                        index = near;
+                        continue;
+                    }
+                }
+            }
+            if stack_size == 0 {
+                return record;
+            }
+            stack_size -= 1;
+            index = stack[stack_size];
+        }
+    }
+
+    pub(crate) fn stats(&self) -> BvhStats {
+        let mut stats = BvhStats::default();
+        self.collect_stats(0, self.bounding_box().surface_area(), 1, &mut stats);
+        stats
+    }
+
+    fn collect_stats(&self, index: usize, root_area: f64, depth: usize, stats: &mut BvhStats) {
+        stats.depth = stats.depth.max(depth);
+        let node = &self.nodes[index];
+        let probability = node.bounding_box().surface_area() / root_area;
+        match *node {
+            BvhNode::Leaf { start, end, .. } => {
+                stats.leaf_count += 1;
+                stats.cost += probability * INTERSECTION_COST * (end - start) as f64;
+            }
+            BvhNode::Interior { second, .. } => {
+                stats.node_count += 1;
+                stats.cost += probability * TRAVERSAL_COST;
+                self.collect_stats(index + 1, root_area, depth + 1, stats);
+                self.collect_stats(second, root_area, depth + 1, stats);
+            }
+        }
+    }
+}
+
+/// A bounding volume hierarchy over bounded primitives.
+#[derive(Clone)]
+pub struct Bvh {
+    nodes: BvhNodes,
+    primitives: Vec<Hittable>,
+}
+
+impl Bvh {
+    /// Builds the BVH over `primitives`, which must not be empty and must all be bounded.
+    /// `Hittable::new_bvh_with_split` checks this and lists unbounded primitives next to it.
+    pub(crate) fn new(
+        primitives: Vec<Hittable>,
+        time_start: f64,
+        time_end: f64,
+        split: BvhSplit,
+        rng: Option<&mut dyn RngCore>,
+    ) -> Self {
+        let boxes = primitives
+            .iter()
+            .map(|primitive| primitive.bounding_box(time_start, time_end).unwrap())
+            .collect();
+        let (nodes, order) = BvhNodes::new(boxes, split, rng);
+        let mut primitives = primitives.into_iter().map(Some).collect::<Vec<_>>();
+        Self {
+            nodes,
+            primitives: order
+                .into_iter()
+                .map(|i| primitives[i].take().unwrap())
+                .collect(),
+        }
+    }
+
+    pub fn bounding_box(&self) -> &AxisAlignedBoundingBox {
+        self.nodes.bounding_box()
+    }
+
+    pub fn hit<R: Rng + ?Sized>(
+        &self,
+        r: &Ray,
+        t_min: f64,
+        t_max: f64,
+        rng: &mut R,
+    ) -> Option<HitRecord<'_>> {
+        self.nodes.hit(r, t_min, t_max, |i, closest_so_far| {
+            self.primitives[i].hit(r, t_min, closest_so_far, rng)
+        })
+    }
+
+    pub fn stats(&self) -> BvhStats {
+        self.nodes.stats()
+    }
+}
+
+/// Returned when an `InstanceBvh` is asked for an instance it doesn't have.
+#[derive(Debug, Clone, PartialEq)]
+pub struct InstanceIndexError {
+    pub index: usize,
+    pub count: usize,
+}
+
+impl fmt::Display for InstanceIndexError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "instance {} is out of range, there are {} instances",
+            self.index, self.count
+        )
+    }
+}
+
+impl Error for InstanceIndexError {}
+
+/// A two-level acceleration structure. Every instance places a shared bottom level, usually a BVH
+/// or mesh built once in object space, with an affine matrix. Rays are moved into object space at
+/// the instance boundary, so moving instances only requires rebuilding the top level over them.
+#[derive(Clone)]
+pub struct InstanceBvh {
+    /// The instances in the order they were added.
+    instances: Vec<Transform>,
+    /// The top level over the bounded instances, `None` if there are none.
+    top: Option<BvhNodes>,
+    /// Indices of the instances in the leaf order of `top`.
+    bounded: Vec<usize>,
+    /// Indices of the instances without a bounding box, such as placed planes,
+    /// which are tested against every ray.
+    unbounded: Vec<usize>,
+    time_start: f64,
+    time_end: f64,
+}
+
+impl InstanceBvh {
+    /// Places every shared geometry with its matrix.
+    pub fn new(instances: Vec<(Arc<Hittable>, Matrix)>, time_start: f64, time_end: f64) -> Self {
+        let mut instance_bvh = Self {
+            instances: instances
+                .into_iter()
+                .map(|(geometry, matrix)| Transform::new(geometry, matrix))
+                .collect(),
+            top: None,
+            bounded: Vec::new(),
+            unbounded: Vec::new(),
+            time_start,
+            time_end,
+        };
+        instance_bvh.rebuild();
+        instance_bvh
+    }
+
+    /// The instances in the order they were added.
+    pub fn instances(&self) -> &[Transform] {
+        &self.instances
+    }
+
+    /// Moves instance `index`, the change is only visible after the next `rebuild`.
+    pub fn set_matrix(&mut self, index: usize, matrix: Matrix) -> Result<(), InstanceIndexError> {
+        let count = self.instances.len();
+        let instance = self
+            .instances
+            .get_mut(index)
+            .ok_or(InstanceIndexError { index, count })?;
+        *instance = Transform::new(instance.geometry().clone(), matrix);
+        Ok(())
+    }
+
+    /// Rebuilds the top level over the current instance matrices, the shared bottom levels are kept.
+    pub fn rebuild(&mut self) -> BvhStats {
+        let now = Instant::now();
+        let mut boxes = Vec::new();
+        self.bounded.clear();
+        self.unbounded.clear();
+        for (i, instance) in self.instances.iter().enumerate() {
+            match instance.bounding_box(self.time_start, self.time_end) {
+                Some(aabb) => {
+                    boxes.push(aabb);
+                    self.bounded.push(i);
+                }
+                None => self.unbounded.push(i),
+            }
+        }
+        self.top = if boxes.is_empty() {
+            None
+        } else {
+            let (top, order) = BvhNodes::new(boxes, BvhSplit::Sah, None);
+            self.bounded = order.into_iter().map(|i| self.bounded[i]).collect();
+            Some(top)
+        };
+        BvhStats {
+            build_time: now.elapsed(),
+            ..self.stats()
+        }
+    }
+
+    /// The bounds of all instances, `None` if there are none or one of them is unbounded.
+    pub fn bounding_box(&self) -> Option<AxisAlignedBoundingBox> {
+        if !self.unbounded.is_empty() {
+            return None;
+        }
+        self.top.as_ref().map(|top| top.bounding_box().clone())
+    }
+
+    pub fn hit<R: Rng + ?Sized>(
+        &self,
+        r: &Ray,
+        t_min: f64,
+        t_max: f64,
+        rng: &mut R,
+    ) -> Option<HitRecord<'_>> {
+        let mut record = self.top.as_ref().and_then(|top| {
+            top.hit(r, t_min, t_max, |i, closest_so_far| {
+                self.instances[self.bounded[i]].hit(r, t_min, closest_so_far, rng)
+            })
+        });
+        for &i in &self.unbounded {
+            let closest_so_far = record.as_ref().map_or(t_max, |hit| hit.t);
+            if let Some(hit) = self.instances[i].hit(r, t_min, closest_so_far, rng) {
+                record = Some(hit);
+            }
+        }
+        record
+    }
+
+    /// Statistics of the top level only.
+    pub fn stats(&self) -> BvhStats {
+        self.top.as_ref().map(BvhNodes::stats).unwrap_or_default()
+    }
+}
+
+fn longest_axis(bounding_box: &AxisAlignedBoundingBox) -> usize {
+    let extent = bounding_box.maximum - bounding_box.minimum;
+    if extent[0] > extent[1] && extent[0] > extent[2] {
+        0
+    } else if extent[1] > extent[2] {
+        1
+    } else {
+        2
+    }
+}
+
+fn surrounding_box(
+    a: Option<AxisAlignedBoundingBox>,
+    b: &AxisAlignedBoundingBox,
+) -> AxisAlignedBoundingBox {
+    match a {
+        Some(a) => a.surrounding_box(b),
+        None => b.clone(),
+    }
+}
+
+/// Reorders `items` for the split with the lowest surface area heuristic cost and returns the
+/// number of items that go to the left child, and the axis they are split along. Returns `None`
+/// if a leaf is cheaper and holds at most `max_leaf_size` items, or if the items can't be
+/// separated at all.
+fn partition_sah<T, F>(
+    items: &mut [T],
+    bounding_box: F,
+    max_leaf_size: usize,
+) -> Option<(usize, usize)>
+where
+    F: Fn(&T) -> AxisAlignedBoundingBox,
+{
+    let mut boxes = items.iter().map(bounding_box).collect::<Vec<_>>();
+    let bounds = boxes
+        .iter()
+        .fold(None, |acc, b| Some(surrounding_box(acc, b)))?;
+    let area = bounds.surface_area().max(f64::MIN_POSITIVE);
+    let centroids = boxes.iter().map(|b| b.centroid()).collect::<Vec<_>>();
+    let centroid_min = centroids
+        .iter()
+        .fold(Point::from_scalar(f64::INFINITY), |acc, c| {
+            Point::from_map(|i| acc[i].min(c[i]))
+        });
+    let centroid_max = centroids
+        .iter()
+        .fold(Point::from_scalar(f64::NEG_INFINITY), |acc, c| {
+            Point::from_map(|i| acc[i].max(c[i]))
+        });
+    let bin = |centroid: &Point, axis: usize| {
+        let extent = centroid_max[axis] - centroid_min[axis];
+        (((centroid[axis] - centroid_min[axis]) / extent * BINS as f64) as usize).min(BINS - 1)
+    };
+
+    // The cheapest (cost, axis, first bin of the right child).
+    let mut best: Option<(f64, usize, usize)> = None;
+    for axis in 0..3 {
+        if centroid_max[axis] <= centroid_min[axis] {
+            continue;
+        }
+        let mut counts = [0; BINS];
+        let mut bin_boxes = vec![None; BINS];
+        for (b, centroid) in boxes.iter().zip(&centroids) {
+            let i = bin(centroid, axis);
+            counts[i] += 1;
+            bin_boxes[i] = Some(surrounding_box(bin_boxes[i].take(), b));
+        }
+
+        // Sweep from the right first to know the cost of every right side.
+        let mut right_costs = [0.0; BINS];
+        let (mut right, mut right_count) = (None, 0);
+        for i in (1..BINS).rev() {
+            if let Some(ref b) = bin_boxes[i] {
+                right = Some(surrounding_box(right, b));
+            }
+            right_count += counts[i];
+            right_costs[i] = right
+                .as_ref()
+                .map_or(0.0, |b| right_count as f64 * b.surface_area());
+        }
+        let (mut left, mut left_count) = (None, 0);
+        for split in 1..BINS {
+            if let Some(ref b) = bin_boxes[split - 1] {
+                left = Some(surrounding_box(left, b));
+            }
+            left_count += counts[split - 1];
+            if left_count == 0 || left_count == items.len() {
+                continue;
+            }
+            let left_cost = left
+                .as_ref()
+                .map_or(0.0, |b| left_count as f64 * b.surface_area());
+            let cost = TRAVERSAL_COST + INTERSECTION_COST * (left_cost + right_costs[split]) / area;
+            if best.is_none_or(|(best_cost, _, _)| cost < best_cost) {
+                best = Some((cost, axis, split));
+            }
+        }
+    }
+
+    let (cost, axis, split) = best?;
+    if items.len() <= max_leaf_size && INTERSECTION_COST * items.len() as f64 <= cost {
+        return None;
+    }
+    let mut mid = 0;
+    for i in 0..items.len() {
+        if bin(&boxes[i].centroid(), axis) < split {
+            items.swap(i, mid);
+            boxes.swap(i, mid);
+            mid += 1;
+        }
+    }
+    Some((mid, axis))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::material::*;
+    use crate::scenes::*;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+    use test_case::test_case;
+
+    #[test]
+    fn test_partition_sah_separates_clusters() {
+        let mut spheres = (0..8)
+            .map(|i| {
+                let x = if i % 2 == 0 { -10.0 } else { 10.0 } + i as f64 * 0.1;
+                Hittable::new_sphere(Point::from(x, 0.0, 0.0), 0.5, Material::new_dielectric(1.5))
+            })
+            .collect::<Vec<_>>();
+        let mid = partition_sah(&mut spheres, |h| h.bounding_box(0.0, 1.0).unwrap(), 1);
+        assert_eq!(mid, Some((4, 0)));
+        assert!(spheres[..4]
+            .iter()
+            .all(|h| h.bounding_box(0.0, 1.0).unwrap().maximum[0] < 0.0));
+    }
+
+    #[test_case(BvhSplit::Median)]
+    #[test_case(BvhSplit::Sah)]
+    fn test_bvh_finds_closest_hit(split: BvhSplit) {
+        let (mut world, _) = random_scene();
+        let list = Hittable::List(world.clone());
+        let mut rng = StdRng::seed_from_u64(1);
+        let (bvh, stats) = build_bvh(&mut world, 0.0, 1.0, split, Some(&mut rng));
+        assert_eq!(stats.leaf_count, stats.node_count + 1);
+
+        for _ in 0..200 {
+            let origin = Point::from(
+                rng.gen_range(-12.0..12.0),
+                rng.gen_range(0.1..3.0),
+                rng.gen_range(-12.0..12.0),
+            );
+            let r = Ray::new(origin, Vector::random_unit_vector(&mut rng), 0.5);
+            let expected = list.hit(&r, 0.001, f64::INFINITY, &mut rng).map(|h| h.t);
+            let actual = bvh.hit(&r, 0.001, f64::INFINITY, &mut rng).map(|h| h.t);
+            assert_eq!(expected, actual);
+        }
+    }
+
+    #[test]
+    fn test_depth_is_bounded() {
+        // Nested spheres, every split separates only the outermost one.
+        let mut spheres = (0..200)
+            .map(|i| {
+                let radius = 1.05f64.powi(i);
+                Hittable::new_sphere(
+                    Point::from(radius, 0.0, 0.0),
+                    radius,
+                    Material::new_dielectric(1.5),
+                )
+            })
+            .collect::<Vec<_>>();
+        let mut rng = StdRng::seed_from_u64(1);
+        let (bvh, stats) = build_bvh(&mut spheres, 0.0, 1.0, BvhSplit::Sah, Some(&mut rng));
+        assert!(stats.depth <= MAX_DEPTH);
+
+        let r = Ray::new(
+            Point::from(-1.0, 0.0, 0.0),
+            Vector::from(1.0, 0.0, 0.0),
+            0.0,
+        );
+        let hit = bvh.hit(&r, 0.001, f64::INFINITY, &mut rng).unwrap();
+        assert!((hit.t - 1.0).abs() < 1e-8);
+    }
+
+    #[test]
+    fn test_sah_is_cheaper_than_median() {
+        let (mut world, _) = random_scene();
+        let mut rng = StdRng::seed_from_u64(1);
+        let (_, median) = build_bvh(
+            &mut world.clone(),
+            0.0,
+            1.0,
+            BvhSplit::Median,
+            Some(&mut rng),
+        );
+        let (_, sah) = build_bvh(&mut world, 0.0, 1.0, BvhSplit::Sah, Some(&mut rng));
+        assert!(sah.cost < median.cost);
+    }
+
+    /// Shrunken instances of one BVH on a grid, every fourth one rotated.
+    fn grid_instances(geometry: &Arc<Hittable>) -> Vec<(Arc<Hittable>, Matrix)> {
+        (0..64)
+            .map(|i| {
+                let offset = Vector::from((i % 8) as f64 * 3.0, 0.0, (i / 8) as f64 * 3.0);
+                let angle = if i % 4 == 0 { 0.7 } else { 0.0 };
+                let axis = Vector::from(0.0, 1.0, 0.0);
+                let matrix = Matrix::from_trs(offset, angle, axis, Vector::from_scalar(0.1));
+                (geometry.clone(), matrix)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_instance_bvh_finds_closest_hit() {
+        let (mut spheres, _) = random_scene();
+        spheres.truncate(50);
+        let geometry = Arc::new(Hittable::new_bvh_sah(&mut spheres, 0.0, 1.0));
+        let instances = grid_instances(&geometry);
+        let list = Hittable::List(
+            instances
+                .iter()
+                .map(|(g, m)| Hittable::new_instance(g.clone(), *m))
+                .collect(),
+        );
+        let tlas = Hittable::new_instance_bvh(instances, 0.0, 1.0);
+
+        let mut rng = StdRng::seed_from_u64(1);
+        for _ in 0..200 {
+            let origin = Point::from(
+                rng.gen_range(-2.0..24.0),
+                rng.gen_range(0.0..1.0),
+                rng.gen_range(-2.0..24.0),
+            );
+            let r = Ray::new(origin, Vector::random_unit_vector(&mut rng), 0.5);
+            let expected = list.hit(&r, 0.001, f64::INFINITY, &mut rng).map(|h| h.t);
+            let actual = tlas.hit(&r, 0.001, f64::INFINITY, &mut rng).map(|h| h.t);
+            assert_eq!(expected, actual);
+        }
+    }
+
+    #[test]
+    fn test_moving_instances_keeps_the_bottom_level() {
+        let sphere = Hittable::new_sphere(Point::new(), 1.0, Material::new_dielectric(1.5));
+        let geometry = Arc::new(sphere);
+        let mut tlas = InstanceBvh::new(grid_instances(&geometry), 0.0, 1.0);
+        let r = Ray::new(
+            Point::from(100.0, 0.0, -10.0),
+            Vector::from(0.0, 0.0, 1.0),
+            0.0,
+        );
+        let mut rng = rand::thread_rng();
+        assert!(tlas.hit(&r, 0.001, f64::INFINITY, &mut rng).is_none());
+
+        tlas.set_matrix(5, Matrix::translation(Vector::from(100.0, 0.0, 0.0)))
+            .unwrap();
+        let stats = tlas.rebuild();
+        assert_eq!(stats.leaf_count, stats.node_count + 1);
+        let hit = tlas.hit(&r, 0.001, f64::INFINITY, &mut rng).unwrap();
+        assert!((hit.p - Point::from(100.0, 0.0, -1.0)).near_zero());
+        // The instances still share the original geometry.
+        assert_eq!(Arc::strong_count(&geometry), 1 + 64);
+    }
+
+    #[test]
+    fn test_instancing_unbounded_geometry() {
+        let material = Material::new_dielectric(1.5);
+        let plane = Arc::new(Hittable::new_plane(
+            Point::new(),
+            Vector::from(0.0, 1.0, 0.0),
+            material.clone(),
+        ));
+        let sphere = Arc::new(Hittable::new_sphere(Point::new(), 1.0, material));
+        let instances = vec![
+            (plane, Matrix::translation(Vector::from(0.0, -1.0, 0.0))),
+            (
+                Arc::new(Hittable::Empty),
+                Matrix::translation(Vector::new()),
+            ),
+            (sphere, Matrix::translation(Vector::from(0.0, 1.0, 0.0))),
+        ];
+        let tlas = InstanceBvh::new(instances, 0.0, 1.0);
+        assert!(tlas.bounding_box().is_none());
+        assert_eq!(tlas.stats().leaf_count, 1);
+
+        let mut rng = StdRng::seed_from_u64(1);
+        let down = Vector::from(0.0, -1.0, 0.0);
+        let r = Ray::new(Point::from(0.0, 5.0, 0.0), down, 0.0);
+        let hit = tlas.hit(&r, 0.001, f64::INFINITY, &mut rng).unwrap();
+        assert!((hit.t - 3.0).abs() < 1e-8);
+        let r = Ray::new(Point::from(5.0, 5.0, 0.0), down, 0.0);
+        let hit = tlas.hit(&r, 0.001, f64::INFINITY, &mut rng).unwrap();
+        assert!((hit.t - 6.0).abs() < 1e-8);
+    }
+
+    #[test]
+    fn test_empty_instance_bvh() {
+        let mut tlas = InstanceBvh::new(Vec::new(), 0.0, 1.0);
+        assert!(tlas.bounding_box().is_none());
+        assert_eq!(tlas.rebuild().leaf_count, 0);
+        let r = Ray::new(Point::new(), Vector::from(1.0, 0.0, 0.0), 0.0);
+        let mut rng = StdRng::seed_from_u64(1);
+        assert!(tlas.hit(&r, 0.001, f64::INFINITY, &mut rng).is_none());
+        assert_eq!(
+            tlas.set_matrix(0, Matrix::translation(Vector::new())),
+            Err(InstanceIndexError { index: 0, count: 0 })
+        );
+    }
+}
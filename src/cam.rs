@@ -1,7 +1,6 @@
 use crate::alias::*;
 use crate::ray::*;
-
-use rand::Rng;
+use crate::sampler::*;
 
 pub struct Camera {
     origin: Point,
@@ -13,6 +12,8 @@ pub struct Camera {
     #[allow(dead_code)]
     w: Vector,
     lens_radius: f64,
+    /// Height of the image plane at unit distance.
+    viewport_height: f64,
     time_open: f64,
     time_close: f64,
 }
@@ -23,6 +24,7 @@ impl Camera {
     /// # Arguments
     ///
     /// * `vfox` - vertical field-of-view in degrees
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         lookfrom: Point,
         lookat: Point,
@@ -55,18 +57,69 @@ impl Camera {
             v,
             w,
             lens_radius: aperture / 2.0,
+            viewport_height,
             time_open,
             time_close,
         }
     }
 
-    pub fn get_ray(&self, s: f64, t: f64) -> Ray {
-        let rd = self.lens_radius * Point::random_in_unit_disk();
+    /// The angle covered by a pixel of an image `image_height` pixels high.
+    pub fn pixel_spread(&self, image_height: u32) -> f64 {
+        (self.viewport_height / image_height as f64).atan()
+    }
+
+    pub fn get_ray(&self, s: f64, t: f64, sampler: &mut Sampler) -> Ray {
+        let rd = self.lens_radius * Point::sample_in_unit_disk(sampler.get_2d());
         let offset = self.u * rd[0] + self.v * rd[1];
         Ray::new(
             self.origin + offset,
             self.lower_left_corner + s * self.horizontal + t * self.vertical - self.origin - offset,
-            rand::thread_rng().gen_range(self.time_open..self.time_close),
+            self.time_open + sampler.get_1d() * (self.time_close - self.time_open),
+        )
+    }
+}
+
+/// The placement and lens of a camera, independent of the image it renders to.
+#[derive(Clone)]
+pub struct CameraSettings {
+    pub lookfrom: Point,
+    pub lookat: Point,
+    pub vup: Vector,
+    /// Vertical field-of-view in degrees
+    pub vfov: f64,
+    pub aperture: f64,
+    pub focus_dist: f64,
+    pub time_open: f64,
+    pub time_close: f64,
+}
+
+impl Default for CameraSettings {
+    fn default() -> Self {
+        Self {
+            lookfrom: Point::from(13.0, 2.0, 3.0),
+            lookat: Point::from(0.0, 0.0, 0.0),
+            vup: Vector::from(0.0, 1.0, 0.0),
+            vfov: 20.0,
+            aperture: 0.1,
+            focus_dist: 10.0,
+            time_open: 0.0,
+            time_close: 1.0,
+        }
+    }
+}
+
+impl CameraSettings {
+    pub fn build(&self, aspect_ratio: f64) -> Camera {
+        Camera::new(
+            self.lookfrom,
+            self.lookat,
+            self.vup,
+            self.vfov,
+            aspect_ratio,
+            self.aperture,
+            self.focus_dist,
+            self.time_open,
+            self.time_close,
         )
     }
 }
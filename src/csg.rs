@@ -0,0 +1,291 @@
+use crate::aabb::AxisAlignedBoundingBox;
+use crate::hittable::*;
+use crate::ray::*;
+use rand::Rng;
+
+/// Hits past this many boundaries of one child are ignored, which stops runaway loops.
+const MAX_CROSSINGS: usize = 64;
+
+/// How a constructive solid geometry node combines its two solids.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CsgOperation {
+    /// Inside either solid.
+    Union,
+    /// Inside both solids.
+    Intersection,
+    /// Inside the left solid but not the right one.
+    Difference,
+}
+
+impl CsgOperation {
+    fn inside(self, left: bool, right: bool) -> bool {
+        match self {
+            Self::Union => left || right,
+            Self::Intersection => left && right,
+            Self::Difference => left && !right,
+        }
+    }
+}
+
+/// The stretch of a ray inside a solid, from where it enters to where it leaves.
+/// A missing end lies outside of the range the ray was traced over.
+struct Interval<'a> {
+    entry: Option<HitRecord<'a>>,
+    exit: Option<HitRecord<'a>>,
+}
+
+/// Combines two closed solids by a boolean operation. The children must be watertight,
+/// so that `front_face` tells whether a ray enters or leaves them.
+#[derive(Clone)]
+pub struct Csg {
+    operation: CsgOperation,
+    left: Hittable,
+    right: Hittable,
+}
+
+impl Csg {
+    pub fn new(operation: CsgOperation, left: Hittable, right: Hittable) -> Self {
+        Self {
+            operation,
+            left,
+            right,
+        }
+    }
+
+    pub fn hit<R: Rng + ?Sized>(
+        &self,
+        r: &Ray,
+        t_min: f64,
+        t_max: f64,
+        rng: &mut R,
+    ) -> Option<HitRecord<'_>> {
+        // A child without boundaries up to `t_max` may still contain that whole stretch,
+        // so the children are traced all the way.
+        let left = intervals(&self.left, r, t_min, rng);
+        let right = intervals(&self.right, r, t_min, rng);
+        combine(self.operation, left, right)
+            .into_iter()
+            .flat_map(|interval| [interval.entry, interval.exit])
+            .flatten()
+            .next()
+            .filter(|hit| hit.t <= t_max)
+    }
+
+    pub fn bounding_box(&self, time_start: f64, time_end: f64) -> Option<AxisAlignedBoundingBox> {
+        let left = self.left.bounding_box(time_start, time_end);
+        let right = self.right.bounding_box(time_start, time_end);
+        match self.operation {
+            CsgOperation::Union => Some(left?.surrounding_box(&right?)),
+            // An unbounded child, like a plane, only clips the other one.
+            CsgOperation::Intersection => match (left, right) {
+                (Some(left), Some(right)) => Some(left.overlap(&right)),
+                (left, right) => left.or(right),
+            },
+            CsgOperation::Difference => left,
+        }
+    }
+}
+
+/// Finds all boundaries of `hittable` along `r` and pairs them up into the stretches inside it.
+fn intervals<'a, R: Rng + ?Sized>(
+    hittable: &'a Hittable,
+    r: &Ray,
+    t_min: f64,
+    rng: &mut R,
+) -> Vec<Interval<'a>> {
+    let mut intervals = Vec::new();
+    let mut entry = None;
+    let mut t = t_min;
+    for _ in 0..MAX_CROSSINGS {
+        let hit = match hittable.hit(r, t, f64::INFINITY, rng) {
+            Some(hit) => hit,
+            None => break,
+        };
+        t = hit.t.next_up();
+        if hit.front_face {
+            entry = Some(hit);
+        } else {
+            // Leaving without having entered means the ray started inside.
+            intervals.push(Interval {
+                entry: entry.take(),
+                exit: Some(hit),
+            });
+        }
+    }
+    if entry.is_some() {
+        intervals.push(Interval { entry, exit: None });
+    }
+    intervals
+}
+
+/// Sweeps over the boundaries of both interval lists and keeps the stretches inside the result.
+fn combine<'a>(
+    operation: CsgOperation,
+    left: Vec<Interval<'a>>,
+    right: Vec<Interval<'a>>,
+) -> Vec<Interval<'a>> {
+    // Boundaries as (t, from the left child, entering, hit), missing ends lie at infinity.
+    let mut events = Vec::new();
+    for (is_left, intervals) in [(true, left), (false, right)] {
+        for interval in intervals {
+            let entry_t = interval
+                .entry
+                .as_ref()
+                .map_or(f64::NEG_INFINITY, |hit| hit.t);
+            let exit_t = interval.exit.as_ref().map_or(f64::INFINITY, |hit| hit.t);
+            events.push((entry_t, is_left, true, interval.entry));
+            events.push((exit_t, is_left, false, interval.exit));
+        }
+    }
+    events.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+    let mut result = Vec::new();
+    let (mut in_left, mut in_right) = (false, false);
+    let mut entry = None;
+    for (_, is_left, entering, hit) in events {
+        let was_inside = operation.inside(in_left, in_right);
+        if is_left {
+            in_left = entering;
+        } else {
+            in_right = entering;
+        }
+        let inside = operation.inside(in_left, in_right);
+        if inside == was_inside {
+            continue;
+        }
+        // The normal already faces the ray, only the side of the combined solid changes.
+        // Leaving the right solid of a difference enters the result, for example.
+        let hit = hit.map(|hit| HitRecord {
+            front_face: inside,
+            ..hit
+        });
+        if inside {
+            entry = Some(hit);
+        } else {
+            result.push(Interval {
+                entry: entry.take().flatten(),
+                exit: hit,
+            });
+        }
+    }
+    if let Some(entry) = entry {
+        result.push(Interval { entry, exit: None });
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::alias::*;
+    use crate::material::*;
+    use std::sync::Arc;
+    use test_case::test_case;
+
+    fn spheres(operation: CsgOperation) -> Hittable {
+        let material = Arc::new(Material::new_lambertian(Color::new()));
+        Hittable::new_csg(
+            operation,
+            Hittable::new_sphere(Point::from(-0.5, 0.0, 0.0), 1.0, material.clone()),
+            Hittable::new_sphere(Point::from(0.5, 0.0, 0.0), 1.0, material),
+        )
+    }
+
+    /// Boundaries along the x axis as (t, front_face), traced from x = -5.
+    fn crossings(hittable: &Hittable) -> Vec<(f64, bool)> {
+        let r = Ray::new(
+            Point::from(-5.0, 0.0, 0.0),
+            Vector::from(1.0, 0.0, 0.0),
+            0.0,
+        );
+        let mut rng = rand::thread_rng();
+        let mut crossings = Vec::new();
+        let mut t_min = 0.001;
+        while let Some(hit) = hittable.hit(&r, t_min, f64::INFINITY, &mut rng) {
+            // The normal always faces the ray.
+            assert!(hit.normal.dot(r.direction) < 0.0);
+            crossings.push((hit.t, hit.front_face));
+            t_min = hit.t.next_up();
+        }
+        crossings
+    }
+
+    fn assert_crossings(hittable: &Hittable, expected: &[(f64, bool)]) {
+        let crossings = crossings(hittable);
+        assert_eq!(crossings.len(), expected.len(), "{:?}", crossings);
+        for (&(t, front_face), &(expected_t, expected_front_face)) in crossings.iter().zip(expected)
+        {
+            assert!((t - expected_t).abs() < 1e-9, "{:?}", crossings);
+            assert_eq!(front_face, expected_front_face);
+        }
+    }
+
+    #[test_case(CsgOperation::Union, &[(3.5, true), (6.5, false)])]
+    #[test_case(CsgOperation::Intersection, &[(4.5, true), (5.5, false)])]
+    #[test_case(CsgOperation::Difference, &[(3.5, true), (4.5, false)])]
+    fn test_csg_crossings(operation: CsgOperation, expected: &[(f64, bool)]) {
+        assert_crossings(&spheres(operation), expected);
+    }
+
+    #[test]
+    fn test_difference_from_inside() {
+        // A ray starting in the hollow of a shell first leaves the hollow, which enters the shell.
+        let material = Arc::new(Material::new_dielectric(1.5));
+        let shell = Hittable::new_csg(
+            CsgOperation::Difference,
+            Hittable::new_sphere(Point::new(), 1.0, material.clone()),
+            Hittable::new_sphere(Point::new(), 0.5, material),
+        );
+        let r = Ray::new(Point::new(), Vector::from(0.0, 0.0, 1.0), 0.0);
+        let mut rng = rand::thread_rng();
+        let hit = shell.hit(&r, 0.001, f64::INFINITY, &mut rng).unwrap();
+        assert!((hit.t - 0.5).abs() < 1e-9);
+        assert!(hit.front_face);
+        assert!((hit.normal - Vector::from(0.0, 0.0, -1.0)).near_zero());
+        let hit = shell.hit(&r, 0.501, f64::INFINITY, &mut rng).unwrap();
+        assert!((hit.t - 1.0).abs() < 1e-9);
+        assert!(!hit.front_face);
+        // Stopping short of the hollow's boundary misses, even though the hollow has no hits yet.
+        assert!(shell.hit(&r, 0.001, 0.25, &mut rng).is_none());
+    }
+
+    #[test]
+    fn test_nested_csg() {
+        // Drilling the intersection out of the union leaves two caps apart.
+        let lens = Hittable::new_csg(
+            CsgOperation::Difference,
+            spheres(CsgOperation::Union),
+            spheres(CsgOperation::Intersection),
+        );
+        assert_crossings(
+            &lens,
+            &[(3.5, true), (4.5, false), (5.5, true), (6.5, false)],
+        );
+
+        // A plane keeps the half space behind its normal.
+        let material = Arc::new(Material::new_lambertian(Color::new()));
+        let clipped = Hittable::new_csg(
+            CsgOperation::Intersection,
+            spheres(CsgOperation::Union),
+            Hittable::new_plane(Point::new(), Vector::from(1.0, 0.0, 0.0), material),
+        );
+        assert_crossings(&clipped, &[(3.5, true), (5.0, false)]);
+        assert!(clipped.bounding_box(0.0, 1.0).is_some());
+    }
+
+    #[test]
+    fn test_csg_bounding_box() {
+        let aabb = spheres(CsgOperation::Union).bounding_box(0.0, 1.0).unwrap();
+        assert!((aabb.minimum - Point::from(-1.5, -1.0, -1.0)).near_zero());
+        assert!((aabb.maximum - Point::from(1.5, 1.0, 1.0)).near_zero());
+        let aabb = spheres(CsgOperation::Intersection)
+            .bounding_box(0.0, 1.0)
+            .unwrap();
+        assert!((aabb.minimum - Point::from(-0.5, -1.0, -1.0)).near_zero());
+        assert!((aabb.maximum - Point::from(0.5, 1.0, 1.0)).near_zero());
+        let aabb = spheres(CsgOperation::Difference)
+            .bounding_box(0.0, 1.0)
+            .unwrap();
+        assert!((aabb.maximum - Point::from(0.5, 1.0, 1.0)).near_zero());
+    }
+}
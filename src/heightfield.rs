@@ -0,0 +1,322 @@
+use crate::aabb::AxisAlignedBoundingBox;
+use crate::alias::*;
+use crate::hittable::*;
+use crate::input::LinearImage;
+use crate::material::*;
+use crate::ray::*;
+use crate::texture::luminance;
+use std::sync::Arc;
+
+/// Slack on the height range of a cell, so that rays grazing a cell still test its triangles.
+const EPSILON: f64 = 1e-6;
+
+/// The two triangles of a grid cell as offsets of their corners, wound to face up.
+const CELL_TRIANGLES: [[[usize; 2]; 3]; 2] = [[[0, 0], [1, 1], [1, 0]], [[0, 0], [0, 1], [1, 1]]];
+
+/// A terrain sampled on a regular grid over the xz plane. Each grid cell is split into two
+/// triangles, and rays walk the cells under them in order until the first hit.
+#[derive(Clone)]
+pub struct Heightfield {
+    /// Number of samples along x and z.
+    samples: [usize; 2],
+    /// World space positions of the samples, row by row along x.
+    positions: Vec<Point>,
+    /// Smooth normals at the samples.
+    normals: Vec<Vector>,
+    /// The lowest and highest height within each cell.
+    cell_heights: Vec<(f64, f64)>,
+    minimum: Point,
+    size: Vector,
+    bounding_box: AxisAlignedBoundingBox,
+    material: Arc<Material>,
+}
+
+impl Heightfield {
+    /// Places the luminance of `image` over the box from `minimum` spanning `size`,
+    /// a luminance of one rises to the top of the box. Pixel columns run along x and
+    /// rows along z, and the uvs line up with the image used as a texture.
+    /// The image needs at least two pixels in each direction, `Hittable::new_heightfield`
+    /// checks this and returns `Hittable::Empty` instead.
+    pub(crate) fn new(
+        image: &LinearImage,
+        minimum: Point,
+        size: Vector,
+        material: impl Into<Arc<Material>>,
+    ) -> Self {
+        let samples = [image.width() as usize, image.height() as usize];
+        assert!(samples[0] >= 2 && samples[1] >= 2);
+        let spacing = [
+            size[0] / (samples[0] - 1) as f64,
+            size[2] / (samples[1] - 1) as f64,
+        ];
+
+        let positions = image
+            .enumerate_pixels()
+            .map(|(x, y, pixel)| {
+                let [r, g, b] = pixel.0;
+                let height = luminance(&Color::from(r as f64, g as f64, b as f64));
+                minimum
+                    + Vector::from(
+                        x as f64 * spacing[0],
+                        height * size[1],
+                        y as f64 * spacing[1],
+                    )
+            })
+            .collect::<Vec<_>>();
+        let height = |x: usize, z: usize| positions[z * samples[0] + x][1];
+
+        // Central differences, one sided at the borders.
+        let mut normals = Vec::with_capacity(positions.len());
+        for z in 0..samples[1] {
+            for x in 0..samples[0] {
+                let (x0, x1) = (x.saturating_sub(1), (x + 1).min(samples[0] - 1));
+                let (z0, z1) = (z.saturating_sub(1), (z + 1).min(samples[1] - 1));
+                let dx = (height(x1, z) - height(x0, z)) / ((x1 - x0) as f64 * spacing[0]);
+                let dz = (height(x, z1) - height(x, z0)) / ((z1 - z0) as f64 * spacing[1]);
+                normals.push(Vector::from(-dx, 1.0, -dz).normalize());
+            }
+        }
+
+        let mut cell_heights = Vec::with_capacity((samples[0] - 1) * (samples[1] - 1));
+        for z in 0..samples[1] - 1 {
+            for x in 0..samples[0] - 1 {
+                let corners = [
+                    height(x, z),
+                    height(x + 1, z),
+                    height(x, z + 1),
+                    height(x + 1, z + 1),
+                ];
+                cell_heights.push((
+                    corners.iter().copied().fold(f64::INFINITY, f64::min),
+                    corners.iter().copied().fold(f64::NEG_INFINITY, f64::max),
+                ));
+            }
+        }
+
+        let bounding_box = AxisAlignedBoundingBox::from_points(positions.iter().copied())
+            .unwrap()
+            .pad(0.0001);
+        Self {
+            samples,
+            positions,
+            normals,
+            cell_heights,
+            minimum,
+            size,
+            bounding_box,
+            material: material.into(),
+        }
+    }
+
+    pub fn bounding_box(&self) -> AxisAlignedBoundingBox {
+        self.bounding_box.clone()
+    }
+
+    pub fn hit(&self, r: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord<'_>> {
+        // Only the part of the ray within the bounds is walked.
+        let (t_near, t_far) = self.bounding_box.clip(r, t_min, t_max)?;
+
+        // Set up a 2D DDA over the cells in x and z.
+        let cells = [self.samples[0] - 1, self.samples[1] - 1];
+        let start = r.at(t_near);
+        let mut cell = [0; 2];
+        let mut step = [0; 2];
+        let mut t_next = [f64::INFINITY; 2];
+        let mut t_delta = [f64::INFINITY; 2];
+        for (k, axis) in [0, 2].iter().copied().enumerate() {
+            let spacing = self.size[axis] / cells[k] as f64;
+            let offset = (start[axis] - self.minimum[axis]) / spacing;
+            cell[k] = (offset.max(0.0) as usize).min(cells[k] - 1);
+            let d = r.direction[axis];
+            let boundary = if d > 0.0 {
+                step[k] = 1;
+                cell[k] + 1
+            } else if d < 0.0 {
+                step[k] = -1;
+                cell[k]
+            } else {
+                continue;
+            };
+            let x = self.minimum[axis] + boundary as f64 * spacing;
+            t_next[k] = (x - r.origin[axis]) / d;
+            t_delta[k] = spacing / d.abs();
+        }
+
+        let mut t_enter = t_near;
+        loop {
+            let t_exit = t_next[0].min(t_next[1]).min(t_far);
+            // Skip cells whose heights the ray passes above or below.
+            let (y0, y1) = (r.at(t_enter)[1], r.at(t_exit)[1]);
+            let (low, high) = self.cell_heights[cell[1] * cells[0] + cell[0]];
+            if y0.min(y1) <= high + EPSILON && y0.max(y1) >= low - EPSILON {
+                if let Some(hit) = self.hit_cell(cell, r, t_min, t_max) {
+                    return Some(hit);
+                }
+            }
+
+            let k = if t_next[0] < t_next[1] { 0 } else { 1 };
+            if t_next[k] > t_far {
+                return None;
+            }
+            let next = cell[k] as isize + step[k];
+            if next < 0 || next >= cells[k] as isize {
+                return None;
+            }
+            cell[k] = next as usize;
+            t_enter = t_next[k];
+            t_next[k] += t_delta[k];
+        }
+    }
+
+    /// Intersects the two triangles of a cell, skipping cut out hits.
+    fn hit_cell(&self, cell: [usize; 2], r: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord<'_>> {
+        let mut record = None;
+        let mut closest_so_far = t_max;
+        for triangle in &CELL_TRIANGLES {
+            let indices = triangle.map(|[x, z]| (cell[1] + z) * self.samples[0] + cell[0] + x);
+            let vertices = indices.map(|i| self.positions[i]);
+            let (t, barycentric) = match intersect_triangle(r, &vertices, t_min, closest_so_far) {
+                Some(hit) => hit,
+                None => continue,
+            };
+            let [p0, p1, p2] = vertices;
+            let outward_normal = (p1 - p0).cross(p2 - p0).normalize();
+            let shading_normal = (barycentric[0] * self.normals[indices[0]]
+                + barycentric[1] * self.normals[indices[1]]
+                + barycentric[2] * self.normals[indices[2]])
+                .normalize();
+
+            let p = r.at(t);
+            let uv = Vec2::from(
+                (p[0] - self.minimum[0]) / self.size[0],
+                1.0 - (p[2] - self.minimum[2]) / self.size[2],
+            );
+            // The slopes of the triangle along x and z give the derivatives of the position.
+            let slope_x = -outward_normal[0] / outward_normal[1];
+            let slope_z = -outward_normal[2] / outward_normal[1];
+            let tangent = self.size[0] * Vector::from(1.0, slope_x, 0.0);
+            let bitangent = -self.size[2] * Vector::from(0.0, slope_z, 1.0);
+
+            let hit = HitRecord::new_with_shading_normal(
+                r,
+                p,
+                t,
+                uv,
+                outward_normal,
+                shading_normal,
+                &self.material,
+            )
+            // Ignores the slope, which stretches the texture on steep terrain.
+            .with_footprint(r, 1.0 / (self.size[0] * self.size[2]).sqrt())
+            .with_tangents(tangent, bitangent);
+            if !hit.material.cuts_out(r, &hit) {
+                closest_so_far = t;
+                record = Some(hit);
+            }
+        }
+        record
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::Rgb;
+    use proptest::prelude::*;
+    use test_case::test_case;
+
+    fn heightfield(width: u32, depth: u32, height: impl Fn(u32, u32) -> f32) -> Heightfield {
+        let image = LinearImage::from_fn(width, depth, |x, y| Rgb([height(x, y); 3]));
+        Heightfield::new(
+            &image,
+            Point::from(-1.0, 0.0, -1.0),
+            Vector::from(2.0, 1.0, 2.0),
+            Material::new_lambertian(Color::new()),
+        )
+    }
+
+    #[test_case(0.0, 0.0, 0.5, 0.5)]
+    #[test_case(-0.5, 0.5, 0.25, 0.25)]
+    #[test_case(0.9, -0.9, 0.95, 0.95)]
+    fn test_flat_heightfield(x: f64, z: f64, u: f64, v: f64) {
+        let field = heightfield(5, 5, |_, _| 0.5);
+        let r = Ray::new(Point::from(x, 3.0, z), Vector::from(0.0, -1.0, 0.0), 0.0);
+        let hit = field.hit(&r, 0.001, f64::INFINITY).unwrap();
+        assert!((hit.t - 2.5).abs() < 1e-9);
+        assert!(hit.front_face);
+        assert!((hit.normal - Vector::from(0.0, 1.0, 0.0)).near_zero());
+        assert!((hit.uv - Vec2::from(u, v)).near_zero());
+        assert!((hit.tangent - Vector::from(2.0, 0.0, 0.0)).near_zero());
+        assert!((hit.bitangent - Vector::from(0.0, 0.0, -2.0)).near_zero());
+    }
+
+    #[test]
+    fn test_ramp_normals_are_smooth() {
+        // A ramp rising along x has the same normal everywhere, even at its borders.
+        let field = heightfield(5, 3, |x, _| x as f32 / 4.0);
+        let expected = Vector::from(-0.5, 1.0, 0.0).normalize();
+        for &(x, z) in &[(-0.9, -0.9), (0.1, 0.3), (0.95, 0.5)] {
+            let r = Ray::new(Point::from(x, 3.0, z), Vector::from(0.0, -1.0, 0.0), 0.0);
+            let hit = field.hit(&r, 0.001, f64::INFINITY).unwrap();
+            assert!((hit.p[1] - (x + 1.0) / 2.0).abs() < 1e-9);
+            assert!((hit.normal - expected).near_zero());
+        }
+        // Rays from below see the underside.
+        let r = Ray::new(
+            Point::from(0.0, -1.0, 0.0),
+            Vector::from(0.0, 1.0, 0.0),
+            0.0,
+        );
+        let hit = field.hit(&r, 0.001, f64::INFINITY).unwrap();
+        assert!(!hit.front_face);
+        assert!((hit.normal + expected).near_zero());
+    }
+
+    #[test]
+    fn test_grazing_ray_finds_ridge() {
+        // A low ray crosses many empty cells before reaching the ridge.
+        let field = heightfield(64, 64, |x, _| if x == 48 { 1.0 } else { 0.0 });
+        let r = Ray::new(
+            Point::from(-2.0, 0.5, 0.3),
+            Vector::from(1.0, 0.0, 0.0),
+            0.0,
+        );
+        let hit = field.hit(&r, 0.001, f64::INFINITY).unwrap();
+        // The ridge rises from x = 47 to x = 48 in grid units.
+        let ridge = -1.0 + 2.0 * 47.5 / 63.0;
+        assert!((hit.p[0] - ridge).abs() < 1e-9);
+        assert!(field.hit(&r, 0.001, 2.0).is_none());
+        let r = Ray::new(
+            Point::from(-2.0, 1.5, 0.3),
+            Vector::from(1.0, 0.0, 0.0),
+            0.0,
+        );
+        assert!(field.hit(&r, 0.001, f64::INFINITY).is_none());
+    }
+
+    fn brute_force(field: &Heightfield, r: &Ray) -> Option<f64> {
+        let cells = [field.samples[0] - 1, field.samples[1] - 1];
+        (0..cells[1])
+            .flat_map(|z| (0..cells[0]).map(move |x| [x, z]))
+            .filter_map(|cell| field.hit_cell(cell, r, 0.001, f64::INFINITY))
+            .map(|hit| hit.t)
+            .min_by(|a, b| a.total_cmp(b))
+    }
+
+    proptest! {
+        #[test]
+        fn test_dda_matches_brute_force(
+            origin in prop::array::uniform3(-2.0f64..2.0),
+            direction in prop::array::uniform3(-1.0f64..1.0),
+        ) {
+            let field = heightfield(9, 7, |x, y| ((x * 7 + y * 3) % 5) as f32 / 4.0);
+            let r = Ray::new(Point::from_array(origin), Vector::from_array(direction), 0.0);
+            let expected = brute_force(&field, &r);
+            let actual = field.hit(&r, 0.001, f64::INFINITY).map(|hit| hit.t);
+            match (expected, actual) {
+                (Some(expected), Some(actual)) => prop_assert!((expected - actual).abs() < 1e-9),
+                (expected, actual) => prop_assert_eq!(expected, actual),
+            }
+        }
+    }
+}
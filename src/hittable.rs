@@ -1,9 +1,17 @@
 use crate::aabb::AxisAlignedBoundingBox;
 use crate::alias::*;
+use crate::bvh::*;
+use crate::csg::*;
+use crate::heightfield::*;
+use crate::input::LinearImage;
 use crate::material::*;
+use crate::mesh::*;
+use crate::quadric::*;
 use crate::ray::*;
+use crate::sdf::*;
 use rand::prelude::*;
 use std::mem;
+use std::sync::Arc;
 
 pub struct HitRecord<'a> {
     pub p: Point,
@@ -12,6 +20,11 @@ pub struct HitRecord<'a> {
     pub uv: Vec2<f64>,
     pub front_face: bool,
     pub material: &'a Material,
+    /// Width of the ray cone at the hit in texture coordinates, zero when unknown.
+    pub footprint: f64,
+    /// Derivatives of `p` along u and v, zero when the surface has no parametrization.
+    pub tangent: Vector,
+    pub bitangent: Vector,
 }
 
 impl<'a> HitRecord<'a> {
@@ -35,6 +48,78 @@ impl<'a> HitRecord<'a> {
                 -outward_normal
             },
             material,
+            footprint: 0.0,
+            tangent: Vector::new(),
+            bitangent: Vector::new(),
+        }
+    }
+
+    /// Converts the width of the ray cone into texture coordinates, which change by
+    /// `uv_per_unit` per unit of distance along the surface.
+    pub fn with_footprint(self, r: &Ray, uv_per_unit: f64) -> Self {
+        Self {
+            footprint: r.width_at(self.t) * uv_per_unit,
+            ..self
+        }
+    }
+
+    pub fn with_tangents(self, tangent: Vector, bitangent: Vector) -> Self {
+        Self {
+            tangent,
+            bitangent,
+            ..self
+        }
+    }
+
+    /// Sets the derivatives of `get_sphere_uv` on a sphere of `radius`.
+    fn with_tangents_of_sphere(self, radius: f64) -> Self {
+        use std::f64::consts::PI;
+        // The normal `get_sphere_uv` was evaluated with, `(p - center) / radius`.
+        let n = if self.front_face {
+            self.normal
+        } else {
+            -self.normal
+        };
+        let (x, y, z) = (n[0], n[1], n[2]);
+        // Distance to the axis, clamped so that the poles keep a finite frame.
+        let rho = (1.0 - y * y).sqrt().max(1e-9);
+        let tangent = 2.0 * PI * radius * Vector::from(z, 0.0, -x);
+        let bitangent = PI * radius * Vector::from(-x * y / rho, rho, -y * z / rho);
+        self.with_tangents(tangent, bitangent)
+    }
+
+    /// Like `new`, but shades with an interpolated normal that may differ from
+    /// the geometric one. `front_face` is still decided by the geometric normal.
+    pub fn new_with_shading_normal(
+        r: &Ray,
+        p: Point,
+        t: f64,
+        uv: Vec2<f64>,
+        outward_normal: Vector,
+        shading_normal: Vector,
+        material: &'a Material,
+    ) -> Self {
+        let front_face = r.direction.dot(outward_normal) < 0.0;
+        // Keep the shading normal in the same hemisphere as the geometry.
+        let shading_normal = if shading_normal.dot(outward_normal) < 0.0 {
+            -shading_normal
+        } else {
+            shading_normal
+        };
+        Self {
+            p,
+            t,
+            uv,
+            front_face,
+            normal: if front_face {
+                shading_normal
+            } else {
+                -shading_normal
+            },
+            material,
+            footprint: 0.0,
+            tangent: Vector::new(),
+            bitangent: Vector::new(),
         }
     }
 }
@@ -43,14 +128,73 @@ impl<'a> HitRecord<'a> {
 pub struct Cube {
     p0: Point,
     p1: Point,
-    material: Material,
+    material: Arc<Material>,
 }
 
 #[derive(Clone)]
 pub struct Sphere {
     center: Point,
     radius: f64,
-    material: Material,
+    material: Arc<Material>,
+}
+
+#[derive(Clone)]
+pub struct Triangle {
+    vertices: [Point; 3],
+    material: Arc<Material>,
+}
+
+/// A parallelogram spanned by the edges `u` and `v` from the corner `origin`.
+#[derive(Clone)]
+pub struct Quad {
+    origin: Point,
+    u: Vector,
+    v: Vector,
+    /// `u` cross `v` divided by its squared length, which projects hits onto the edges.
+    w: Vector,
+    normal: Vector,
+    area: f64,
+    material: Arc<Material>,
+}
+
+#[derive(Clone)]
+pub struct Disk {
+    center: Point,
+    /// The normal and two tangents, u runs around the center from the first tangent.
+    frame: ONB,
+    radius: f64,
+    material: Arc<Material>,
+}
+
+/// An infinite plane, it has no bounding box and is kept out of BVHs.
+#[derive(Clone)]
+pub struct Plane {
+    point: Point,
+    /// The normal and the axes of the texture coordinates, one unit apart.
+    frame: ONB,
+    material: Arc<Material>,
+}
+
+/// The parameter of the hit of `r` with the plane through `point`, if it is in range.
+fn hit_plane(r: &Ray, point: &Point, normal: &Vector, t_min: f64, t_max: f64) -> Option<f64> {
+    let denominator = normal.dot(r.direction);
+    // Parallel rays never hit.
+    if denominator.abs() < 1e-12 {
+        return None;
+    }
+    let t = normal.dot(*point - r.origin) / denominator;
+    (t_min..=t_max).contains(&t).then_some(t)
+}
+
+/// The density of a uniformly sampled area light of `area` as seen from `origin` along `direction`,
+/// with the light hit at `t`. Converts the density over the area to one over solid angle.
+fn area_pdf(direction: &Vector, normal: &Vector, t: f64, area: f64) -> f64 {
+    let distance_squared = t * t * direction.sqrlen();
+    let cosine = (direction.dot(*normal) / direction.length()).abs();
+    if cosine < 1e-12 {
+        return 0.0;
+    }
+    distance_squared / (cosine * area)
 }
 
 #[derive(Clone)]
@@ -60,7 +204,7 @@ pub struct MovingSphere {
     time_start: f64,
     time_end: f64,
     radius: f64,
-    material: Material,
+    material: Arc<Material>,
 }
 
 impl MovingSphere {
@@ -75,7 +219,7 @@ impl MovingSphere {
 pub struct ConstantMedium {
     boundary: Box<Hittable>,
     neg_inv_density: f64,
-    material: Material,
+    material: Arc<Material>,
 }
 
 #[derive(Clone)]
@@ -90,11 +234,100 @@ pub struct Rotate {
     rotation: Quaternion,
 }
 
+/// An affine transform, the child is placed in the world by `matrix`.
+/// The child is shared, so many instances of one object only store it once.
 #[derive(Clone)]
-pub struct BvhNode {
-    left: Box<Hittable>,
-    right: Box<Hittable>,
-    bounding_box: AxisAlignedBoundingBox,
+pub struct Transform {
+    hittable: Arc<Hittable>,
+    matrix: Matrix,
+    inverse: Matrix,
+    /// The inverse transpose of `matrix`, which keeps normals perpendicular to the surface.
+    normal_matrix: Matrix,
+    /// Average scale from world to object space, applied to the width of ray cones.
+    width_scale: f64,
+}
+
+impl Transform {
+    /// Places the shared `geometry` with an invertible affine `matrix`.
+    pub fn new(geometry: Arc<Hittable>, matrix: Matrix) -> Self {
+        // Nested transforms collapse into a single one.
+        let (geometry, matrix) = match *geometry {
+            Hittable::Transform(ref transform) => {
+                (transform.hittable.clone(), matrix.dot(transform.matrix))
+            }
+            _ => (geometry, matrix),
+        };
+        let inverse = matrix.inverse();
+        Self {
+            hittable: geometry,
+            matrix,
+            inverse,
+            normal_matrix: inverse.transpose(),
+            width_scale: inverse.det().abs().cbrt(),
+        }
+    }
+
+    pub fn hit<R: Rng + ?Sized>(
+        &self,
+        r: &Ray,
+        t_min: f64,
+        t_max: f64,
+        rng: &mut R,
+    ) -> Option<HitRecord<'_>> {
+        // The direction isn't normalized, so `t` and the spread of the cone are the
+        // same in both spaces.
+        let ray = Ray::new(
+            self.inverse.transform_point(&r.origin),
+            self.inverse.transform_vector(&r.direction),
+            r.time,
+        )
+        .with_cone(r.width * self.width_scale, r.spread);
+        self.hittable
+            .hit(&ray, t_min, t_max, rng)
+            .map(|hit| HitRecord {
+                p: self.matrix.transform_point(&hit.p),
+                normal: self.normal_matrix.transform_vector(&hit.normal).normalize(),
+                tangent: self.matrix.transform_vector(&hit.tangent),
+                bitangent: self.matrix.transform_vector(&hit.bitangent),
+                ..hit
+            })
+    }
+
+    pub fn bounding_box(&self, time_start: f64, time_end: f64) -> Option<AxisAlignedBoundingBox> {
+        let matrix = &self.matrix;
+        let sphere_box = |center: Point, radius: f64| {
+            // An ellipsoid, its extent along each axis is the radius times the row length.
+            let center = matrix.transform_point(&center);
+            let extent =
+                Vector::from_map(|i| radius.abs() * Vector::from_map(|j| matrix[(j, i)]).length());
+            AxisAlignedBoundingBox::new(center - extent, center + extent)
+        };
+        match *self.hittable {
+            Hittable::Sphere(ref sphere) => Some(sphere_box(sphere.center, sphere.radius)),
+            Hittable::MovingSphere(ref sphere) => Some(
+                sphere_box(sphere.center(time_start), sphere.radius)
+                    .surrounding_box(&sphere_box(sphere.center(time_end), sphere.radius)),
+            ),
+            _ => self
+                .hittable
+                .bounding_box(time_start, time_end)
+                .and_then(|aabb| {
+                    AxisAlignedBoundingBox::from_points(
+                        aabb.corners().map(|corner| matrix.transform_point(&corner)),
+                    )
+                }),
+        }
+    }
+
+    /// The shared object in object space.
+    pub fn geometry(&self) -> &Arc<Hittable> {
+        &self.hittable
+    }
+
+    /// The object to world matrix.
+    pub fn matrix(&self) -> &Matrix {
+        &self.matrix
+    }
 }
 
 #[derive(Clone)]
@@ -102,24 +335,43 @@ pub enum Hittable {
     Cube(Cube),
     Sphere(Sphere),
     MovingSphere(MovingSphere),
+    Triangle(Triangle),
+    Quad(Quad),
+    Disk(Disk),
+    Plane(Plane),
+    Cylinder(Quadric),
+    Cone(Quadric),
+    Paraboloid(Quadric),
+    Hyperboloid(Quadric),
+    Torus(Torus),
+    Csg(Box<Csg>),
+    Sdf(Box<SdfObject>),
+    Mesh(Mesh),
+    Heightfield(Box<Heightfield>),
     ConstantMedium(ConstantMedium),
     Translate(Translate),
     Rotate(Rotate),
+    Transform(Box<Transform>),
     List(Vec<Hittable>),
-    Bvh(BvhNode),
+    Bvh(Bvh),
+    Instances(InstanceBvh),
     Empty,
 }
 
 impl Hittable {
-    pub fn new_cube(p0: Point, p1: Point, material: Material) -> Self {
-        Self::Cube(Cube { p0, p1, material })
+    pub fn new_cube(p0: Point, p1: Point, material: impl Into<Arc<Material>>) -> Self {
+        Self::Cube(Cube {
+            p0,
+            p1,
+            material: material.into(),
+        })
     }
 
-    pub fn new_sphere(center: Point, radius: f64, material: Material) -> Self {
+    pub fn new_sphere(center: Point, radius: f64, material: impl Into<Arc<Material>>) -> Self {
         Self::Sphere(Sphere {
             center,
             radius,
-            material,
+            material: material.into(),
         })
     }
 
@@ -129,7 +381,7 @@ impl Hittable {
         time_start: f64,
         time_end: f64,
         radius: f64,
-        material: Material,
+        material: impl Into<Arc<Material>>,
     ) -> Self {
         Self::MovingSphere(MovingSphere {
             center_start,
@@ -137,15 +389,189 @@ impl Hittable {
             time_start,
             time_end,
             radius,
-            material,
+            material: material.into(),
+        })
+    }
+
+    pub fn new_triangle(
+        p0: Point,
+        p1: Point,
+        p2: Point,
+        material: impl Into<Arc<Material>>,
+    ) -> Self {
+        Self::Triangle(Triangle {
+            vertices: [p0, p1, p2],
+            material: material.into(),
+        })
+    }
+
+    /// Creates the parallelogram with the corners `origin`, `origin + u`, `origin + u + v`
+    /// and `origin + v`. Texture coordinates run along `u` and `v`, which must not be parallel.
+    pub fn new_quad(
+        origin: Point,
+        u: Vector,
+        v: Vector,
+        material: impl Into<Arc<Material>>,
+    ) -> Self {
+        let n = u.cross(v);
+        Self::Quad(Quad {
+            origin,
+            u,
+            v,
+            w: n / n.sqrlen(),
+            normal: n.normalize(),
+            area: n.length(),
+            material: material.into(),
+        })
+    }
+
+    /// A disk of positive `radius` facing along a non-zero `normal`.
+    pub fn new_disk(
+        center: Point,
+        normal: Vector,
+        radius: f64,
+        material: impl Into<Arc<Material>>,
+    ) -> Self {
+        Self::Disk(Disk {
+            center,
+            frame: ONB::from_w(&normal),
+            radius,
+            material: material.into(),
         })
     }
 
+    /// An infinite plane through `point`, `normal` must not be zero.
+    pub fn new_plane(point: Point, normal: Vector, material: impl Into<Arc<Material>>) -> Self {
+        Self::Plane(Plane {
+            point,
+            frame: ONB::from_w(&normal),
+            material: material.into(),
+        })
+    }
+
+    /// Creates a cylinder from `base` to `base + axis`, `capped` closes both ends.
+    pub fn new_cylinder(
+        base: Point,
+        axis: Vector,
+        radius: f64,
+        capped: bool,
+        material: impl Into<Arc<Material>>,
+    ) -> Self {
+        Self::Cylinder(Quadric::new_cylinder(base, axis, radius, capped, material))
+    }
+
+    /// Creates a cone with a base of `radius` and its apex at `base + axis`.
+    pub fn new_cone(
+        base: Point,
+        axis: Vector,
+        radius: f64,
+        capped: bool,
+        material: impl Into<Arc<Material>>,
+    ) -> Self {
+        Self::Cone(Quadric::new_cone(base, axis, radius, capped, material))
+    }
+
+    /// Creates a paraboloid with its tip at `base` and a rim of `radius` at `base + axis`.
+    pub fn new_paraboloid(
+        base: Point,
+        axis: Vector,
+        radius: f64,
+        capped: bool,
+        material: impl Into<Arc<Material>>,
+    ) -> Self {
+        Self::Paraboloid(Quadric::new_paraboloid(
+            base, axis, radius, capped, material,
+        ))
+    }
+
+    /// Creates a hyperboloid of one sheet, narrowing from `end_radius` at both ends
+    /// to `radius` halfway along the axis.
+    pub fn new_hyperboloid(
+        base: Point,
+        axis: Vector,
+        radius: f64,
+        end_radius: f64,
+        capped: bool,
+        material: impl Into<Arc<Material>>,
+    ) -> Self {
+        Self::Hyperboloid(Quadric::new_hyperboloid(
+            base, axis, radius, end_radius, capped, material,
+        ))
+    }
+
+    /// Creates a torus around `axis` through `center`.
+    pub fn new_torus(
+        center: Point,
+        axis: Vector,
+        major_radius: f64,
+        minor_radius: f64,
+        material: impl Into<Arc<Material>>,
+    ) -> Self {
+        Self::Torus(Torus::new(
+            center,
+            axis,
+            major_radius,
+            minor_radius,
+            material,
+        ))
+    }
+
+    /// Combines two closed hittables into one solid by a boolean `operation`.
+    pub fn new_csg(operation: CsgOperation, left: Hittable, right: Hittable) -> Self {
+        Self::Csg(Box::new(Csg::new(operation, left, right)))
+    }
+
+    /// Creates a shape from a signed distance function, which is sphere traced within `bounds`.
+    pub fn new_sdf(
+        sdf: Sdf,
+        bounds: AxisAlignedBoundingBox,
+        material: impl Into<Arc<Material>>,
+    ) -> Self {
+        Self::Sdf(Box::new(SdfObject::new(sdf, bounds, material)))
+    }
+
+    /// Creates a triangle mesh sharing one set of vertex buffers.
+    /// Faces without normal or uv indices fall back to the flat geometric
+    /// normal and barycentric coordinates respectively.
+    pub fn new_mesh(
+        positions: Vec<Point>,
+        normals: Vec<Vector>,
+        uvs: Vec<Vec2<f64>>,
+        faces: Vec<[VertexIndex; 3]>,
+        material: impl Into<Arc<Material>>,
+    ) -> Self {
+        if faces.is_empty() {
+            return Self::Empty;
+        }
+        Self::Mesh(Mesh::new(positions, normals, uvs, faces, material))
+    }
+
+    /// Creates a terrain from the luminance of `image`, see `Heightfield::new`.
+    /// Images narrower than two pixels in either direction have no cells and are empty.
+    pub fn new_heightfield(
+        image: &LinearImage,
+        minimum: Point,
+        size: Vector,
+        material: impl Into<Arc<Material>>,
+    ) -> Self {
+        if image.width() < 2 || image.height() < 2 {
+            return Self::Empty;
+        }
+        Self::Heightfield(Box::new(Heightfield::new(image, minimum, size, material)))
+    }
+
     pub fn new_constant_medium(boundary: Hittable, density: f64, color: Color) -> Self {
         Self::ConstantMedium(ConstantMedium {
             boundary: Box::new(boundary),
             neg_inv_density: -(1.0 / density),
-            material: Material::new_isotropic(color),
+            material: Arc::new(Material::new_isotropic(color)),
+        })
+    }
+
+    pub fn new_translate(hittable: Hittable, offset: Vector) -> Self {
+        Self::Translate(Translate {
+            hittable: Box::new(hittable),
+            offset,
         })
     }
 
@@ -156,93 +582,125 @@ impl Hittable {
         })
     }
 
-    pub fn new_bvh(hittables: &mut [Hittable], time_start: f64, time_end: f64) -> Self {
-        if hittables.len() >= 2 {
-            let axis = rand::thread_rng().gen_range(0..3);
-            let compare = |a: &Hittable, b: &Hittable| {
-                a.bounding_box(time_start, time_end)
-                    .unwrap()
-                    .compare(&b.bounding_box(time_start, time_end).unwrap(), axis)
-            };
-            hittables.sort_by(compare);
+    /// Places `hittable` in the world with an invertible affine `matrix`.
+    pub fn new_transform(hittable: Hittable, matrix: Matrix) -> Self {
+        Self::new_instance(Arc::new(hittable), matrix)
+    }
+
+    /// Places an instance of the shared `geometry` in the world with an invertible affine `matrix`.
+    /// Build the geometry once, e.g. as a BVH or mesh, and share it between all of its instances.
+    pub fn new_instance(geometry: Arc<Hittable>, matrix: Matrix) -> Self {
+        Self::Transform(Box::new(Transform::new(geometry, matrix)))
+    }
+
+    /// Scales `hittable`, then rotates it by `angle` radians around `axis` and moves it by `translation`.
+    pub fn new_trs(
+        hittable: Hittable,
+        translation: Vector,
+        angle: f64,
+        axis: Vector,
+        scale: Vector,
+    ) -> Self {
+        Self::new_transform(hittable, Matrix::from_trs(translation, angle, axis, scale))
+    }
+
+    /// Builds a BVH that sorts along a random axis and splits at the median.
+    pub fn new_bvh<R: RngCore>(
+        hittables: &mut [Hittable],
+        time_start: f64,
+        time_end: f64,
+        rng: &mut R,
+    ) -> Self {
+        Self::new_bvh_with_split(hittables, time_start, time_end, BvhSplit::Median, Some(rng))
+    }
+
+    /// Builds a BVH that splits where the surface area heuristic expects the cheapest traversal.
+    pub fn new_bvh_sah(hittables: &mut [Hittable], time_start: f64, time_end: f64) -> Self {
+        Self::new_bvh_with_split(hittables, time_start, time_end, BvhSplit::Sah, None)
+    }
+
+    /// Builds a two-level acceleration structure over instances of shared geometry.
+    pub fn new_instance_bvh(
+        instances: Vec<(Arc<Hittable>, Matrix)>,
+        time_start: f64,
+        time_end: f64,
+    ) -> Self {
+        if instances.is_empty() {
+            return Self::Empty;
         }
+        Self::Instances(InstanceBvh::new(instances, time_start, time_end))
+    }
 
+    /// Builds a BVH with the given split. Only the median split draws from `rng`,
+    /// without one it splits along the longest axis.
+    pub fn new_bvh_with_split(
+        hittables: &mut [Hittable],
+        time_start: f64,
+        time_end: f64,
+        split: BvhSplit,
+        rng: Option<&mut dyn RngCore>,
+    ) -> Self {
         match hittables {
             [] => Hittable::Empty,
             [hittable] => mem::replace(hittable, Hittable::Empty),
-            [left, right] => Hittable::new_bvh_from_left_right(
-                mem::replace(left, Hittable::Empty),
-                mem::replace(right, Hittable::Empty),
-                time_start,
-                time_end,
-            ),
             _ => {
-                let (left, right) = hittables.split_at_mut(hittables.len() / 2);
-                Hittable::new_bvh_from_left_right(
-                    Hittable::new_bvh(left, time_start, time_end),
-                    Hittable::new_bvh(right, time_start, time_end),
+                let (mut bounded, mut unbounded): (Vec<_>, Vec<_>) = hittables
+                    .iter_mut()
+                    .map(|hittable| mem::replace(hittable, Hittable::Empty))
+                    .partition(|hittable| hittable.bounding_box(time_start, time_end).is_some());
+                if unbounded.is_empty() {
+                    return Self::Bvh(Bvh::new(bounded, time_start, time_end, split, rng));
+                }
+                // Unbounded primitives like planes don't fit into the tree, they are tested next to it.
+                unbounded.push(Self::new_bvh_with_split(
+                    &mut bounded,
                     time_start,
                     time_end,
-                )
+                    split,
+                    rng,
+                ));
+                Self::List(unbounded)
             }
         }
     }
 
-    fn new_bvh_from_left_right(
-        left: Hittable,
-        right: Hittable,
-        time_start: f64,
-        time_end: f64,
-    ) -> Self {
-        let bounding_box = left
-            .bounding_box(time_start, time_end)
-            .unwrap()
-            .surrounding_box(&right.bounding_box(time_start, time_end).unwrap());
-        Self::Bvh(BvhNode {
-            left: Box::new(left),
-            right: Box::new(right),
-            bounding_box,
-        })
-    }
-
-    pub fn hit(&self, r: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord> {
+    pub fn hit<R: Rng + ?Sized>(
+        &self,
+        r: &Ray,
+        t_min: f64,
+        t_max: f64,
+        rng: &mut R,
+    ) -> Option<HitRecord<'_>> {
         match *self {
-            Self::Cube(ref cube) => {
-                let mut t_min = t_min;
-                let mut t_max = t_max;
-                for i in 0..3 {
-                    let inv_d = 1.0 / r.direction[i];
-                    // Calculate the time when the ray is in the region for this axis.
-                    let mut t0 = (cube.p0[i] - r.origin[i]) * inv_d;
-                    let mut t1 = (cube.p1[i] - r.origin[i]) * inv_d;
-                    if inv_d < 0.0 {
-                        mem::swap(&mut t0, &mut t1);
-                    }
-                    t_min = t0.max(t_min);
-                    t_max = t1.min(t_max);
-                    // if t_max ever gets smaller then t_min we do not have a hit.
-                    if t_max <= t_min {
-                        return None;
-                    }
+            Self::Cube(ref cube) => first_opaque(r, t_min, |t_min| {
+                let (t_near, t_far) = AxisAlignedBoundingBox::new(cube.p0, cube.p1).clip(
+                    r,
+                    f64::NEG_INFINITY,
+                    f64::INFINITY,
+                )?;
+                // Rays from inside the cube, or behind a cut out face, leave through the far face.
+                let t = if t_near >= t_min { t_near } else { t_far };
+                if t < t_min || t > t_max {
+                    return None;
                 }
-                let t = t_min;
                 let p = r.at(t);
                 // Scale p to a range to 0,1 on all axis
                 let rel_p = (p - cube.p0) / (cube.p1 - cube.p0);
 
+                // Outward normals, so that `front_face` tells rays entering the cube apart.
                 let epsilon = 0.002;
                 let normal = if rel_p[0] < epsilon {
-                    Vector::from(1.0, 0.0, 0.0)
-                } else if rel_p[0] > 1.0 - epsilon {
                     Vector::from(-1.0, 0.0, 0.0)
+                } else if rel_p[0] > 1.0 - epsilon {
+                    Vector::from(1.0, 0.0, 0.0)
                 } else if rel_p[1] < epsilon {
-                    Vector::from(0.0, 1.0, 0.0)
-                } else if rel_p[1] > 1.0 - epsilon {
                     Vector::from(0.0, -1.0, 0.0)
+                } else if rel_p[1] > 1.0 - epsilon {
+                    Vector::from(0.0, 1.0, 0.0)
                 } else if rel_p[2] < epsilon {
-                    Vector::from(0.0, 0.0, 1.0)
-                } else if rel_p[2] > 1.0 - epsilon {
                     Vector::from(0.0, 0.0, -1.0)
+                } else if rel_p[2] > 1.0 - epsilon {
+                    Vector::from(0.0, 0.0, 1.0)
                 } else {
                     //println!("Miss! {}", rel_p);
                     Vector::from(0.5, 0.5, 0.5).normalize()
@@ -251,10 +709,32 @@ impl Hittable {
                 let uv = Vec2::from(rel_p[0], rel_p[1]) * normal[2].abs()
                     + Vec2::from(rel_p[1], rel_p[2]) * normal[0].abs()
                     + Vec2::from(rel_p[0], rel_p[2]) * normal[1].abs();
+                // Texture coordinates span the face, the shorter side changes them fastest.
+                let size = cube.p1 - cube.p0;
+                let face = Vec2::from(size[0], size[1]) * normal[2].abs()
+                    + Vec2::from(size[1], size[2]) * normal[0].abs()
+                    + Vec2::from(size[0], size[2]) * normal[1].abs();
+                let uv_per_unit = 1.0 / face[0].abs().min(face[1].abs());
+                let (u_axis, v_axis) = if normal[0].abs() == 1.0 {
+                    (1, 2)
+                } else if normal[1].abs() == 1.0 {
+                    (0, 2)
+                } else {
+                    (0, 1)
+                };
+                let along = |axis: usize| {
+                    let mut v = Vector::new();
+                    v[axis] = size[axis];
+                    v
+                };
 
-                Some(HitRecord::new(r, p, t, uv, normal, &cube.material))
-            }
-            Self::Sphere(ref sphere) => {
+                Some(
+                    HitRecord::new(r, p, t, uv, normal, &cube.material)
+                        .with_footprint(r, uv_per_unit)
+                        .with_tangents(along(u_axis), along(v_axis)),
+                )
+            }),
+            Self::Sphere(ref sphere) => first_opaque(r, t_min, |t_min| {
                 let oc = r.origin - sphere.center;
                 let a = r.direction.sqrlen();
                 let half_b = oc.dot(r.direction);
@@ -276,16 +756,20 @@ impl Hittable {
 
                 let p = r.at(root);
                 let outward_normal = (p - sphere.center) / sphere.radius;
-                Some(HitRecord::new(
-                    r,
-                    p,
-                    root,
-                    get_sphere_uv(&outward_normal),
-                    outward_normal,
-                    &sphere.material,
-                ))
-            }
-            Self::MovingSphere(ref sphere) => {
+                Some(
+                    HitRecord::new(
+                        r,
+                        p,
+                        root,
+                        get_sphere_uv(&outward_normal),
+                        outward_normal,
+                        &sphere.material,
+                    )
+                    .with_footprint(r, sphere_uv_per_unit(sphere.radius))
+                    .with_tangents_of_sphere(sphere.radius),
+                )
+            }),
+            Self::MovingSphere(ref sphere) => first_opaque(r, t_min, |t_min| {
                 let oc = r.origin - sphere.center(r.time);
                 let a = r.direction.sqrlen();
                 let half_b = oc.dot(r.direction);
@@ -307,22 +791,123 @@ impl Hittable {
 
                 let p = r.at(root);
                 let outward_normal = (p - sphere.center(r.time)) / sphere.radius;
-                Some(HitRecord::new(
-                    r,
-                    p,
-                    root,
-                    get_sphere_uv(&outward_normal),
-                    outward_normal,
-                    &sphere.material,
-                ))
+                Some(
+                    HitRecord::new(
+                        r,
+                        p,
+                        root,
+                        get_sphere_uv(&outward_normal),
+                        outward_normal,
+                        &sphere.material,
+                    )
+                    .with_footprint(r, sphere_uv_per_unit(sphere.radius))
+                    .with_tangents_of_sphere(sphere.radius),
+                )
+            }),
+            Self::Triangle(ref triangle) => first_opaque(r, t_min, |t_min| {
+                let [p0, p1, p2] = triangle.vertices;
+                let (t, barycentric) = intersect_triangle(r, &triangle.vertices, t_min, t_max)?;
+                let cross = (p1 - p0).cross(p2 - p0);
+                Some(
+                    HitRecord::new(
+                        r,
+                        r.at(t),
+                        t,
+                        Vec2::from(barycentric[1], barycentric[2]),
+                        cross.normalize(),
+                        &triangle.material,
+                    )
+                    // Barycentric coordinates span half a unit square over the triangle.
+                    .with_footprint(r, (1.0 / cross.length()).sqrt())
+                    .with_tangents(p1 - p0, p2 - p0),
+                )
+            }),
+            Self::Quad(ref quad) => first_opaque(r, t_min, |t_min| {
+                let t = hit_plane(r, &quad.origin, &quad.normal, t_min, t_max)?;
+                let p = r.at(t);
+                // Coordinates of the hit along the edges.
+                let planar = p - quad.origin;
+                let alpha = quad.w.dot(planar.cross(quad.v));
+                let beta = quad.w.dot(quad.u.cross(planar));
+                if !(0.0..=1.0).contains(&alpha) || !(0.0..=1.0).contains(&beta) {
+                    return None;
+                }
+                Some(
+                    HitRecord::new(
+                        r,
+                        p,
+                        t,
+                        Vec2::from(alpha, beta),
+                        quad.normal,
+                        &quad.material,
+                    )
+                    .with_footprint(r, 1.0 / quad.u.length().min(quad.v.length()))
+                    .with_tangents(quad.u, quad.v),
+                )
+            }),
+            Self::Disk(ref disk) => first_opaque(r, t_min, |t_min| {
+                use std::f64::consts::PI;
+                let (u, v, normal) = (disk.frame.u(), disk.frame.v(), disk.frame.w());
+                let t = hit_plane(r, &disk.center, &normal, t_min, t_max)?;
+                let p = r.at(t);
+                let (x, y) = ((p - disk.center).dot(u), (p - disk.center).dot(v));
+                let distance = (x * x + y * y).sqrt();
+                if distance > disk.radius {
+                    return None;
+                }
+                // u is the angle around the center and v the distance from it.
+                let phi = y.atan2(x).rem_euclid(2.0 * PI);
+                let radial = if distance > 0.0 {
+                    (x * u + y * v) / distance
+                } else {
+                    u
+                };
+                Some(
+                    HitRecord::new(
+                        r,
+                        p,
+                        t,
+                        Vec2::from(phi / (2.0 * PI), distance / disk.radius),
+                        normal,
+                        &disk.material,
+                    )
+                    .with_footprint(r, 1.0 / disk.radius)
+                    .with_tangents(2.0 * PI * (x * v - y * u), disk.radius * radial),
+                )
+            }),
+            Self::Plane(ref plane) => first_opaque(r, t_min, |t_min| {
+                let (u, v, normal) = (plane.frame.u(), plane.frame.v(), plane.frame.w());
+                let t = hit_plane(r, &plane.point, &normal, t_min, t_max)?;
+                let p = r.at(t);
+                let uv = Vec2::from((p - plane.point).dot(u), (p - plane.point).dot(v));
+                Some(
+                    HitRecord::new(r, p, t, uv, normal, &plane.material)
+                        .with_footprint(r, 1.0)
+                        .with_tangents(u, v),
+                )
+            }),
+            Self::Cylinder(ref quadric)
+            | Self::Cone(ref quadric)
+            | Self::Paraboloid(ref quadric)
+            | Self::Hyperboloid(ref quadric) => {
+                first_opaque(r, t_min, |t_min| quadric.hit(r, t_min, t_max))
             }
+            Self::Torus(ref torus) => first_opaque(r, t_min, |t_min| torus.hit(r, t_min, t_max)),
+            Self::Csg(ref csg) => csg.hit(r, t_min, t_max, rng),
+            Self::Sdf(ref sdf) => first_opaque(r, t_min, |t_min| sdf.hit(r, t_min, t_max)),
+            Self::Mesh(ref mesh) => mesh.hit(r, t_min, t_max),
+            Self::Heightfield(ref heightfield) => heightfield.hit(r, t_min, t_max),
             Self::ConstantMedium(ref medium) => {
                 // Print occasional samples when debugging. To enable, set enableDebug true.
                 let enable_debug = false;
-                let debugging = enable_debug && rand::random::<f64>() < 0.00001;
+                let debugging = enable_debug && rng.gen::<f64>() < 0.00001;
 
-                let mut hit1 = medium.boundary.hit(r, f64::NEG_INFINITY, f64::INFINITY)?;
-                let mut hit2 = medium.boundary.hit(r, hit1.t + 0.0001, f64::INFINITY)?;
+                let mut hit1 = medium
+                    .boundary
+                    .hit(r, f64::NEG_INFINITY, f64::INFINITY, rng)?;
+                let mut hit2 = medium
+                    .boundary
+                    .hit(r, hit1.t + 0.0001, f64::INFINITY, rng)?;
 
                 if debugging {
                     println!("t_min={}, t_max={}", hit1.t, hit2.t);
@@ -339,7 +924,7 @@ impl Hittable {
 
                 let ray_length = r.direction.length();
                 let distance_inside_boundary = (hit2.t - hit1.t) * ray_length;
-                let hit_distance = medium.neg_inv_density * rand::random::<f64>().ln();
+                let hit_distance = medium.neg_inv_density * rng.gen::<f64>().ln();
 
                 if hit_distance > distance_inside_boundary {
                     return None;
@@ -362,10 +947,11 @@ impl Hittable {
                 ))
             }
             Self::Translate(ref translate) => {
-                let moved_ray = Ray::new(r.origin - translate.offset, r.direction, r.time);
+                let moved_ray = Ray::new(r.origin - translate.offset, r.direction, r.time)
+                    .with_cone(r.width, r.spread);
                 translate
                     .hittable
-                    .hit(&moved_ray, t_min, t_max)
+                    .hit(&moved_ray, t_min, t_max, rng)
                     .map(|hit| HitRecord {
                         p: hit.p + translate.offset,
                         ..hit
@@ -374,21 +960,27 @@ impl Hittable {
             Self::Rotate(ref rotate) => {
                 let origin = r.origin.rotate(&rotate.rotation);
                 let direction = r.direction.rotate(&rotate.rotation);
-                let rotated_ray = Ray::new(origin, direction, r.time);
-                rotate.hittable.hit(&rotated_ray, t_min, t_max).map(|hit| {
-                    let inverted_rotation = rotate.rotation.invert();
-                    HitRecord {
-                        p: hit.p.rotate(&inverted_rotation),
-                        normal: hit.normal.rotate(&inverted_rotation),
-                        ..hit
-                    }
-                })
+                let rotated_ray = Ray::new(origin, direction, r.time).with_cone(r.width, r.spread);
+                rotate
+                    .hittable
+                    .hit(&rotated_ray, t_min, t_max, rng)
+                    .map(|hit| {
+                        let inverted_rotation = rotate.rotation.invert();
+                        HitRecord {
+                            p: hit.p.rotate(&inverted_rotation),
+                            normal: hit.normal.rotate(&inverted_rotation),
+                            tangent: hit.tangent.rotate(&inverted_rotation),
+                            bitangent: hit.bitangent.rotate(&inverted_rotation),
+                            ..hit
+                        }
+                    })
             }
+            Self::Transform(ref transform) => transform.hit(r, t_min, t_max, rng),
             Self::List(ref list) => {
                 let mut record = None;
                 let mut closest_so_far = t_max;
                 for hittable in list {
-                    if let Some(new_record) = hittable.hit(r, t_min, closest_so_far) {
+                    if let Some(new_record) = hittable.hit(r, t_min, closest_so_far, rng) {
                         if new_record.t < closest_so_far {
                             closest_so_far = new_record.t;
                             record = Some(new_record);
@@ -397,19 +989,8 @@ impl Hittable {
                 }
                 record
             }
-            Self::Bvh(ref node) => {
-                if !node.bounding_box.hit(r, t_min, t_max) {
-                    return None;
-                }
-
-                return match node.left.hit(r, t_min, t_max) {
-                    Some(left_record) => Some(match node.right.hit(r, t_min, left_record.t) {
-                        Some(right_record) if right_record.t < left_record.t => right_record,
-                        _ => left_record,
-                    }),
-                    _ => node.right.hit(r, t_min, t_max),
-                };
-            }
+            Self::Bvh(ref bvh) => bvh.hit(r, t_min, t_max, rng),
+            Self::Instances(ref instances) => instances.hit(r, t_min, t_max, rng),
             Self::Empty => None,
         }
     }
@@ -432,6 +1013,34 @@ impl Hittable {
                 );
                 Some(box0.surrounding_box(&box1))
             }
+            Self::Triangle(ref triangle) => Some(triangle_bounding_box(&triangle.vertices)),
+            Self::Quad(ref quad) => AxisAlignedBoundingBox::from_points([
+                quad.origin,
+                quad.origin + quad.u,
+                quad.origin + quad.v,
+                quad.origin + quad.u + quad.v,
+            ])
+            .map(|aabb| aabb.pad(0.0001)),
+            Self::Disk(ref disk) => {
+                // The extent along an axis is the radius times the sine between axis and normal.
+                let normal = disk.frame.w();
+                let extent =
+                    Vector::from_map(|i| disk.radius * (1.0 - normal[i] * normal[i]).sqrt());
+                Some(
+                    AxisAlignedBoundingBox::new(disk.center - extent, disk.center + extent)
+                        .pad(0.0001),
+                )
+            }
+            Self::Plane(_) => None,
+            Self::Cylinder(ref quadric)
+            | Self::Cone(ref quadric)
+            | Self::Paraboloid(ref quadric)
+            | Self::Hyperboloid(ref quadric) => Some(quadric.bounding_box()),
+            Self::Torus(ref torus) => Some(torus.bounding_box()),
+            Self::Csg(ref csg) => csg.bounding_box(time_start, time_end),
+            Self::Sdf(ref sdf) => Some(sdf.bounding_box()),
+            Self::Mesh(ref mesh) => Some(mesh.bounding_box()),
+            Self::Heightfield(ref heightfield) => Some(heightfield.bounding_box()),
             Self::ConstantMedium(ref medium) => medium.boundary.bounding_box(time_start, time_end),
             Self::Translate(ref translate) => translate
                 .hittable
@@ -443,17 +1052,31 @@ impl Hittable {
                     )
                 }),
             Self::Rotate(ref rotate) => {
-                rotate
-                    .hittable
-                    .bounding_box(time_start, time_end)
-                    .map(|aabb| {
-                        /* Rotate bounding box */
-                        AxisAlignedBoundingBox::new(
-                            aabb.minimum.rotate(&rotate.rotation),
-                            aabb.maximum.rotate(&rotate.rotation),
-                        )
-                    })
+                // Rays are rotated into the child, so the child is placed by the inverse rotation.
+                let to_world = rotate.rotation.invert();
+                let sphere_box = |center: Point, radius: f64| {
+                    let center = center.rotate(&to_world);
+                    let radius = Vector::from_scalar(radius.abs());
+                    AxisAlignedBoundingBox::new(center - radius, center + radius)
+                };
+                match *rotate.hittable {
+                    // Spheres stay spheres, which is much tighter than rotating their box.
+                    Self::Sphere(ref sphere) => Some(sphere_box(sphere.center, sphere.radius)),
+                    Self::MovingSphere(ref sphere) => Some(
+                        sphere_box(sphere.center(time_start), sphere.radius)
+                            .surrounding_box(&sphere_box(sphere.center(time_end), sphere.radius)),
+                    ),
+                    _ => rotate
+                        .hittable
+                        .bounding_box(time_start, time_end)
+                        .and_then(|aabb| {
+                            AxisAlignedBoundingBox::from_points(
+                                aabb.corners().map(|corner| corner.rotate(&to_world)),
+                            )
+                        }),
+                }
             }
+            Self::Transform(ref transform) => transform.bounding_box(time_start, time_end),
             Self::List(ref list) => {
                 let mut result: Option<AxisAlignedBoundingBox> = None;
                 for item in list {
@@ -469,16 +1092,22 @@ impl Hittable {
                 }
                 result
             }
-            Self::Bvh(ref node) => Some(node.bounding_box.clone()),
+            Self::Bvh(ref bvh) => Some(bvh.bounding_box().clone()),
+            Self::Instances(ref instances) => instances.bounding_box(),
             Self::Empty => None,
         }
     }
 
-    pub fn pdf_value(&self, origin: &Point, direction: &Vector) -> f64 {
+    pub fn pdf_value<R: Rng + ?Sized>(
+        &self,
+        origin: &Point,
+        direction: &Vector,
+        rng: &mut R,
+    ) -> f64 {
         match *self {
             Self::Sphere(ref sphere) => {
                 if self
-                    .hit(&Ray::new(*origin, *direction, 0.0), 0.0, std::f64::INFINITY)
+                    .hit(&Ray::new(*origin, *direction, 0.0), 0.0, f64::INFINITY, rng)
                     .is_some()
                 {
                     let cos_theta_max = (1.0
@@ -489,32 +1118,83 @@ impl Hittable {
                 }
                 0.0
             }
+            Self::Quad(ref quad) => self
+                .hit(
+                    &Ray::new(*origin, *direction, 0.0),
+                    0.001,
+                    f64::INFINITY,
+                    rng,
+                )
+                .map_or(0.0, |hit| {
+                    area_pdf(direction, &quad.normal, hit.t, quad.area)
+                }),
+            Self::Disk(ref disk) => {
+                let area = std::f64::consts::PI * disk.radius * disk.radius;
+                self.hit(
+                    &Ray::new(*origin, *direction, 0.0),
+                    0.001,
+                    f64::INFINITY,
+                    rng,
+                )
+                .map_or(0.0, |hit| area_pdf(direction, &disk.frame.w(), hit.t, area))
+            }
             Self::List(ref list) => {
                 let weight = 1.0 / list.len() as f64;
                 list.iter()
-                    .map(|pdf| weight * pdf.pdf_value(origin, direction))
+                    .map(|pdf| weight * pdf.pdf_value(origin, direction, rng))
                     .sum()
             }
             _ => 0.0,
         }
     }
 
-    pub fn random(&self, origin: &Point) -> Vector {
+    /// A direction from `origin` towards the hittable, generated from a sample in [0,1)^2.
+    pub fn random(&self, origin: &Point, u: Vec2<f64>) -> Vector {
         match *self {
             Self::Sphere(ref sphere) => {
                 let direction = sphere.center - *origin;
                 let distance_squared = direction.sqrlen();
                 let uvw = ONB::from_w(&direction);
-                uvw.local(&Vector::random_to_sphere(sphere.radius, distance_squared))
+                uvw.local(&Vector::sample_to_sphere(
+                    sphere.radius,
+                    distance_squared,
+                    u,
+                ))
+            }
+            Self::Quad(ref quad) => quad.origin + u[0] * quad.u + u[1] * quad.v - *origin,
+            Self::Disk(ref disk) => {
+                let radius = disk.radius * u[0].sqrt();
+                let (sin, cos) = (2.0 * std::f64::consts::PI * u[1]).sin_cos();
+                disk.center + radius * (cos * disk.frame.u() + sin * disk.frame.v()) - *origin
             }
             Self::List(ref list) if !list.is_empty() => {
-                list.choose(&mut rand::thread_rng()).unwrap().random(origin)
+                // Pick the hittable with the first component and reuse the remainder.
+                let scaled = u[0] * list.len() as f64;
+                let i = (scaled as usize).min(list.len() - 1);
+                list[i].random(origin, Vec2::from(scaled - i as f64, u[1]))
             }
             _ => Vector::from(1.0, 0.0, 0.0),
         }
     }
 }
 
+/// The closest hit `find` returns after some `t`, skipping the cut out parts of surfaces.
+/// A surface can be hit several times, so the search continues behind every cut out hit.
+fn first_opaque<'a>(
+    r: &Ray,
+    t_min: f64,
+    find: impl Fn(f64) -> Option<HitRecord<'a>>,
+) -> Option<HitRecord<'a>> {
+    let mut t_min = t_min;
+    loop {
+        let hit = find(t_min)?;
+        if !hit.material.cuts_out(r, &hit) {
+            return Some(hit);
+        }
+        t_min = hit.t.next_up();
+    }
+}
+
 /// Calculate the UV coordinates on a sphere.
 /// u: returned value [0,1] of angle around the Y axis from X=-1.
 /// v: returned value [0,1] of angle from Y=-1 to Y=+1.
@@ -527,6 +1207,8 @@ impl Hittable {
 /// * `p` - A given point on the sphere of radius one, centered at the origin.
 ///
 /// ```
+/// # use raytracer::alias::*;
+/// # use raytracer::hittable::get_sphere_uv;
 /// let uv = get_sphere_uv(&Point::from(1.0, 0.0, 0.0));
 /// assert_eq!(uv, Vec2::from(0.5, 0.5));
 /// ```
@@ -539,9 +1221,87 @@ pub fn get_sphere_uv(p: &Point) -> Vec2<f64> {
     Vec2::from(u, v)
 }
 
+/// How fast `get_sphere_uv` changes along the surface of a sphere. v runs from pole to pole,
+/// which is twice as fast as u runs around the equator.
+fn sphere_uv_per_unit(radius: f64) -> f64 {
+    1.0 / (std::f64::consts::PI * radius.abs())
+}
+
+/// Watertight ray/triangle intersection (Woop, Benthin and Wald 2013).
+/// Rays through a shared edge or vertex always hit at least one of the adjacent triangles.
+///
+/// Returns the ray parameter and the barycentric coordinates of the hit.
+pub fn intersect_triangle(
+    r: &Ray,
+    vertices: &[Point; 3],
+    t_min: f64,
+    t_max: f64,
+) -> Option<(f64, [f64; 3])> {
+    // Permute the axes so that the ray direction is largest along z.
+    let abs_direction = r.direction.map(|i| i.abs());
+    let kz = if abs_direction[0] > abs_direction[1] {
+        if abs_direction[0] > abs_direction[2] {
+            0
+        } else {
+            2
+        }
+    } else if abs_direction[1] > abs_direction[2] {
+        1
+    } else {
+        2
+    };
+    let mut kx = (kz + 1) % 3;
+    let mut ky = (kx + 1) % 3;
+    // Swap to preserve the winding direction of the triangle.
+    if r.direction[kz] < 0.0 {
+        mem::swap(&mut kx, &mut ky);
+    }
+
+    // Shear the vertices so the ray points along +z from the origin.
+    let sx = r.direction[kx] / r.direction[kz];
+    let sy = r.direction[ky] / r.direction[kz];
+    let sz = 1.0 / r.direction[kz];
+
+    let [a, b, c] = vertices.map(|v| v - r.origin);
+    let ax = a[kx] - sx * a[kz];
+    let ay = a[ky] - sy * a[kz];
+    let bx = b[kx] - sx * b[kz];
+    let by = b[ky] - sy * b[kz];
+    let cx = c[kx] - sx * c[kz];
+    let cy = c[ky] - sy * c[kz];
+
+    // Scaled barycentric coordinates as 2D edge functions.
+    let u = cx * by - cy * bx;
+    let v = ax * cy - ay * cx;
+    let w = bx * ay - by * ax;
+
+    if (u < 0.0 || v < 0.0 || w < 0.0) && (u > 0.0 || v > 0.0 || w > 0.0) {
+        return None;
+    }
+    let det = u + v + w;
+    if det == 0.0 {
+        return None;
+    }
+
+    let t = (u * sz * a[kz] + v * sz * b[kz] + w * sz * c[kz]) / det;
+    if t < t_min || t > t_max {
+        return None;
+    }
+    Some((t, [u / det, v / det, w / det]))
+}
+
+pub fn triangle_bounding_box(vertices: &[Point; 3]) -> AxisAlignedBoundingBox {
+    let [p0, p1, p2] = *vertices;
+    AxisAlignedBoundingBox::new(p0, p0)
+        .surrounding_box(&AxisAlignedBoundingBox::new(p1, p1))
+        .surrounding_box(&AxisAlignedBoundingBox::new(p2, p2))
+        .pad(0.0001)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::texture::Texture;
     use test_case::test_case;
 
     #[test_case(Point::from(1.0, 0.0, 0.0), Vec2::from(0.50, 0.5))]
@@ -554,4 +1314,474 @@ mod tests {
         let uv = get_sphere_uv(&p);
         assert!((uv - result).near_zero());
     }
+
+    /// Shoots rays from all around at the origin and checks that every hit lies inside the box.
+    fn assert_hits_inside_bounding_box(hittable: &Hittable, targets: &[Point]) {
+        let aabb = hittable.bounding_box(0.0, 1.0).unwrap().pad(1e-6);
+        let mut rng = rand::rngs::mock::StepRng::new(0, 1);
+        for (i, target) in targets.iter().enumerate() {
+            let theta = i as f64 * 2.399963;
+            let z = 1.0 - 2.0 * (i as f64 + 0.5) / targets.len() as f64;
+            let r = (1.0 - z * z).sqrt();
+            let origin = 20.0 * Point::from(r * theta.cos(), r * theta.sin(), z);
+            let ray = Ray::new(origin, *target - origin, 0.5);
+            if let Some(hit) = hittable.hit(&ray, 0.001, f64::INFINITY, &mut rng) {
+                for axis in 0..3 {
+                    assert!(aabb.minimum[axis] - 1e-6 <= hit.p[axis]);
+                    assert!(hit.p[axis] <= aabb.maximum[axis] + 1e-6);
+                }
+            }
+        }
+    }
+
+    use proptest::prelude::*;
+
+    fn axis() -> impl Strategy<Value = Vector> {
+        (-1.0f64..1.0, -1.0f64..1.0, -1.0f64..1.0)
+            .prop_map(|(x, y, z)| Vector::from(x, y, z))
+            .prop_filter("axis must not be zero", |axis| axis.length() > 0.1)
+    }
+
+    fn targets() -> impl Strategy<Value = Vec<Point>> {
+        prop::collection::vec(
+            (-3.0f64..3.0, -3.0f64..3.0, -3.0f64..3.0).prop_map(|(x, y, z)| Point::from(x, y, z)),
+            64,
+        )
+    }
+
+    proptest! {
+        #[test]
+        fn test_rotated_cube_hits_are_bounded(
+            angle in -std::f64::consts::PI..std::f64::consts::PI,
+            axis in axis(),
+            min in (-2.0f64..0.0, -2.0f64..0.0, -2.0f64..0.0),
+            size in (0.1f64..2.0, 0.1f64..2.0, 0.1f64..2.0),
+            targets in targets(),
+        ) {
+            let p0 = Point::from(min.0, min.1, min.2);
+            let p1 = p0 + Vector::from(size.0, size.1, size.2);
+            let cube = Hittable::new_cube(p0, p1, Material::new_lambertian(Color::new()));
+            let offset = Vector::from(0.5, -0.25, 1.0);
+            let rotated = Hittable::new_rotate(Hittable::new_translate(cube, offset), angle, axis);
+            assert_hits_inside_bounding_box(&rotated, &targets);
+        }
+
+        #[test]
+        fn test_disk_hits_are_bounded(
+            normal in axis(),
+            center in (-2.0f64..2.0, -2.0f64..2.0, -2.0f64..2.0),
+            radius in 0.1f64..2.0,
+            targets in targets(),
+        ) {
+            let center = Point::from(center.0, center.1, center.2);
+            let disk = Hittable::new_disk(center, normal, radius, Material::new_lambertian(Color::new()));
+            assert_hits_inside_bounding_box(&disk, &targets);
+        }
+
+        #[test]
+        fn test_quadric_hits_are_bounded(
+            axis in axis(),
+            base in (-2.0f64..2.0, -2.0f64..2.0, -2.0f64..2.0),
+            radius in 0.1f64..2.0,
+            capped: bool,
+            targets in targets(),
+        ) {
+            let base = Point::from(base.0, base.1, base.2);
+            let material = Arc::new(Material::new_lambertian(Color::new()));
+            for hittable in [
+                Hittable::new_cylinder(base, axis, radius, capped, material.clone()),
+                Hittable::new_cone(base, axis, radius, capped, material.clone()),
+                Hittable::new_paraboloid(base, axis, radius, capped, material.clone()),
+                Hittable::new_hyperboloid(base, axis, radius, 2.0 * radius, capped, material.clone()),
+                Hittable::new_torus(base, axis, radius, 0.5 * radius, material.clone()),
+            ] {
+                assert_hits_inside_bounding_box(&hittable, &targets);
+            }
+        }
+
+        #[test]
+        fn test_rotated_sphere_hits_are_bounded(
+            angle in -std::f64::consts::PI..std::f64::consts::PI,
+            axis in axis(),
+            center in (-2.0f64..2.0, -2.0f64..2.0, -2.0f64..2.0),
+            radius in 0.1f64..2.0,
+            targets in targets(),
+        ) {
+            let center = Point::from(center.0, center.1, center.2);
+            let sphere = Hittable::new_sphere(center, radius, Material::new_lambertian(Color::new()));
+            let rotated = Hittable::new_rotate(sphere, angle, axis);
+            assert_hits_inside_bounding_box(&rotated, &targets);
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn test_transformed_hits_are_bounded(
+            angle in -std::f64::consts::PI..std::f64::consts::PI,
+            axis in axis(),
+            scale in (0.2f64..3.0, 0.2f64..3.0, 0.2f64..3.0),
+            shear in (-1.0f64..1.0, -1.0f64..1.0, -1.0f64..1.0),
+            sphere in any::<bool>(),
+            targets in targets(),
+        ) {
+            let material = Material::new_lambertian(Color::new());
+            let child = if sphere {
+                Hittable::new_sphere(Point::from(0.5, -0.5, 0.25), 1.0, material)
+            } else {
+                Hittable::new_cube(Point::from(-1.0, -0.5, 0.0), Point::from(1.0, 0.5, 1.5), material)
+            };
+            let matrix = Matrix::from_trs(
+                Vector::from(0.5, 0.25, -0.5),
+                angle,
+                axis,
+                Vector::from(scale.0, scale.1, scale.2),
+            )
+            .dot(Matrix::shearing(shear.0, shear.1, 0.0, shear.2, 0.0, 0.0));
+            assert_hits_inside_bounding_box(&Hittable::new_transform(child, matrix), &targets);
+        }
+    }
+
+    #[test]
+    fn test_scaled_sphere_normal() {
+        // The unit sphere stretched into an ellipse x^2/4 + y^2 = 1 in the xy plane.
+        let sphere =
+            Hittable::new_sphere(Point::new(), 1.0, Material::new_lambertian(Color::new()));
+        let ellipsoid =
+            Hittable::new_transform(sphere, Matrix::scaling(Vector::from(2.0, 1.0, 1.0)));
+        let r = Ray::new(
+            Point::from(1.0, 5.0, 0.0),
+            Vector::from(0.0, -1.0, 0.0),
+            0.0,
+        );
+        let hit = ellipsoid
+            .hit(&r, 0.001, f64::INFINITY, &mut rand::thread_rng())
+            .unwrap();
+        let y = 0.75f64.sqrt();
+        assert!((hit.t - (5.0 - y)).abs() < 1e-8);
+        assert!((hit.p - Point::from(1.0, y, 0.0)).near_zero());
+        assert!((hit.normal - Vector::from(0.25, y, 0.0).normalize()).near_zero());
+        assert!(hit.front_face);
+    }
+
+    #[test_case(Point::from(0.0, 0.0, 5.0), 4.0 * 0.01 / std::f64::consts::PI)]
+    #[test_case(Point::from(0.0, 0.0, 9.0), 8.0 * 0.01 / std::f64::consts::PI)]
+    fn test_footprint_grows_with_distance(origin: Point, footprint: f64) {
+        let sphere =
+            Hittable::new_sphere(Point::new(), 1.0, Material::new_lambertian(Color::new()));
+        let r = Ray::new(origin, Vector::from(0.0, 0.0, -1.0), 0.0).with_cone(0.0, 0.01);
+        let hit = sphere
+            .hit(&r, 0.001, f64::INFINITY, &mut rand::thread_rng())
+            .unwrap();
+        assert!((hit.footprint - footprint).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_footprint_is_independent_of_transforms() {
+        let material = Material::new_lambertian(Color::new());
+        let sphere = Hittable::new_sphere(Point::new(), 2.0, material.clone());
+        let scaled = Hittable::new_transform(
+            Hittable::new_sphere(Point::new(), 1.0, material),
+            Matrix::scaling(Vector::from_scalar(2.0)),
+        );
+        let r = Ray::new(
+            Point::from(0.0, 0.0, 7.0),
+            Vector::from(0.0, 0.0, -2.0),
+            0.0,
+        )
+        .with_cone(0.1, 0.01);
+        let mut rng = rand::thread_rng();
+        let expected = sphere.hit(&r, 0.001, f64::INFINITY, &mut rng).unwrap();
+        let actual = scaled.hit(&r, 0.001, f64::INFINITY, &mut rng).unwrap();
+        assert!((expected.footprint - actual.footprint).abs() < 1e-12);
+    }
+
+    #[test_case(Vector::from(1.0, 0.3, 0.5))]
+    #[test_case(Vector::from(0.2, -0.7, -0.6))]
+    #[test_case(Vector::from(-0.3, 0.5, 0.9))]
+    fn test_sphere_tangents_follow_uv(direction: Vector) {
+        let center = Point::from(1.0, 2.0, 3.0);
+        let sphere = Hittable::new_sphere(center, 2.0, Material::new_lambertian(Color::new()));
+        let n = direction.normalize();
+        let r = Ray::new(center + 5.0 * n, -n, 0.0);
+        let hit = sphere
+            .hit(&r, 0.001, f64::INFINITY, &mut rand::thread_rng())
+            .unwrap();
+        let eps = 1e-6;
+        let uv_at = |p: Point| get_sphere_uv(&(p - center).normalize());
+        let du = (uv_at(hit.p + eps * hit.tangent) - hit.uv) / eps;
+        let dv = (uv_at(hit.p + eps * hit.bitangent) - hit.uv) / eps;
+        assert!((du - Vec2::from(1.0, 0.0)).length() < 1e-4);
+        assert!((dv - Vec2::from(0.0, 1.0)).length() < 1e-4);
+    }
+
+    #[test]
+    fn test_nested_transforms_collapse() {
+        let sphere =
+            Hittable::new_sphere(Point::new(), 1.0, Material::new_lambertian(Color::new()));
+        let scaled = Hittable::new_transform(sphere, Matrix::scaling(Vector::from_scalar(2.0)));
+        let moved =
+            Hittable::new_transform(scaled, Matrix::translation(Vector::from(1.0, 0.0, 0.0)));
+        match moved {
+            Hittable::Transform(ref transform) => {
+                assert!(matches!(*transform.hittable, Hittable::Sphere(_)))
+            }
+            _ => panic!("expected a transform"),
+        }
+        let aabb = moved.bounding_box(0.0, 1.0).unwrap();
+        assert!((aabb.minimum - Point::from(-1.0, -2.0, -2.0)).near_zero());
+        assert!((aabb.maximum - Point::from(3.0, 2.0, 2.0)).near_zero());
+    }
+
+    #[test]
+    fn test_instances_share_geometry() {
+        let material = Material::new_lambertian(Color::new());
+        let mut parts = vec![
+            Hittable::new_sphere(Point::from(0.0, 1.0, 0.0), 0.5, material.clone()),
+            Hittable::new_cube(
+                Point::from(-0.1, 0.0, -0.1),
+                Point::from(0.1, 0.5, 0.1),
+                material,
+            ),
+        ];
+        let tree = Arc::new(Hittable::new_bvh_sah(&mut parts, 0.0, 1.0));
+        let forest = (0..100)
+            .map(|i| {
+                let offset = Vector::from((i % 10) as f64 * 2.0, 0.0, (i / 10) as f64 * 2.0);
+                Hittable::new_instance(tree.clone(), Matrix::translation(offset))
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(Arc::strong_count(&tree), 101);
+
+        // The crown of the tree in the third row and fourth column.
+        let r = Ray::new(
+            Point::from(6.0, 1.0, -10.0),
+            Vector::from(0.0, 0.0, 1.0),
+            0.0,
+        );
+        let forest = Hittable::List(forest);
+        let hit = forest
+            .hit(&r, 0.001, f64::INFINITY, &mut rand::thread_rng())
+            .unwrap();
+        assert!((hit.p - Point::from(6.0, 1.0, -0.5)).near_zero());
+    }
+
+    fn cutout(opacity: Texture, alpha: AlphaMode) -> Material {
+        Material::new_cutout(Material::new_lambertian(Color::new()), opacity, alpha)
+    }
+
+    #[test]
+    fn test_cutout_reveals_the_back_face() {
+        // The luminance of the UV texture is 0.41 on the front and 0.52 on the back.
+        let sphere = Hittable::new_sphere(
+            Point::new(),
+            1.0,
+            cutout(Texture::UV, AlphaMode::Threshold(0.45)),
+        );
+        let r = Ray::new(
+            Point::from(0.0, 0.0, 5.0),
+            Vector::from(0.0, 0.0, -1.0),
+            0.0,
+        );
+        let hit = sphere
+            .hit(&r, 0.001, f64::INFINITY, &mut rand::thread_rng())
+            .unwrap();
+        assert!((hit.t - 6.0).abs() < 1e-8);
+        assert!(!hit.front_face);
+    }
+
+    #[test]
+    fn test_bvh_traverses_past_cutouts() {
+        let mut spheres = vec![
+            Hittable::new_sphere(
+                Point::new(),
+                1.0,
+                cutout(Texture::Solid(Color::new()), AlphaMode::Threshold(0.5)),
+            ),
+            Hittable::new_sphere(
+                Point::from(0.0, 0.0, -3.0),
+                1.0,
+                Material::new_lambertian(Color::new()),
+            ),
+        ];
+        let bvh = Hittable::new_bvh_sah(&mut spheres, 0.0, 1.0);
+        let r = Ray::new(
+            Point::from(0.0, 0.0, 5.0),
+            Vector::from(0.0, 0.0, -1.0),
+            0.0,
+        );
+        let hit = bvh
+            .hit(&r, 0.001, f64::INFINITY, &mut rand::thread_rng())
+            .unwrap();
+        assert!((hit.t - 7.0).abs() < 1e-8);
+    }
+
+    #[test]
+    fn test_ray_from_inside_cube_hits_far_face() {
+        let cube = Hittable::new_cube(
+            Point::from(-1.0, -1.0, -1.0),
+            Point::from(1.0, 1.0, 1.0),
+            Material::new_lambertian(Color::new()),
+        );
+        let r = Ray::new(Point::new(), Vector::from(0.0, 0.0, -1.0), 0.0);
+        let hit = cube
+            .hit(&r, 0.001, f64::INFINITY, &mut rand::thread_rng())
+            .unwrap();
+        assert!((hit.p - Point::from(0.0, 0.0, -1.0)).near_zero());
+        assert!(!hit.front_face);
+
+        let r = Ray::new(
+            Point::from(0.0, 0.0, -5.0),
+            Vector::from(0.0, 0.0, 1.0),
+            0.0,
+        );
+        let hit = cube
+            .hit(&r, 0.001, f64::INFINITY, &mut rand::thread_rng())
+            .unwrap();
+        assert!(hit.front_face);
+        assert!((hit.normal - Vector::from(0.0, 0.0, -1.0)).near_zero());
+    }
+
+    #[test]
+    fn test_stochastic_cutout() {
+        let triangle = Hittable::new_triangle(
+            Point::from(-10.0, -10.0, 0.0),
+            Point::from(10.0, -10.0, 0.0),
+            Point::from(0.0, 10.0, 0.0),
+            cutout(
+                Texture::Solid(Color::from_scalar(0.3)),
+                AlphaMode::Stochastic,
+            ),
+        );
+        let mut rng = rand::thread_rng();
+        let rays = (0..4000)
+            .map(|i| {
+                let x = (i % 64) as f64 / 64.0;
+                let y = (i / 64) as f64 / 64.0;
+                Ray::new(Point::from(x, y, 1.0), Vector::from(0.0, 0.0, -1.0), 0.0)
+            })
+            .collect::<Vec<_>>();
+        let hits = rays
+            .iter()
+            .filter(|r| triangle.hit(r, 0.001, f64::INFINITY, &mut rng).is_some())
+            .count();
+        assert!((hits as f64 / rays.len() as f64 - 0.3).abs() < 0.03);
+        // The same ray always sees the same holes.
+        for r in &rays[..100] {
+            let first = triangle.hit(r, 0.001, f64::INFINITY, &mut rng).is_some();
+            assert_eq!(
+                triangle.hit(r, 0.001, f64::INFINITY, &mut rng).is_some(),
+                first
+            );
+        }
+    }
+
+    #[test]
+    fn test_rotated_box_is_tight() {
+        let cube = Hittable::new_cube(
+            Point::from(-1.0, -1.0, -1.0),
+            Point::from(1.0, 1.0, 1.0),
+            Material::new_lambertian(Color::new()),
+        );
+        let rotated = Hittable::new_rotate(cube, 45f64.to_radians(), Vector::from(0.0, 1.0, 0.0));
+        let aabb = rotated.bounding_box(0.0, 1.0).unwrap();
+        let extent = std::f64::consts::SQRT_2;
+        assert!((aabb.minimum - Point::from(-extent, -1.0, -extent)).near_zero());
+        assert!((aabb.maximum - Point::from(extent, 1.0, extent)).near_zero());
+    }
+
+    #[test_case(Point::from(0.5, 0.5, 1.0), Some(Vec2::from(0.25, 0.5)))]
+    #[test_case(Point::from(1.5, 0.25, -1.0), Some(Vec2::from(0.75, 0.25)))]
+    #[test_case(Point::from(2.5, 0.5, 1.0), None)]
+    #[test_case(Point::from(0.5, -0.5, 1.0), None)]
+    fn test_quad_uv(origin: Point, uv: Option<Vec2<f64>>) {
+        let quad = Hittable::new_quad(
+            Point::new(),
+            Vector::from(2.0, 0.0, 0.0),
+            Vector::from(0.0, 1.0, 0.0),
+            Material::new_lambertian(Color::new()),
+        );
+        let r = Ray::new(origin, Vector::from(0.0, 0.0, -origin[2]), 0.0);
+        let hit = quad.hit(&r, 0.001, f64::INFINITY, &mut rand::thread_rng());
+        assert_eq!(hit.is_some(), uv.is_some());
+        if let (Some(hit), Some(uv)) = (hit, uv) {
+            assert!((hit.t - 1.0).abs() < 1e-9);
+            assert!((hit.uv - uv).near_zero());
+            assert_eq!(hit.front_face, origin[2] > 0.0);
+        }
+    }
+
+    #[test]
+    fn test_planes_are_kept_out_of_the_bvh() {
+        let material = Arc::new(Material::new_lambertian(Color::new()));
+        let mut hittables = vec![
+            Hittable::new_sphere(Point::from(0.0, 1.0, 0.0), 1.0, material.clone()),
+            Hittable::new_sphere(Point::from(4.0, 1.0, 0.0), 1.0, material.clone()),
+            Hittable::new_plane(Point::new(), Vector::from(0.0, 1.0, 0.0), material),
+        ];
+        let mut rng = rand::thread_rng();
+        let world = Hittable::new_bvh(&mut hittables, 0.0, 1.0, &mut rng);
+        assert!(matches!(world, Hittable::List(ref list) if list.len() == 2));
+        assert!(world.bounding_box(0.0, 1.0).is_none());
+
+        let r = Ray::new(
+            Point::from(100.0, 1.0, 0.0),
+            Vector::from(0.0, -1.0, 0.0),
+            0.0,
+        );
+        let hit = world.hit(&r, 0.001, f64::INFINITY, &mut rng).unwrap();
+        assert!((hit.t - 1.0).abs() < 1e-9);
+        let r = Ray::new(
+            Point::from(-100.0, 1.0, 0.0),
+            Vector::from(1.0, 0.0, 0.0),
+            0.0,
+        );
+        let hit = world.hit(&r, 0.001, f64::INFINITY, &mut rng).unwrap();
+        assert!((hit.t - 99.0).abs() < 1e-9);
+    }
+
+    /// Integrates the density of sampling `light` from `origin` over the sphere of directions.
+    fn integrate_light_pdf(light: &Hittable, origin: Point) -> f64 {
+        let (n_theta, n_phi) = (400, 800);
+        let mut rng = rand::thread_rng();
+        let mut sum = 0.0;
+        for i in 0..n_theta {
+            let z = 1.0 - 2.0 * (i as f64 + 0.5) / n_theta as f64;
+            let r = (1.0 - z * z).sqrt();
+            for j in 0..n_phi {
+                let phi = 2.0 * std::f64::consts::PI * (j as f64 + 0.5) / n_phi as f64;
+                let direction = Vector::from(r * phi.cos(), r * phi.sin(), z);
+                sum += light.pdf_value(&origin, &direction, &mut rng);
+            }
+        }
+        sum * 4.0 * std::f64::consts::PI / (n_theta * n_phi) as f64
+    }
+
+    #[test]
+    fn test_area_light_pdfs_integrate_to_one() {
+        let material = Material::new_diffuse_light(Color::from(1.0, 1.0, 1.0));
+        let quad = Hittable::new_quad(
+            Point::from(-1.0, 2.0, -0.5),
+            Vector::from(2.0, 0.0, 0.0),
+            Vector::from(0.5, 0.0, 1.5),
+            material.clone(),
+        );
+        let disk = Hittable::new_disk(
+            Point::from(0.0, 2.0, 0.0),
+            Vector::from(0.0, -1.0, 0.5),
+            1.0,
+            material,
+        );
+        for light in [&quad, &disk] {
+            let integral = integrate_light_pdf(light, Point::new());
+            assert!((integral - 1.0).abs() < 0.02, "{}", integral);
+
+            // Sampled directions point at the light and carry its density.
+            let mut rng = rand::thread_rng();
+            for i in 0..100 {
+                let u = Vec2::from((i % 10) as f64 / 10.0 + 0.05, (i / 10) as f64 / 10.0 + 0.05);
+                let direction = light.random(&Point::new(), u);
+                assert!(light.pdf_value(&Point::new(), &direction, &mut rng) > 0.0);
+            }
+        }
+    }
 }
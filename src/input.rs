@@ -0,0 +1,179 @@
+use crate::tonemap::*;
+use image::codecs::hdr::HdrDecoder;
+use image::error::{DecodingError, ImageFormatHint};
+use image::io::Reader as ImageReader;
+use image::{ColorType, DynamicImage, ImageBuffer, ImageError, ImageFormat, ImageResult, Rgb};
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+
+/// An image of linear light, or of linear data such as roughness.
+pub type LinearImage = ImageBuffer<Rgb<f32>, Vec<f32>>;
+
+/// Loads an image file into linear light. Integer formats are sRGB decoded unless `linear` is set,
+/// float formats (OpenEXR and Radiance `.hdr`) are always linear and keep their full range.
+pub fn load_image<P>(path: P, linear: bool) -> ImageResult<LinearImage>
+where
+    P: AsRef<Path>,
+{
+    let path = path.as_ref();
+    let is_exr = path
+        .extension()
+        .is_some_and(|e| e.eq_ignore_ascii_case("exr"));
+    if is_exr {
+        load_exr(path)
+    } else if let Ok(ImageFormat::Hdr) = ImageFormat::from_path(path) {
+        load_hdr(path)
+    } else {
+        Ok(linearize(&ImageReader::open(path)?.decode()?, linear))
+    }
+}
+
+/// Loads the alpha channel of an image file as a gray linear image, images without alpha are opaque.
+pub fn load_alpha<P>(path: P) -> ImageResult<LinearImage>
+where
+    P: AsRef<Path>,
+{
+    let image = ImageReader::open(path)?.decode()?;
+    Ok(match image.color() {
+        ColorType::La16 | ColorType::Rgba16 => {
+            let image = image.to_rgba16();
+            LinearImage::from_fn(image.width(), image.height(), |x, y| {
+                Rgb([image.get_pixel(x, y)[3] as f32 / u16::MAX as f32; 3])
+            })
+        }
+        _ => {
+            let image = image.to_rgba8();
+            LinearImage::from_fn(image.width(), image.height(), |x, y| {
+                Rgb([image.get_pixel(x, y)[3] as f32 / u8::MAX as f32; 3])
+            })
+        }
+    })
+}
+
+/// Converts an integer image to linear values, 16-bit images keep their precision.
+pub fn linearize(image: &DynamicImage, linear: bool) -> LinearImage {
+    let table = |max: u32| {
+        (0..=max)
+            .map(|v| {
+                let v = v as f64 / max as f64;
+                (if linear { v } else { srgb_eotf(v) }) as f32
+            })
+            .collect::<Vec<_>>()
+    };
+    match image.color() {
+        ColorType::L16 | ColorType::La16 | ColorType::Rgb16 | ColorType::Rgba16 => {
+            let image = image.to_rgb16();
+            let table = table(u16::MAX as u32);
+            LinearImage::from_fn(image.width(), image.height(), |x, y| {
+                Rgb(image.get_pixel(x, y).0.map(|c| table[c as usize]))
+            })
+        }
+        _ => {
+            let image = image.to_rgb8();
+            let table = table(u8::MAX as u32);
+            LinearImage::from_fn(image.width(), image.height(), |x, y| {
+                Rgb(image.get_pixel(x, y).0.map(|c| table[c as usize]))
+            })
+        }
+    }
+}
+
+fn load_hdr(path: &Path) -> ImageResult<LinearImage> {
+    let decoder = HdrDecoder::new(BufReader::new(File::open(path)?))?;
+    let metadata = decoder.metadata();
+    let pixels = decoder.read_image_hdr()?;
+    Ok(LinearImage::from_fn(
+        metadata.width,
+        metadata.height,
+        |x, y| pixels[(y * metadata.width + x) as usize],
+    ))
+}
+
+fn load_exr(path: &Path) -> ImageResult<LinearImage> {
+    let image = exr::prelude::read_first_rgba_layer_from_file(
+        path,
+        |resolution, _| LinearImage::new(resolution.width() as u32, resolution.height() as u32),
+        |image: &mut LinearImage, position, (r, g, b, _): (f32, f32, f32, f32)| {
+            image.put_pixel(position.x() as u32, position.y() as u32, Rgb([r, g, b]))
+        },
+    )
+    .map_err(|e| {
+        ImageError::Decoding(DecodingError::new(
+            ImageFormatHint::PathExtension(path.into()),
+            e,
+        ))
+    })?;
+    Ok(image.layer_data.channel_data.pixels)
+}
+
+/// A file in the temporary directory, unique to the test process, removed when dropped.
+#[cfg(test)]
+pub(crate) struct TempFile(pub std::path::PathBuf);
+
+#[cfg(test)]
+impl TempFile {
+    pub fn new(name: &str) -> Self {
+        let name = format!("raytracer_{}_{}", std::process::id(), name);
+        Self(std::env::temp_dir().join(name))
+    }
+}
+
+#[cfg(test)]
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_linearize_decodes_srgb() {
+        let image = DynamicImage::ImageRgb8(ImageBuffer::from_pixel(1, 1, Rgb([188, 0, 255])));
+        let pixel = linearize(&image, false).get_pixel(0, 0).0;
+        assert!((pixel[0] - 0.5029).abs() < 1e-3);
+        assert_eq!(pixel[1], 0.0);
+        assert_eq!(pixel[2], 1.0);
+        let pixel = linearize(&image, true).get_pixel(0, 0).0;
+        assert!((pixel[0] - 188.0 / 255.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_linearize_keeps_16_bits() {
+        let image = DynamicImage::ImageRgb16(ImageBuffer::from_pixel(1, 1, Rgb([1, 2, 65535])));
+        let pixel = linearize(&image, true).get_pixel(0, 0).0;
+        assert_eq!(pixel, [1.0 / 65535.0, 2.0 / 65535.0, 1.0]);
+    }
+
+    #[test]
+    fn test_load_float_formats() {
+        let exr = TempFile::new("test_load_float_formats.exr");
+        exr::prelude::write_rgb_file(&exr.0, 2, 1, |x, _| (4.0 * x as f32, 0.25f32, 0.5f32))
+            .unwrap();
+        let image = load_image(&exr.0, false).unwrap();
+        assert_eq!(image.dimensions(), (2, 1));
+        assert_eq!(image.get_pixel(1, 0).0, [4.0, 0.25, 0.5]);
+
+        let hdr = TempFile::new("test_load_float_formats.hdr");
+        let file = std::io::BufWriter::new(File::create(&hdr.0).unwrap());
+        image::codecs::hdr::HdrEncoder::new(file)
+            .encode(&[Rgb([8.0, 0.5, 0.25]), Rgb([0.0, 0.0, 0.0])], 2, 1)
+            .unwrap();
+        let image = load_image(&hdr.0, false).unwrap();
+        assert_eq!(image.get_pixel(0, 0).0, [8.0, 0.5, 0.25]);
+    }
+
+    #[test]
+    fn test_load_alpha() {
+        let png = TempFile::new("test_load_alpha.png");
+        image::RgbaImage::from_fn(2, 1, |x, _| image::Rgba([255, 255, 255, (x * 255) as u8]))
+            .save(&png.0)
+            .unwrap();
+        let alpha = load_alpha(&png.0).unwrap();
+        assert_eq!(alpha.get_pixel(0, 0).0, [0.0; 3]);
+        assert_eq!(alpha.get_pixel(1, 0).0, [1.0; 3]);
+    }
+}
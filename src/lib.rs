@@ -0,0 +1,43 @@
+#![warn(clippy::all)]
+
+//! A simple raytracer inspired by Peter Shirley's Ray Tracing book series.
+//!
+//! Build a [`Scene`], either in code or from a scene file, and render it
+//! with a [`Renderer`] into a floating point [`Framebuffer`].
+
+pub mod aabb;
+pub mod alias;
+pub mod bvh;
+pub mod cam;
+pub mod csg;
+pub mod heightfield;
+pub mod hittable;
+pub mod input;
+pub mod material;
+pub mod mesh;
+pub mod mipmap;
+pub mod obj;
+pub mod output;
+pub mod pdf;
+pub mod perlin;
+pub mod quadric;
+pub mod ray;
+pub mod render;
+pub mod sampler;
+pub mod scene;
+pub mod scenes;
+pub mod sdf;
+pub mod texture;
+pub mod tonemap;
+pub mod worley;
+
+pub use crate::bvh::{BvhSplit, BvhStats, InstanceBvh, InstanceIndexError};
+pub use crate::cam::{Camera, CameraSettings};
+pub use crate::hittable::Hittable;
+pub use crate::material::Material;
+pub use crate::output::{ExrPrecision, OutputFormat};
+pub use crate::render::{Framebuffer, Renderer};
+pub use crate::sampler::{Sampler, SamplerKind};
+pub use crate::scene::{Scene, SceneError};
+pub use crate::texture::Texture;
+pub use crate::tonemap::{ToneMapOperator, ToneMapping};
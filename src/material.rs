@@ -1,9 +1,12 @@
 use crate::alias::*;
 use crate::hittable::*;
+use crate::pdf::PDF;
 use crate::ray::*;
+use crate::sampler::*;
 use crate::texture::*;
-use crate::PDF;
+use std::sync::Arc;
 
+#[allow(clippy::upper_case_acronyms)]
 pub enum ScatterRecord<'a> {
     Specular { ray: Ray, attenuation: Color },
     PDF { pdf: PDF<'a>, attenuation: Color },
@@ -20,6 +23,118 @@ pub struct Metal {
     pub fuzz: f64,
 }
 
+/// How a texture perturbs the shading normal.
+#[derive(Clone)]
+pub enum Bump {
+    /// A tangent space normal map, its colors in [0,1] map to normal components in [-1,1]
+    /// along the u and v tangents and the normal. The texture should be linear.
+    NormalMap(Texture),
+    /// A height map, the luminance of the texture displaces the surface by `scale` along its normal.
+    HeightMap { texture: Texture, scale: f64 },
+}
+
+/// Shades with `material` after perturbing the normal with `bump`.
+#[derive(Clone)]
+pub struct Bumped {
+    pub material: Arc<Material>,
+    pub bump: Bump,
+}
+
+impl Bumped {
+    fn perturb<'a>(&'a self, hit: &HitRecord<'a>) -> HitRecord<'a> {
+        let n = hit.normal;
+        // Surfaces without a parametrization get an arbitrary frame.
+        let (tangent, bitangent) = if hit.tangent.cross(hit.bitangent).near_zero() {
+            let onb = ONB::from_w(&n);
+            (onb.u(), onb.v())
+        } else {
+            (hit.tangent, hit.bitangent)
+        };
+        let normal = match self.bump {
+            Bump::NormalMap(ref texture) => {
+                let t = (tangent - n * n.dot(tangent)).normalize();
+                let b = n.cross(t);
+                // Follow the handedness of the parametrization.
+                let b = if b.dot(bitangent) < 0.0 { -b } else { b };
+                let m = 2.0 * texture.value(&hit.uv, &hit.p, &n, hit.footprint)
+                    - Color::from_scalar(1.0);
+                (m[0] * t + m[1] * b + m[2] * n).normalize()
+            }
+            Bump::HeightMap { ref texture, scale } => {
+                // Displace along the outward normal, so both sides see the same bumps.
+                let outward = if hit.front_face { n } else { -n };
+                let height = |uv: Vec2<f64>, p: Point| {
+                    scale * luminance(&texture.value(&uv, &p, &n, hit.footprint))
+                };
+                // Finite differences over about the footprint of the ray.
+                let d = (0.5 * hit.footprint).max(5e-4);
+                let h = height(hit.uv, hit.p);
+                let du = height(hit.uv + Vec2::from(d, 0.0), hit.p + d * tangent) - h;
+                let dv = height(hit.uv + Vec2::from(0.0, d), hit.p + d * bitangent) - h;
+                let m = (tangent + du / d * outward)
+                    .cross(bitangent + dv / d * outward)
+                    .normalize();
+                if m.dot(n) < 0.0 {
+                    -m
+                } else {
+                    m
+                }
+            }
+        };
+        HitRecord {
+            normal,
+            material: &self.material,
+            ..*hit
+        }
+    }
+}
+
+/// How the opacity of a cutout decides whether a ray passes through the surface.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AlphaMode {
+    /// Surfaces with an opacity below the threshold are cut away, for sharp edges.
+    Threshold(f64),
+    /// Rays pass with a probability of one minus the opacity, for soft edges and translucency.
+    Stochastic,
+}
+
+/// Cuts holes into `material` where the luminance of `opacity` is low.
+#[derive(Clone)]
+pub struct Cutout {
+    pub material: Arc<Material>,
+    pub opacity: Texture,
+    pub alpha: AlphaMode,
+}
+
+impl Cutout {
+    fn cuts_out(&self, r: &Ray, hit: &HitRecord) -> bool {
+        let opacity = luminance(
+            &self
+                .opacity
+                .value(&hit.uv, &hit.p, &hit.normal, hit.footprint),
+        );
+        match self.alpha {
+            AlphaMode::Threshold(threshold) => opacity < threshold,
+            AlphaMode::Stochastic => {
+                // Hash the ray instead of drawing a sample, so that every query of the
+                // same ray, like a light sample and its pdf, sees the same holes.
+                let bits = [
+                    r.origin[0],
+                    r.origin[1],
+                    r.origin[2],
+                    r.direction[0],
+                    r.direction[1],
+                    r.direction[2],
+                    hit.t,
+                ]
+                .map(f64::to_bits);
+                let u = (hash(&bits) >> 11) as f64 / (1u64 << 53) as f64;
+                u >= opacity
+            }
+        }
+    }
+}
+
 #[derive(Clone)]
 pub enum Material {
     Lambertian(Texture),
@@ -27,6 +142,8 @@ pub enum Material {
     Metal(Metal),
     DiffuseLight(Texture),
     Isotropic(Texture),
+    Bumped(Bumped),
+    Cutout(Cutout),
 }
 
 impl Material {
@@ -54,10 +171,70 @@ impl Material {
         Self::Isotropic(Texture::Solid(albedo))
     }
 
-    pub fn scatter(&self, r: &Ray, hit: &HitRecord) -> Option<ScatterRecord> {
+    pub fn new_normal_mapped(material: impl Into<Arc<Material>>, normal_map: Texture) -> Self {
+        Self::Bumped(Bumped {
+            material: material.into(),
+            bump: Bump::NormalMap(normal_map),
+        })
+    }
+
+    pub fn new_bump_mapped(
+        material: impl Into<Arc<Material>>,
+        height_map: Texture,
+        scale: f64,
+    ) -> Self {
+        Self::Bumped(Bumped {
+            material: material.into(),
+            bump: Bump::HeightMap {
+                texture: height_map,
+                scale,
+            },
+        })
+    }
+
+    pub fn new_cutout(
+        material: impl Into<Arc<Material>>,
+        opacity: Texture,
+        alpha: AlphaMode,
+    ) -> Self {
+        Self::Cutout(Cutout {
+            material: material.into(),
+            opacity,
+            alpha,
+        })
+    }
+
+    /// Whether `r` passes through the surface at `hit`.
+    pub fn cuts_out(&self, r: &Ray, hit: &HitRecord) -> bool {
+        match *self {
+            Self::Bumped(ref bumped) => bumped.material.cuts_out(r, hit),
+            Self::Cutout(ref cutout) => cutout.cuts_out(r, hit) || cutout.material.cuts_out(r, hit),
+            _ => false,
+        }
+    }
+
+    /// Applies normal and bump maps to `hit`, and replaces its material by the one
+    /// that shades the perturbed hit.
+    pub fn shade<'a>(&'a self, hit: HitRecord<'a>) -> HitRecord<'a> {
+        match *self {
+            Self::Bumped(ref bumped) => bumped.material.shade(bumped.perturb(&hit)),
+            Self::Cutout(ref cutout) => cutout.material.shade(HitRecord {
+                material: &cutout.material,
+                ..hit
+            }),
+            _ => hit,
+        }
+    }
+
+    pub fn scatter(
+        &self,
+        r: &Ray,
+        hit: &HitRecord,
+        sampler: &mut Sampler,
+    ) -> Option<ScatterRecord<'_>> {
         match *self {
             Self::Lambertian(ref texture) => {
-                let albedo = texture.value(&hit.uv, &hit.p, &hit.normal);
+                let albedo = texture.value(&hit.uv, &hit.p, &hit.normal, hit.footprint);
                 Some(ScatterRecord::PDF {
                     attenuation: albedo,
                     pdf: PDF::Cosine(ONB::from_w(&hit.normal)),
@@ -74,34 +251,37 @@ impl Material {
                 let sin_theta = (1.0 - cos_theta * cos_theta).sqrt();
                 let cannot_refract = refraction_ratio * sin_theta > 1.0;
                 let direction = if cannot_refract
-                    || reflectance(cos_theta, refraction_ratio) > rand::random()
+                    || reflectance(cos_theta, refraction_ratio) > sampler.get_1d()
                 {
                     unit_direction.reflect(&hit.normal)
                 } else {
                     unit_direction.refract(&hit.normal, refraction_ratio)
                 };
                 Some(ScatterRecord::Specular {
-                    ray: Ray::new(hit.p, direction, r.time),
+                    ray: Ray::new(hit.p, direction, r.time).with_cone(r.width_at(hit.t), r.spread),
                     attenuation: Color::from(1.0, 1.0, 1.0),
                 })
             }
             Self::Metal(ref metal) => {
                 let reflected = r.direction.normalize().reflect(&hit.normal);
-                let direction = reflected + metal.fuzz * Point::random_in_unit_sphere();
+                let direction = reflected
+                    + metal.fuzz * Point::sample_in_unit_sphere(sampler.get_2d(), sampler.get_1d());
                 Some(ScatterRecord::Specular {
-                    ray: Ray::new(hit.p, direction, r.time),
+                    ray: Ray::new(hit.p, direction, r.time).with_cone(r.width_at(hit.t), r.spread),
                     attenuation: metal.albedo,
                 })
             }
             Self::DiffuseLight(_) => None,
             Self::Isotropic(ref texture) => Some(ScatterRecord::PDF {
-                attenuation: texture.value(&hit.uv, &hit.p, &hit.normal),
+                attenuation: texture.value(&hit.uv, &hit.p, &hit.normal, hit.footprint),
                 pdf: PDF::Cosine(ONB::from_w(&hit.normal)),
             }),
+            Self::Bumped(ref bumped) => bumped.material.scatter(r, &bumped.perturb(hit), sampler),
+            Self::Cutout(ref cutout) => cutout.material.scatter(r, hit, sampler),
         }
     }
 
-    pub fn scattering_pdf(&self, r: &Ray, hit: &HitRecord, scattered: &Ray) -> f64 {
+    pub fn scattering_pdf(&self, _r: &Ray, hit: &HitRecord, scattered: &Ray) -> f64 {
         match *self {
             Self::Lambertian(_) => {
                 let cosine = hit.normal.dot(scattered.direction.normalize());
@@ -111,6 +291,12 @@ impl Material {
                     cosine / std::f64::consts::PI
                 }
             }
+            Self::Bumped(ref bumped) => {
+                bumped
+                    .material
+                    .scattering_pdf(_r, &bumped.perturb(hit), scattered)
+            }
+            Self::Cutout(ref cutout) => cutout.material.scattering_pdf(_r, hit, scattered),
             _ => 1.0,
         }
     }
@@ -118,8 +304,10 @@ impl Material {
     pub fn emit(&self, hit: &HitRecord) -> Color {
         match *self {
             Self::DiffuseLight(ref texture) if hit.front_face => {
-                texture.value(&hit.uv, &hit.p, &hit.normal)
+                texture.value(&hit.uv, &hit.p, &hit.normal, hit.footprint)
             }
+            Self::Bumped(ref bumped) => bumped.material.emit(&bumped.perturb(hit)),
+            Self::Cutout(ref cutout) => cutout.material.emit(hit),
             _ => Color::new(),
         }
     }
@@ -131,3 +319,57 @@ fn reflectance(cosine: f64, ref_idx: f64) -> f64 {
     let r0 = r0 * r0;
     r0 + (1.0 - r0) * (1.0 - cosine).powi(5)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_case::test_case;
+
+    /// Shades a hit on a triangle in the xy plane, with u along x and v along y.
+    fn shade_triangle(material: Material) -> Vector {
+        let triangle = Hittable::new_triangle(
+            Point::new(),
+            Point::from(1.0, 0.0, 0.0),
+            Point::from(0.0, 1.0, 0.0),
+            material,
+        );
+        let r = Ray::new(
+            Point::from(0.25, 0.25, 1.0),
+            Vector::from(0.0, 0.0, -1.0),
+            0.0,
+        );
+        let hit = triangle
+            .hit(&r, 0.001, f64::INFINITY, &mut rand::thread_rng())
+            .unwrap();
+        let hit = hit.material.shade(hit);
+        assert!(matches!(hit.material, Material::Lambertian(_)));
+        hit.normal
+    }
+
+    #[test_case(Color::from(0.5, 0.5, 1.0), Vector::from(0.0, 0.0, 1.0))]
+    #[test_case(Color::from(1.0, 0.5, 0.5), Vector::from(1.0, 0.0, 0.0))]
+    #[test_case(Color::from(0.5, 0.0, 1.0), Vector::from(0.0, -1.0, 1.0))]
+    fn test_normal_map(color: Color, normal: Vector) {
+        let material = Material::new_normal_mapped(
+            Material::new_lambertian(Color::new()),
+            Texture::Solid(color),
+        );
+        assert!((shade_triangle(material) - normal.normalize()).near_zero());
+    }
+
+    #[test]
+    fn test_bump_map_tilts_against_the_slope() {
+        // The luminance of the UV texture is 0.2126 u + 0.7152 v.
+        let material =
+            Material::new_bump_mapped(Material::new_lambertian(Color::new()), Texture::UV, 0.5);
+        let normal = Vector::from(-0.5 * 0.2126, -0.5 * 0.7152, 1.0).normalize();
+        assert!((shade_triangle(material) - normal).near_zero());
+
+        let flat = Material::new_bump_mapped(
+            Material::new_lambertian(Color::new()),
+            Texture::Solid(Color::from_scalar(0.3)),
+            0.5,
+        );
+        assert!((shade_triangle(flat) - Vector::from(0.0, 0.0, 1.0)).near_zero());
+    }
+}
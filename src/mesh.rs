@@ -0,0 +1,260 @@
+use crate::aabb::AxisAlignedBoundingBox;
+use crate::alias::*;
+use crate::bvh::{BvhNodes, BvhSplit};
+use crate::hittable::*;
+use crate::material::*;
+use crate::ray::*;
+use std::sync::Arc;
+
+/// Indices of one triangle corner into the vertex buffers of a mesh.
+#[derive(Clone, Copy, Default)]
+pub struct VertexIndex {
+    pub position: usize,
+    pub normal: Option<usize>,
+    pub uv: Option<usize>,
+}
+
+impl VertexIndex {
+    pub fn new(position: usize, normal: Option<usize>, uv: Option<usize>) -> Self {
+        Self {
+            position,
+            normal,
+            uv,
+        }
+    }
+}
+
+/// A triangle mesh with shared vertex, normal and uv buffers.
+/// The mesh keeps its own BVH over its faces, so it acts as a single
+/// primitive for the scene BVH.
+#[derive(Clone)]
+pub struct Mesh {
+    positions: Vec<Point>,
+    normals: Vec<Vector>,
+    uvs: Vec<Vec2<f64>>,
+    faces: Vec<[VertexIndex; 3]>,
+    nodes: BvhNodes,
+    material: Arc<Material>,
+}
+
+impl Mesh {
+    /// Builds the mesh BVH over `faces`, which must not be empty.
+    /// `Hittable::new_mesh` checks this and returns `Hittable::Empty` instead.
+    pub(crate) fn new(
+        positions: Vec<Point>,
+        normals: Vec<Vector>,
+        uvs: Vec<Vec2<f64>>,
+        faces: Vec<[VertexIndex; 3]>,
+        material: impl Into<Arc<Material>>,
+    ) -> Self {
+        let boxes = faces
+            .iter()
+            .map(|face| triangle_bounding_box(&face.map(|index| positions[index.position])))
+            .collect();
+        let (nodes, order) = BvhNodes::new(boxes, BvhSplit::Sah, None);
+        Self {
+            faces: order.into_iter().map(|i| faces[i]).collect(),
+            positions,
+            normals,
+            uvs,
+            nodes,
+            material: material.into(),
+        }
+    }
+
+    fn face_vertices(&self, face: &[VertexIndex; 3]) -> [Point; 3] {
+        face.map(|index| self.positions[index.position])
+    }
+
+    pub fn bounding_box(&self) -> AxisAlignedBoundingBox {
+        self.nodes.bounding_box().clone()
+    }
+
+    pub fn hit(&self, r: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord<'_>> {
+        self.nodes.hit(r, t_min, t_max, |i, closest_so_far| {
+            self.hit_face(&self.faces[i], r, t_min, closest_so_far)
+        })
+    }
+
+    fn hit_face(
+        &self,
+        face: &[VertexIndex; 3],
+        r: &Ray,
+        t_min: f64,
+        t_max: f64,
+    ) -> Option<HitRecord<'_>> {
+        let vertices = self.face_vertices(face);
+        let (t, barycentric) = intersect_triangle(r, &vertices, t_min, t_max)?;
+        let [p0, p1, p2] = vertices;
+        let outward_normal = (p1 - p0).cross(p2 - p0).normalize();
+
+        let shading_normal = match face.map(|index| index.normal) {
+            [Some(n0), Some(n1), Some(n2)] => (barycentric[0] * self.normals[n0]
+                + barycentric[1] * self.normals[n1]
+                + barycentric[2] * self.normals[n2])
+                .normalize(),
+            _ => outward_normal,
+        };
+        // Twice the area of the face, in space and in texture coordinates.
+        let area = (p1 - p0).cross(p2 - p0).length();
+        let (dp1, dp2) = (p1 - p0, p2 - p0);
+        let (uv, uv_area, tangents) = match face.map(|index| index.uv) {
+            [Some(uv0), Some(uv1), Some(uv2)] => {
+                let (uv0, uv1, uv2) = (self.uvs[uv0], self.uvs[uv1], self.uvs[uv2]);
+                let (e1, e2) = (uv1 - uv0, uv2 - uv0);
+                let det = e1[0] * e2[1] - e1[1] * e2[0];
+                // Solve dp1 = e1.u dpdu + e1.v dpdv and dp2 = e2.u dpdu + e2.v dpdv,
+                // degenerate texture coordinates leave the tangents unknown.
+                let tangents = if det.abs() > 1e-12 {
+                    (
+                        (e2[1] * dp1 - e1[1] * dp2) / det,
+                        (e1[0] * dp2 - e2[0] * dp1) / det,
+                    )
+                } else {
+                    (Vector::new(), Vector::new())
+                };
+                (
+                    uv0 * barycentric[0] + uv1 * barycentric[1] + uv2 * barycentric[2],
+                    det.abs(),
+                    tangents,
+                )
+            }
+            _ => (Vec2::from(barycentric[1], barycentric[2]), 1.0, (dp1, dp2)),
+        };
+
+        let hit = HitRecord::new_with_shading_normal(
+            r,
+            r.at(t),
+            t,
+            uv,
+            outward_normal,
+            shading_normal,
+            &self.material,
+        )
+        .with_footprint(r, (uv_area / area).sqrt())
+        .with_tangents(tangents.0, tangents.1);
+        // The traversal continues to the faces behind a cut out face.
+        if hit.material.cuts_out(r, &hit) {
+            None
+        } else {
+            Some(hit)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    fn quad() -> [Point; 4] {
+        [
+            Point::from(-1.0, -1.0, 0.0),
+            Point::from(1.0, -1.0, 0.0),
+            Point::from(1.0, 1.0, 0.0),
+            Point::from(-1.0, 1.0, 0.0),
+        ]
+    }
+
+    #[test]
+    fn test_exponentially_spaced_faces() {
+        // Every split only separates the farthest face, the depth still stays bounded.
+        let mut positions = Vec::new();
+        let mut faces = Vec::new();
+        for i in 0..2000 {
+            let x = 1.05f64.powi(i);
+            let start = positions.len();
+            positions.push(Point::from(x, -1.0, -1.0));
+            positions.push(Point::from(x, 1.0, -1.0));
+            positions.push(Point::from(x, 0.0, 1.0));
+            faces.push([0, 1, 2].map(|k| VertexIndex::new(start + k, None, None)));
+        }
+        let mesh = Mesh::new(
+            positions,
+            Vec::new(),
+            Vec::new(),
+            faces,
+            Material::new_lambertian(Color::new()),
+        );
+        assert!(mesh.nodes.stats().depth <= crate::bvh::MAX_DEPTH);
+
+        let r = Ray::new(Point::new(), Vector::from(1.0, 0.0, 0.0), 0.0);
+        let hit = mesh.hit(&r, 0.001, f64::INFINITY).unwrap();
+        assert!((hit.t - 1.0).abs() < 1e-9);
+        let r = Ray::new(
+            Point::from(1e30, 0.0, 0.0),
+            Vector::from(-1.0, 0.0, 0.0),
+            0.0,
+        );
+        let hit = mesh.hit(&r, 0.001, f64::INFINITY).unwrap();
+        assert!(hit.p[0] < 1e30 && hit.p[0] > 1e29, "{:?}", hit.p);
+    }
+
+    #[test]
+    fn test_mesh_interpolates_normals_and_uvs() {
+        let [p0, p1, p2, _] = quad();
+        let mesh = Mesh::new(
+            vec![p0, p1, p2],
+            vec![
+                Vector::from(-1.0, 0.0, 1.0).normalize(),
+                Vector::from(1.0, 0.0, 1.0).normalize(),
+                Vector::from(1.0, 0.0, 1.0).normalize(),
+            ],
+            vec![
+                Vec2::from(0.0, 0.0),
+                Vec2::from(1.0, 0.0),
+                Vec2::from(1.0, 1.0),
+            ],
+            vec![[
+                VertexIndex::new(0, Some(0), Some(0)),
+                VertexIndex::new(1, Some(1), Some(1)),
+                VertexIndex::new(2, Some(2), Some(2)),
+            ]],
+            Material::new_lambertian(Color::new()),
+        );
+
+        let r = Ray::new(
+            Point::from(0.0, -0.5, 5.0),
+            Vector::from(0.0, 0.0, -1.0),
+            0.0,
+        );
+        let hit = mesh.hit(&r, 0.0, f64::INFINITY).unwrap();
+        assert!(hit.front_face);
+        assert!((hit.t - 5.0).abs() < 1e-8);
+        assert!((hit.uv - Vec2::from(0.5, 0.25)).near_zero());
+        assert!((hit.normal - Vector::from(0.0, 0.0, 1.0)).near_zero());
+        assert!((hit.tangent - Vector::from(2.0, 0.0, 0.0)).near_zero());
+        assert!((hit.bitangent - Vector::from(0.0, 2.0, 0.0)).near_zero());
+    }
+
+    #[test]
+    fn test_mesh_without_faces_is_empty() {
+        let mesh = Hittable::new_mesh(
+            quad().to_vec(),
+            Vec::new(),
+            Vec::new(),
+            Vec::new(),
+            Material::new_lambertian(Color::new()),
+        );
+        assert!(matches!(mesh, Hittable::Empty));
+        assert!(mesh.bounding_box(0.0, 1.0).is_none());
+    }
+
+    proptest! {
+        #[test]
+        fn test_shared_edge_is_watertight(
+            s in 0.0f64..1.0,
+            x in -10.0f64..10.0,
+            y in -10.0f64..10.0,
+        ) {
+            let [p0, p1, p2, p3] = quad();
+            let target = p0 + s * (p2 - p0);
+            let origin = Point::from(x, y, 3.0);
+            let r = Ray::new(origin, target - origin, 0.0);
+
+            let first = intersect_triangle(&r, &[p0, p1, p2], 0.0, f64::INFINITY);
+            let second = intersect_triangle(&r, &[p0, p2, p3], 0.0, f64::INFINITY);
+            prop_assert!(first.is_some() || second.is_some());
+        }
+    }
+}
@@ -0,0 +1,314 @@
+use crate::alias::*;
+use crate::input::*;
+use serde::Deserialize;
+
+/// Filters with a stronger eccentricity are widened, which bounds the cost of a lookup.
+const MAX_ANISOTROPY: f64 = 8.0;
+
+/// Falloff of the Gaussian used by the EWA filter.
+const EWA_ALPHA: f64 = 2.0;
+
+/// How texels are combined into the color of a lookup.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ImageFilter {
+    /// The texel under the lookup, aliases when minified.
+    Nearest,
+    /// Blends the four texels around the lookup.
+    Bilinear,
+    /// Blends bilinear lookups of the two mipmap levels closest to the footprint.
+    Trilinear,
+    /// Elliptically weighted average with a Gaussian over the footprint, the slowest.
+    /// Ray cones have round footprints, so it only filters anisotropically where the
+    /// texture transform scales u and v differently.
+    Ewa,
+}
+
+/// What a lookup outside of [0,1] texture coordinates returns.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WrapMode {
+    /// Tiles the image.
+    Repeat,
+    /// Tiles the image, flipping every other tile.
+    Mirror,
+    /// Repeats the outermost texels.
+    Clamp,
+    /// Returns the border color.
+    Border,
+}
+
+/// An image and its successively halved versions down to a single texel,
+/// each level is a box filtered version of the previous one. Texels are linear and unbounded.
+pub struct MipMap {
+    levels: Vec<LinearImage>,
+    wrap: WrapMode,
+    border: Color,
+}
+
+impl MipMap {
+    pub fn new(image: LinearImage, wrap: WrapMode, border: Color) -> Self {
+        let mut levels = vec![image];
+        loop {
+            let previous = levels.last().unwrap();
+            let (width, height) = previous.dimensions();
+            if width <= 1 && height <= 1 {
+                break;
+            }
+            let next = LinearImage::from_fn(width.div_ceil(2), height.div_ceil(2), |x, y| {
+                let mut sum = [0.0f32; 3];
+                for (dx, dy) in [(0, 0), (1, 0), (0, 1), (1, 1)] {
+                    // Odd sizes repeat the last row or column.
+                    let pixel = previous
+                        .get_pixel((2 * x + dx).min(width - 1), (2 * y + dy).min(height - 1));
+                    for c in 0..3 {
+                        sum[c] += pixel[c];
+                    }
+                }
+                image::Rgb(sum.map(|c| c / 4.0))
+            });
+            levels.push(next);
+        }
+        Self {
+            levels,
+            wrap,
+            border,
+        }
+    }
+
+    pub fn levels(&self) -> usize {
+        self.levels.len()
+    }
+
+    pub fn width(&self) -> u32 {
+        self.levels[0].width()
+    }
+
+    pub fn height(&self) -> u32 {
+        self.levels[0].height()
+    }
+
+    /// The texel at integer coordinates of `level`, after applying the wrap mode.
+    fn texel(&self, level: usize, x: i64, y: i64) -> Color {
+        let image = &self.levels[level];
+        let x = wrap(x, image.width() as i64, self.wrap);
+        let y = wrap(y, image.height() as i64, self.wrap);
+        match (x, y) {
+            (Some(x), Some(y)) => {
+                let pixel = image.get_pixel(x as u32, y as u32);
+                Color::from(pixel[0] as f64, pixel[1] as f64, pixel[2] as f64)
+            }
+            _ => self.border,
+        }
+    }
+
+    /// Looks up `st`, where s runs from left to right and t from top to bottom of the image.
+    /// `axes` are the half axes of the elliptical footprint of the lookup in st coordinates.
+    pub fn lookup(&self, st: Vec2<f64>, axes: [Vec2<f64>; 2], filter: ImageFilter) -> Color {
+        match filter {
+            ImageFilter::Nearest => {
+                let x = (st[0] * self.width() as f64).floor() as i64;
+                let y = (st[1] * self.height() as f64).floor() as i64;
+                self.texel(0, x, y)
+            }
+            ImageFilter::Bilinear => self.bilinear(0, st),
+            ImageFilter::Trilinear => {
+                let width = 2.0 * axes[0].length().max(axes[1].length());
+                self.trilinear(st, width)
+            }
+            ImageFilter::Ewa => self.ewa(st, axes),
+        }
+    }
+
+    fn bilinear(&self, level: usize, st: Vec2<f64>) -> Color {
+        let image = &self.levels[level];
+        let x = st[0] * image.width() as f64 - 0.5;
+        let y = st[1] * image.height() as f64 - 0.5;
+        let (x0, y0) = (x.floor(), y.floor());
+        let (dx, dy) = (x - x0, y - y0);
+        let (x0, y0) = (x0 as i64, y0 as i64);
+        (1.0 - dx) * (1.0 - dy) * self.texel(level, x0, y0)
+            + dx * (1.0 - dy) * self.texel(level, x0 + 1, y0)
+            + (1.0 - dx) * dy * self.texel(level, x0, y0 + 1)
+            + dx * dy * self.texel(level, x0 + 1, y0 + 1)
+    }
+
+    /// Level of detail where one texel is `texels` texels of the full resolution image wide.
+    fn level_of_detail(&self, texels: f64) -> f64 {
+        texels
+            .max(1e-8)
+            .log2()
+            .clamp(0.0, (self.levels() - 1) as f64)
+    }
+
+    /// Blends the two levels closest to a footprint `width` wide in st coordinates.
+    fn trilinear(&self, st: Vec2<f64>, width: f64) -> Color {
+        let lod = self.level_of_detail(width * self.width().max(self.height()) as f64);
+        let level = lod.floor() as usize;
+        if level + 1 >= self.levels() {
+            return self.bilinear(level, st);
+        }
+        let t = lod - level as f64;
+        (1.0 - t) * self.bilinear(level, st) + t * self.bilinear(level + 1, st)
+    }
+
+    fn ewa(&self, st: Vec2<f64>, axes: [Vec2<f64>; 2]) -> Color {
+        // Measure the axes in texels of the full resolution image.
+        let size = Vec2::from(self.width() as f64, self.height() as f64);
+        let [mut major, mut minor] = axes.map(|axis| axis * size);
+        if major.length() < minor.length() {
+            std::mem::swap(&mut major, &mut minor);
+        }
+        let major_length = major.length();
+        let mut minor_length = minor.length();
+        if minor_length == 0.0 {
+            return self.bilinear(0, st);
+        }
+        // Widen very eccentric ellipses, they would cover too many texels of a fine level.
+        if minor_length * MAX_ANISOTROPY < major_length {
+            let scale = major_length / (minor_length * MAX_ANISOTROPY);
+            minor *= scale;
+            minor_length *= scale;
+        }
+
+        // Pick the levels where the minor axis spans about one texel.
+        let lod = self.level_of_detail(minor_length);
+        let level = lod.floor() as usize;
+        let ewa = |level: usize| {
+            let scale = self.levels[level].width() as f64 / self.width() as f64;
+            self.ewa_level(level, st, major * scale, minor * scale)
+        };
+        if level + 1 >= self.levels() {
+            return ewa(level);
+        }
+        let t = lod - level as f64;
+        (1.0 - t) * ewa(level) + t * ewa(level + 1)
+    }
+
+    /// Filters `level` with a Gaussian over the ellipse spanned by the half axes `a` and `b`, in texels.
+    fn ewa_level(&self, level: usize, st: Vec2<f64>, a: Vec2<f64>, b: Vec2<f64>) -> Color {
+        let image = &self.levels[level];
+        let s = st[0] * image.width() as f64 - 0.5;
+        let t = st[1] * image.height() as f64 - 0.5;
+
+        // Implicit ellipse A s^2 + B s t + C t^2 < 1, grown by a texel so it never falls between texels.
+        let mut ea = a[1] * a[1] + b[1] * b[1] + 1.0;
+        let mut eb = -2.0 * (a[0] * a[1] + b[0] * b[1]);
+        let mut ec = a[0] * a[0] + b[0] * b[0] + 1.0;
+        let inverse_f = 1.0 / (ea * ec - eb * eb * 0.25);
+        ea *= inverse_f;
+        eb *= inverse_f;
+        ec *= inverse_f;
+
+        // Bounding box of the ellipse.
+        let det = -eb * eb + 4.0 * ea * ec;
+        let inverse_det = 1.0 / det;
+        let s_radius = 2.0 * inverse_det * (det * ec).sqrt();
+        let t_radius = 2.0 * inverse_det * (det * ea).sqrt();
+        let (s0, s1) = ((s - s_radius).ceil() as i64, (s + s_radius).floor() as i64);
+        let (t0, t1) = ((t - t_radius).ceil() as i64, (t + t_radius).floor() as i64);
+
+        let mut sum = Color::new();
+        let mut weights = 0.0;
+        for y in t0..=t1 {
+            let dt = y as f64 - t;
+            for x in s0..=s1 {
+                let ds = x as f64 - s;
+                let r2 = ea * ds * ds + eb * ds * dt + ec * dt * dt;
+                if r2 < 1.0 {
+                    let weight = (-EWA_ALPHA * r2).exp() - (-EWA_ALPHA).exp();
+                    sum += weight * self.texel(level, x, y);
+                    weights += weight;
+                }
+            }
+        }
+        if weights > 0.0 {
+            sum / weights
+        } else {
+            self.bilinear(level, st)
+        }
+    }
+}
+
+/// Maps texel index `i` into `0..n`, `None` when it falls on the border.
+fn wrap(i: i64, n: i64, mode: WrapMode) -> Option<i64> {
+    match mode {
+        WrapMode::Repeat => Some(i.rem_euclid(n)),
+        WrapMode::Mirror => {
+            let i = i.rem_euclid(2 * n);
+            Some(if i < n { i } else { 2 * n - 1 - i })
+        }
+        WrapMode::Clamp => Some(i.clamp(0, n - 1)),
+        WrapMode::Border => (0..n).contains(&i).then_some(i),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_case::test_case;
+
+    /// Columns alternate between black and white.
+    fn stripes(width: u32, height: u32) -> LinearImage {
+        LinearImage::from_fn(width, height, |x, _| image::Rgb([(x % 2) as f32; 3]))
+    }
+
+    #[test_case(-1, WrapMode::Repeat, Some(3))]
+    #[test_case(5, WrapMode::Repeat, Some(1))]
+    #[test_case(-1, WrapMode::Mirror, Some(0))]
+    #[test_case(5, WrapMode::Mirror, Some(2))]
+    #[test_case(9, WrapMode::Mirror, Some(1))]
+    #[test_case(-3, WrapMode::Clamp, Some(0))]
+    #[test_case(7, WrapMode::Clamp, Some(3))]
+    #[test_case(-1, WrapMode::Border, None)]
+    #[test_case(2, WrapMode::Border, Some(2))]
+    fn test_wrap(i: i64, mode: WrapMode, result: Option<i64>) {
+        assert_eq!(wrap(i, 4, mode), result);
+    }
+
+    #[test]
+    fn test_pyramid_averages_down_to_one_texel() {
+        let mipmap = MipMap::new(stripes(8, 3), WrapMode::Clamp, Color::new());
+        let sizes = mipmap
+            .levels
+            .iter()
+            .map(|level| level.dimensions())
+            .collect::<Vec<_>>();
+        assert_eq!(sizes, vec![(8, 3), (4, 2), (2, 1), (1, 1)]);
+        assert_eq!(mipmap.levels[1].get_pixel(0, 0)[0], 0.5);
+    }
+
+    #[test_case(ImageFilter::Nearest)]
+    #[test_case(ImageFilter::Bilinear)]
+    #[test_case(ImageFilter::Trilinear)]
+    #[test_case(ImageFilter::Ewa)]
+    fn test_lookup_reproduces_texels(filter: ImageFilter) {
+        let mipmap = MipMap::new(stripes(8, 8), WrapMode::Repeat, Color::new());
+        // Texel centers of the white fourth and the black fifth column, with a tiny footprint.
+        let axes = [Vec2::from(1e-6, 0.0), Vec2::from(0.0, 1e-6)];
+        let white = mipmap.lookup(Vec2::from(3.5 / 8.0, 0.5), axes, filter);
+        let black = mipmap.lookup(Vec2::from(4.5 / 8.0, 0.5), axes, filter);
+        assert!((white - Color::from_scalar(1.0)).near_zero());
+        assert!(black.near_zero());
+    }
+
+    #[test_case(ImageFilter::Trilinear)]
+    #[test_case(ImageFilter::Ewa)]
+    fn test_wide_footprint_averages_stripes(filter: ImageFilter) {
+        let mipmap = MipMap::new(stripes(64, 64), WrapMode::Repeat, Color::new());
+        let axes = [Vec2::from(0.1, 0.0), Vec2::from(0.0, 0.1)];
+        let color = mipmap.lookup(Vec2::from(0.3, 0.6), axes, filter);
+        assert!((color - Color::from_scalar(0.5)).length() < 0.02);
+    }
+
+    #[test]
+    fn test_border_color() {
+        let border = Color::from(1.0, 0.0, 0.0);
+        let mipmap = MipMap::new(stripes(4, 4), WrapMode::Border, border);
+        let axes = [Vec2::from(0.0, 0.0); 2];
+        assert_eq!(
+            mipmap.lookup(Vec2::from(1.5, 0.5), axes, ImageFilter::Nearest),
+            border
+        );
+    }
+}
@@ -0,0 +1,533 @@
+use crate::alias::*;
+use crate::hittable::*;
+use crate::material::*;
+use crate::mesh::*;
+use crate::texture::*;
+use image::ImageError;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+#[derive(Debug)]
+pub enum ObjError {
+    Io(PathBuf, io::Error),
+    Image(PathBuf, ImageError),
+    Parse {
+        path: PathBuf,
+        line: usize,
+        message: String,
+    },
+}
+
+impl fmt::Display for ObjError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Self::Io(ref path, ref error) => write!(f, "{}: {}", path.display(), error),
+            Self::Image(ref path, ref error) => write!(f, "{}: {}", path.display(), error),
+            Self::Parse {
+                ref path,
+                line,
+                ref message,
+            } => write!(f, "{}:{}: {}", path.display(), line, message),
+        }
+    }
+}
+
+impl Error for ObjError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match *self {
+            Self::Io(_, ref error) => Some(error),
+            Self::Image(_, ref error) => Some(error),
+            Self::Parse { .. } => None,
+        }
+    }
+}
+
+pub type ObjResult<T> = Result<T, ObjError>;
+
+/// Loads a Wavefront OBJ file and returns one mesh per group and material.
+/// Materials are read from the `mtllib` files referenced by the OBJ.
+pub fn load_obj<P>(path: P) -> ObjResult<Vec<Hittable>>
+where
+    P: AsRef<Path>,
+{
+    let path = path.as_ref();
+    let source = fs::read_to_string(path).map_err(|e| ObjError::Io(path.to_path_buf(), e))?;
+    parse_obj(&source, path)
+}
+
+/// Loads a Wavefront MTL file. See [`parse_mtl`] for how materials are mapped.
+pub fn load_mtl<P>(path: P) -> ObjResult<HashMap<String, Material>>
+where
+    P: AsRef<Path>,
+{
+    let path = path.as_ref();
+    let source = fs::read_to_string(path).map_err(|e| ObjError::Io(path.to_path_buf(), e))?;
+    parse_mtl(&source, path)
+}
+
+/// Splits a line into its keyword and arguments, skipping blank lines and comments.
+fn tokenize(line: &str) -> Option<(&str, Vec<&str>)> {
+    let line = line.split('#').next().unwrap_or("");
+    let mut tokens = line.split_whitespace();
+    let keyword = tokens.next()?;
+    Some((keyword, tokens.collect()))
+}
+
+struct LineParser<'a> {
+    path: &'a Path,
+    line: usize,
+}
+
+impl<'a> LineParser<'a> {
+    fn error<T>(&self, message: String) -> ObjResult<T> {
+        Err(ObjError::Parse {
+            path: self.path.to_path_buf(),
+            line: self.line,
+            message,
+        })
+    }
+
+    fn floats(&self, args: &[&str], min: usize, max: usize) -> ObjResult<Vec<f64>> {
+        if args.len() < min || args.len() > max {
+            return self.error(format!(
+                "expected {} to {} numbers, found {}",
+                min,
+                max,
+                args.len()
+            ));
+        }
+        args.iter()
+            .map(|arg| match arg.parse::<f64>() {
+                Ok(value) => Ok(value),
+                Err(_) => self.error(format!("invalid number '{}'", arg)),
+            })
+            .collect()
+    }
+
+    fn vector(&self, args: &[&str]) -> ObjResult<Vector> {
+        let values = self.floats(args, 3, 3)?;
+        Ok(Vector::from(values[0], values[1], values[2]))
+    }
+
+    fn float(&self, args: &[&str]) -> ObjResult<f64> {
+        Ok(self.floats(args, 1, 1)?[0])
+    }
+
+    /// Resolves a 1-based (or negative, relative) OBJ index into a buffer of `len` elements.
+    fn index(&self, arg: &str, len: usize) -> ObjResult<usize> {
+        let index = match arg.parse::<isize>() {
+            Ok(index) => index,
+            Err(_) => return self.error(format!("invalid index '{}'", arg)),
+        };
+        let resolved = if index < 0 {
+            len as isize + index
+        } else {
+            index - 1
+        };
+        if index == 0 || resolved < 0 || resolved >= len as isize {
+            return self.error(format!("index {} out of range", index));
+        }
+        Ok(resolved as usize)
+    }
+}
+
+/// Collects the faces of one group/material pair and compacts the
+/// referenced vertices into buffers local to that mesh.
+struct MeshBuilder {
+    material: Arc<Material>,
+    positions: Vec<Point>,
+    normals: Vec<Vector>,
+    uvs: Vec<Vec2<f64>>,
+    faces: Vec<[VertexIndex; 3]>,
+    position_map: HashMap<usize, usize>,
+    normal_map: HashMap<usize, usize>,
+    uv_map: HashMap<usize, usize>,
+}
+
+impl MeshBuilder {
+    fn new(material: Arc<Material>) -> Self {
+        Self {
+            material,
+            positions: Vec::new(),
+            normals: Vec::new(),
+            uvs: Vec::new(),
+            faces: Vec::new(),
+            position_map: HashMap::new(),
+            normal_map: HashMap::new(),
+            uv_map: HashMap::new(),
+        }
+    }
+
+    fn remap<T: Copy>(
+        map: &mut HashMap<usize, usize>,
+        local: &mut Vec<T>,
+        global: &[T],
+        index: usize,
+    ) -> usize {
+        *map.entry(index).or_insert_with(|| {
+            local.push(global[index]);
+            local.len() - 1
+        })
+    }
+
+    fn add_vertex(
+        &mut self,
+        position: usize,
+        normal: Option<usize>,
+        uv: Option<usize>,
+        obj: &ObjBuffers,
+    ) -> VertexIndex {
+        VertexIndex::new(
+            Self::remap(
+                &mut self.position_map,
+                &mut self.positions,
+                &obj.positions,
+                position,
+            ),
+            normal.map(|n| Self::remap(&mut self.normal_map, &mut self.normals, &obj.normals, n)),
+            uv.map(|uv| Self::remap(&mut self.uv_map, &mut self.uvs, &obj.uvs, uv)),
+        )
+    }
+
+    fn build(self) -> Hittable {
+        Hittable::new_mesh(
+            self.positions,
+            self.normals,
+            self.uvs,
+            self.faces,
+            self.material,
+        )
+    }
+}
+
+#[derive(Default)]
+struct ObjBuffers {
+    positions: Vec<Point>,
+    normals: Vec<Vector>,
+    uvs: Vec<Vec2<f64>>,
+}
+
+/// Parses the contents of an OBJ file. `path` is used for error messages and
+/// to resolve `mtllib` statements relative to the OBJ file.
+pub fn parse_obj(source: &str, path: &Path) -> ObjResult<Vec<Hittable>> {
+    let directory = path.parent().unwrap_or_else(|| Path::new(""));
+    let default_material = Arc::new(Material::new_lambertian(Color::from(0.8, 0.8, 0.8)));
+
+    let mut buffers = ObjBuffers::default();
+    // Meshes using the same material share it.
+    let mut materials: HashMap<String, Arc<Material>> = HashMap::new();
+    let mut meshes = Vec::new();
+    let mut current = MeshBuilder::new(default_material);
+
+    for (number, line) in source.lines().enumerate() {
+        let parser = LineParser {
+            path,
+            line: number + 1,
+        };
+        let (keyword, args) = match tokenize(line) {
+            Some(tokens) => tokens,
+            None => continue,
+        };
+
+        match keyword {
+            "v" => {
+                // An optional fourth (w) component is ignored.
+                let values = parser.floats(&args, 3, 4)?;
+                buffers
+                    .positions
+                    .push(Point::from(values[0], values[1], values[2]));
+            }
+            "vn" => buffers.normals.push(parser.vector(&args)?.normalize()),
+            "vt" => {
+                let values = parser.floats(&args, 1, 3)?;
+                buffers
+                    .uvs
+                    .push(Vec2::from(values[0], values.get(1).copied().unwrap_or(0.0)));
+            }
+            "f" => {
+                if args.len() < 3 {
+                    return parser.error(format!(
+                        "a face needs at least 3 vertices, found {}",
+                        args.len()
+                    ));
+                }
+                let mut corners = Vec::with_capacity(args.len());
+                for arg in &args {
+                    let mut parts = arg.split('/');
+                    let position = parser.index(parts.next().unwrap(), buffers.positions.len())?;
+                    let uv = match parts.next() {
+                        Some("") | None => None,
+                        Some(uv) => Some(parser.index(uv, buffers.uvs.len())?),
+                    };
+                    let normal = match parts.next() {
+                        Some("") | None => None,
+                        Some(normal) => Some(parser.index(normal, buffers.normals.len())?),
+                    };
+                    if parts.next().is_some() {
+                        return parser.error(format!("invalid face vertex '{}'", arg));
+                    }
+                    corners.push(current.add_vertex(position, normal, uv, &buffers));
+                }
+                // Triangulate polygons as a fan around the first vertex.
+                for i in 1..corners.len() - 1 {
+                    current.faces.push([corners[0], corners[i], corners[i + 1]]);
+                }
+            }
+            "usemtl" => {
+                let name = args.join(" ");
+                let material = match materials.get(&name) {
+                    Some(material) => material.clone(),
+                    None => return parser.error(format!("unknown material '{}'", name)),
+                };
+                let previous = std::mem::replace(&mut current, MeshBuilder::new(material));
+                meshes.push(previous.build());
+            }
+            "g" | "o" => {
+                let material = current.material.clone();
+                let previous = std::mem::replace(&mut current, MeshBuilder::new(material));
+                meshes.push(previous.build());
+            }
+            "mtllib" => {
+                for file in args {
+                    let loaded = load_mtl(directory.join(file))?;
+                    materials.extend(loaded.into_iter().map(|(name, m)| (name, Arc::new(m))));
+                }
+            }
+            // Smoothing groups, lines, points, free-form curves and other statements
+            // do not affect rendering, they are skipped so files from any exporter load.
+            _ => {}
+        }
+    }
+    meshes.push(current.build());
+
+    Ok(meshes
+        .into_iter()
+        .filter(|mesh| !matches!(mesh, Hittable::Empty))
+        .collect())
+}
+
+#[derive(Default)]
+struct MtlBuilder {
+    diffuse: Option<Color>,
+    specular: Option<Color>,
+    emission: Option<Color>,
+    shininess: Option<f64>,
+    ior: Option<f64>,
+    dissolve: Option<f64>,
+    illum: Option<u32>,
+    diffuse_map: Option<Texture>,
+}
+
+impl MtlBuilder {
+    /// Maps the Phong style MTL description onto the closest material we support:
+    ///
+    /// * any non-black `Ke` becomes a `DiffuseLight`,
+    /// * transparent materials (`d` < 1 or `illum` 4, 6, 7 or 9) become a `Dielectric` with index `Ni`,
+    /// * reflective materials (`illum` 3 or 5, or a black `Kd` with a non-black `Ks`)
+    ///   become `Metal` tinted by `Ks`, with a fuzz derived from the exponent `Ns`,
+    /// * everything else is `Lambertian` using `map_Kd` or `Kd`.
+    fn build(self) -> Material {
+        let is_black = |color: Option<Color>| color.is_none_or(|c| c.max() <= 0.0);
+
+        if !is_black(self.emission) {
+            return Material::new_diffuse_light(self.emission.unwrap());
+        }
+
+        let transparent = self.dissolve.is_some_and(|d| d < 1.0)
+            || matches!(self.illum, Some(4) | Some(6) | Some(7) | Some(9));
+        if transparent {
+            return Material::new_dielectric(self.ior.unwrap_or(1.5));
+        }
+
+        let reflective = matches!(self.illum, Some(3) | Some(5))
+            || (is_black(self.diffuse) && self.diffuse_map.is_none() && !is_black(self.specular));
+        if reflective {
+            let shininess = self.shininess.unwrap_or(0.0).max(0.0);
+            // Roughness of the Blinn-Phong lobe with exponent Ns.
+            let fuzz = (2.0 / (shininess + 2.0)).sqrt();
+            return Material::new_metal(
+                self.specular.unwrap_or_else(|| Color::from(1.0, 1.0, 1.0)),
+                fuzz,
+            );
+        }
+
+        match self.diffuse_map {
+            Some(texture) => Material::new_lambertian_with_texture(texture),
+            None => {
+                Material::new_lambertian(self.diffuse.unwrap_or_else(|| Color::from(0.8, 0.8, 0.8)))
+            }
+        }
+    }
+}
+
+/// Parses the contents of an MTL file. `path` is used for error messages and
+/// to resolve texture maps relative to the MTL file.
+pub fn parse_mtl(source: &str, path: &Path) -> ObjResult<HashMap<String, Material>> {
+    let directory = path.parent().unwrap_or_else(|| Path::new(""));
+    let mut materials = HashMap::new();
+    let mut current: Option<(String, MtlBuilder)> = None;
+
+    for (number, line) in source.lines().enumerate() {
+        let parser = LineParser {
+            path,
+            line: number + 1,
+        };
+        let (keyword, args) = match tokenize(line) {
+            Some(tokens) => tokens,
+            None => continue,
+        };
+
+        if keyword == "newmtl" {
+            if args.is_empty() {
+                return parser.error("newmtl needs a name".to_string());
+            }
+            if let Some((name, builder)) = current.take() {
+                materials.insert(name, builder.build());
+            }
+            current = Some((args.join(" "), MtlBuilder::default()));
+            continue;
+        }
+
+        let builder = match current {
+            Some((_, ref mut builder)) => builder,
+            None => return parser.error(format!("'{}' before the first newmtl", keyword)),
+        };
+        match keyword {
+            "Kd" => builder.diffuse = Some(parser.vector(&args)?),
+            "Ks" => builder.specular = Some(parser.vector(&args)?),
+            "Ke" => builder.emission = Some(parser.vector(&args)?),
+            "Ns" => builder.shininess = Some(parser.float(&args)?),
+            "Ni" => builder.ior = Some(parser.float(&args)?),
+            "d" => builder.dissolve = Some(parser.float(&args)?),
+            "Tr" => builder.dissolve = Some(1.0 - parser.float(&args)?),
+            "illum" => {
+                builder.illum = match args.as_slice() {
+                    [value] => match value.parse() {
+                        Ok(illum) => Some(illum),
+                        Err(_) => return parser.error(format!("invalid illum '{}'", value)),
+                    },
+                    _ => return parser.error("illum needs exactly one value".to_string()),
+                }
+            }
+            "map_Kd" => {
+                // Options like `-s` are not supported, the file name is the last argument.
+                let file = match args.last() {
+                    Some(file) => directory.join(file),
+                    None => return parser.error("map_Kd needs a file name".to_string()),
+                };
+                let texture =
+                    Texture::new_image(&file).map_err(|e| ObjError::Image(file.clone(), e))?;
+                builder.diffuse_map = Some(texture);
+            }
+            // Ambient color, transmission filter, other maps and extensions like the
+            // physically based `Pr` and `Pm` have no equivalent and are skipped.
+            _ => {}
+        }
+    }
+    if let Some((name, builder)) = current {
+        materials.insert(name, builder.build());
+    }
+    Ok(materials)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ray::*;
+
+    #[test]
+    fn test_parse_quad_with_negative_indices() {
+        let source = "
+            v -1 -1 0
+            v 1 -1 0
+            v 1 1 0
+            v -1 1 0
+            vt 0 0
+            vt 1 1
+            f -4/1 -3/1 -2/2 -1/2
+        ";
+        let meshes = parse_obj(source, Path::new("quad.obj")).unwrap();
+        assert_eq!(meshes.len(), 1);
+
+        let r = Ray::new(
+            Point::from(0.5, 0.5, 1.0),
+            Vector::from(0.0, 0.0, -1.0),
+            0.0,
+        );
+        let hit = meshes[0]
+            .hit(&r, 0.0, f64::INFINITY, &mut rand::thread_rng())
+            .unwrap();
+        assert!(hit.front_face);
+        assert!((hit.t - 1.0).abs() < 1e-8);
+    }
+
+    #[test]
+    fn test_parse_errors_report_line() {
+        let source = "v 0 0 0\nv 1 0 0\nf 1 2 3\n";
+        match parse_obj(source, Path::new("broken.obj")) {
+            Err(ObjError::Parse { line, .. }) => assert_eq!(line, 3),
+            _ => panic!("expected a parse error"),
+        }
+    }
+
+    #[test]
+    fn test_unknown_statements_are_skipped() {
+        let source = "
+            v 0 0 0
+            v 1 0 0
+            v 0 1 0
+            vp 0.5 0.5
+            cstype bspline
+            f 1 2 3
+        ";
+        assert_eq!(parse_obj(source, Path::new("curves.obj")).unwrap().len(), 1);
+
+        let source = "
+            newmtl rough
+            Kd 0.5 0.5 0.5
+            Pr 0.8
+            Pm 0.1
+            map_Bump -bm 0.5 bump.png
+            map_Ke glow.png
+            aniso 0.2
+            norm normal.png
+        ";
+        let materials = parse_mtl(source, Path::new("pbr.mtl")).unwrap();
+        assert!(matches!(materials["rough"], Material::Lambertian(_)));
+
+        // Known statements still report bad arguments.
+        match parse_mtl("newmtl bad\nKd 1 2\nPr 0.5\n", Path::new("bad.mtl")) {
+            Err(ObjError::Parse { line, .. }) => assert_eq!(line, 2),
+            _ => panic!("expected a parse error"),
+        }
+    }
+
+    #[test]
+    fn test_parse_mtl_maps_materials() {
+        let source = "
+            newmtl light
+            Ke 4 4 4
+            newmtl glass
+            Ni 1.33
+            illum 7
+            newmtl gold
+            Kd 0 0 0
+            Ks 1 0.8 0.3
+            Ns 1000
+            newmtl clay
+            Kd 0.5 0.4 0.3
+        ";
+        let materials = parse_mtl(source, Path::new("test.mtl")).unwrap();
+        assert!(matches!(materials["light"], Material::DiffuseLight(_)));
+        assert!(
+            matches!(materials["glass"], Material::Dielectric(Dielectric { ir }) if ir == 1.33)
+        );
+        assert!(matches!(materials["gold"], Material::Metal(_)));
+        assert!(matches!(materials["clay"], Material::Lambertian(_)));
+    }
+}
@@ -0,0 +1,270 @@
+use crate::alias::*;
+use crate::render::*;
+use crate::tonemap::*;
+use exr::prelude::f16;
+use image::codecs::hdr::HdrEncoder;
+use image::{ImageBuffer, ImageError, Rgb, RgbImage};
+use std::error::Error;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+/// Sample type used for OpenEXR output.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ExrPrecision {
+    Half,
+    Float,
+}
+
+/// File formats a framebuffer can be written in, chosen by the file extension.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OutputFormat {
+    /// OpenEXR, keeps the full linear radiance.
+    Exr,
+    /// Radiance RGBE (`.hdr`), a shared 8-bit exponent per pixel.
+    Hdr,
+    /// Portable float map, uncompressed 32-bit floats.
+    Pfm,
+    /// Any 8-bit format supported by the image crate.
+    Ldr,
+}
+
+impl OutputFormat {
+    pub fn from_path<P>(path: P) -> Self
+    where
+        P: AsRef<Path>,
+    {
+        let extension = path
+            .as_ref()
+            .extension()
+            .and_then(|e| e.to_str())
+            .map(|e| e.to_ascii_lowercase());
+        match extension.as_deref() {
+            Some("exr") => Self::Exr,
+            Some("hdr") => Self::Hdr,
+            Some("pfm") => Self::Pfm,
+            _ => Self::Ldr,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum OutputError {
+    Io(io::Error),
+    Image(ImageError),
+    Exr(exr::error::Error),
+}
+
+impl fmt::Display for OutputError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Self::Io(ref error) => error.fmt(f),
+            Self::Image(ref error) => error.fmt(f),
+            Self::Exr(ref error) => error.fmt(f),
+        }
+    }
+}
+
+impl Error for OutputError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match *self {
+            Self::Io(ref error) => Some(error),
+            Self::Image(ref error) => Some(error),
+            Self::Exr(ref error) => Some(error),
+        }
+    }
+}
+
+impl From<io::Error> for OutputError {
+    fn from(error: io::Error) -> Self {
+        Self::Io(error)
+    }
+}
+
+impl From<ImageError> for OutputError {
+    fn from(error: ImageError) -> Self {
+        Self::Image(error)
+    }
+}
+
+impl From<exr::error::Error> for OutputError {
+    fn from(error: exr::error::Error) -> Self {
+        Self::Exr(error)
+    }
+}
+
+pub type OutputResult = Result<(), OutputError>;
+
+impl Framebuffer {
+    /// Writes the framebuffer to `path`, in the format given by the file extension.
+    /// Only the 8-bit formats are tone mapped, all float formats store linear radiance.
+    pub fn save<P>(
+        &self,
+        path: P,
+        precision: ExrPrecision,
+        tone_mapping: &ToneMapping,
+    ) -> OutputResult
+    where
+        P: AsRef<Path>,
+    {
+        let path = path.as_ref();
+        match OutputFormat::from_path(path) {
+            OutputFormat::Exr => self.save_exr(path, precision),
+            OutputFormat::Hdr => self.save_hdr(path),
+            OutputFormat::Pfm => self.save_pfm(path),
+            OutputFormat::Ldr => Ok(self.to_rgb_image(tone_mapping).save(path)?),
+        }
+    }
+
+    pub fn save_exr<P>(&self, path: P, precision: ExrPrecision) -> OutputResult
+    where
+        P: AsRef<Path>,
+    {
+        let (width, height) = (self.width() as usize, self.height() as usize);
+        let pixel = |x: usize, y: usize| self.get_pixel(x as u32, y as u32);
+        match precision {
+            ExrPrecision::Half => exr::prelude::write_rgb_file(path, width, height, |x, y| {
+                let color = pixel(x, y);
+                (
+                    f16::from_f64(color[0]),
+                    f16::from_f64(color[1]),
+                    f16::from_f64(color[2]),
+                )
+            })?,
+            ExrPrecision::Float => exr::prelude::write_rgb_file(path, width, height, |x, y| {
+                let color = pixel(x, y);
+                (color[0] as f32, color[1] as f32, color[2] as f32)
+            })?,
+        }
+        Ok(())
+    }
+
+    pub fn save_hdr<P>(&self, path: P) -> OutputResult
+    where
+        P: AsRef<Path>,
+    {
+        let pixels = self
+            .pixels()
+            .iter()
+            .map(|color| Rgb(to_f32(color)))
+            .collect::<Vec<_>>();
+        let file = BufWriter::new(File::create(path)?);
+        HdrEncoder::new(file).encode(&pixels, self.width() as usize, self.height() as usize)?;
+        Ok(())
+    }
+
+    pub fn save_pfm<P>(&self, path: P) -> OutputResult
+    where
+        P: AsRef<Path>,
+    {
+        let mut file = BufWriter::new(File::create(path)?);
+        // A negative scale marks little endian data.
+        write!(file, "PF\n{} {}\n-1.0\n", self.width(), self.height())?;
+        // Scanlines are stored from the bottom to the top.
+        for y in (0..self.height()).rev() {
+            for x in 0..self.width() {
+                for component in &to_f32(&self.get_pixel(x, y)) {
+                    file.write_all(&component.to_le_bytes())?;
+                }
+            }
+        }
+        file.flush()?;
+        Ok(())
+    }
+
+    /// Converts the framebuffer to an 8-bit sRGB image.
+    pub fn to_rgb_image(&self, tone_mapping: &ToneMapping) -> RgbImage {
+        ImageBuffer::from_fn(self.width(), self.height(), |x, y| {
+            pixel_from_color(tone_mapping.apply(self.get_pixel(x, y)))
+        })
+    }
+}
+
+fn to_f32(color: &Color) -> [f32; 3] {
+    [color[0] as f32, color[1] as f32, color[2] as f32]
+}
+
+/// Quantizes an encoded display color in [0,1].
+fn pixel_from_color(color: Color) -> Rgb<u8> {
+    // Write the translated [0,255] value of each color component.
+    Rgb([
+        (color[0].clamp(0.0, 0.9999) * 256.0) as u8,
+        (color[1].clamp(0.0, 0.9999) * 256.0) as u8,
+        (color[2].clamp(0.0, 0.9999) * 256.0) as u8,
+    ])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::input::{load_image, TempFile};
+    use test_case::test_case;
+
+    /// A 2x3 framebuffer with radiance above one, every value exact in half floats and RGBE.
+    fn framebuffer() -> Framebuffer {
+        let mut framebuffer = Framebuffer::new(2, 3);
+        for y in 0..3 {
+            for x in 0..2 {
+                let red = 1.5 * (1 + x + 2 * y) as f64;
+                framebuffer.put_pixel(x, y, Color::from(red, 0.5, 0.25));
+            }
+        }
+        framebuffer
+    }
+
+    fn assert_round_trip(path: &Path) {
+        let expected = framebuffer();
+        let image = load_image(path, false).unwrap();
+        assert_eq!(image.dimensions(), (2, 3));
+        for (x, y, pixel) in image.enumerate_pixels() {
+            assert_eq!(pixel.0, to_f32(&expected.get_pixel(x, y)), "{} {}", x, y);
+        }
+    }
+
+    #[test_case(ExrPrecision::Half, "half.exr")]
+    #[test_case(ExrPrecision::Float, "float.exr")]
+    fn test_exr_round_trip(precision: ExrPrecision, name: &str) {
+        let file = TempFile::new(&format!("test_exr_round_trip_{}", name));
+        framebuffer().save_exr(&file.0, precision).unwrap();
+        assert_round_trip(&file.0);
+    }
+
+    #[test]
+    fn test_hdr_round_trip() {
+        let file = TempFile::new("test_hdr_round_trip.hdr");
+        framebuffer().save_hdr(&file.0).unwrap();
+        assert_round_trip(&file.0);
+    }
+
+    #[test]
+    fn test_pfm_layout() {
+        let file = TempFile::new("test_pfm_layout.pfm");
+        let framebuffer = framebuffer();
+        framebuffer.save_pfm(&file.0).unwrap();
+        let bytes = std::fs::read(&file.0).unwrap();
+
+        let header = b"PF\n2 3\n-1.0\n";
+        assert_eq!(&bytes[..header.len()], header);
+        let data = &bytes[header.len()..];
+        assert_eq!(data.len(), 2 * 3 * 3 * 4);
+        // Little endian floats, the bottom row first.
+        let floats = data
+            .chunks_exact(4)
+            .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+            .collect::<Vec<_>>();
+        for (i, pixel) in floats.chunks_exact(3).enumerate() {
+            let (x, y) = (i as u32 % 2, 2 - i as u32 / 2);
+            assert_eq!(pixel, to_f32(&framebuffer.get_pixel(x, y)), "{} {}", x, y);
+        }
+    }
+
+    #[test_case("image.exr", OutputFormat::Exr)]
+    #[test_case("renders/image.HDR", OutputFormat::Hdr)]
+    #[test_case("image.pfm", OutputFormat::Pfm)]
+    #[test_case("image.png", OutputFormat::Ldr)]
+    #[test_case("image", OutputFormat::Ldr)]
+    fn test_output_format_from_path(path: &str, format: OutputFormat) {
+        assert_eq!(OutputFormat::from_path(path), format);
+    }
+}
@@ -1,7 +1,9 @@
 use crate::alias::*;
-use crate::Hittable;
-use rand::seq::SliceRandom;
+use crate::hittable::Hittable;
+use crate::sampler::Sampler;
+use rand::Rng;
 
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Clone)]
 pub enum PDF<'a> {
     Cosine(ONB),
@@ -10,7 +12,7 @@ pub enum PDF<'a> {
 }
 
 impl<'a> PDF<'a> {
-    pub fn value(&self, direction: &Vector) -> f64 {
+    pub fn value<R: Rng + ?Sized>(&self, direction: &Vector, rng: &mut R) -> f64 {
         match *self {
             Self::Cosine(ref uvw) => {
                 let cosine = direction.normalize().dot(uvw.w());
@@ -23,18 +25,21 @@ impl<'a> PDF<'a> {
             Self::Mixture(ref pdfs) => {
                 let probability = 1.0 / pdfs.len() as f64;
                 pdfs.iter()
-                    .map(|pdf| probability * pdf.value(direction))
+                    .map(|pdf| probability * pdf.value(direction, rng))
                     .sum()
             }
-            Self::Hittable(ref hittable, ref origin) => hittable.pdf_value(origin, direction),
+            Self::Hittable(hittable, ref origin) => hittable.pdf_value(origin, direction, rng),
         }
     }
 
-    pub fn generate(&self) -> Vector {
+    pub fn generate(&self, sampler: &mut Sampler) -> Vector {
         match *self {
-            Self::Cosine(ref uvw) => uvw.local(&Vector::random_cosine_direction()),
-            Self::Mixture(ref pdfs) => pdfs.choose(&mut rand::thread_rng()).unwrap().generate(),
-            Self::Hittable(ref hittable, ref origin) => hittable.random(origin),
+            Self::Cosine(ref uvw) => uvw.local(&Vector::sample_cosine_direction(sampler.get_2d())),
+            Self::Mixture(ref pdfs) => {
+                let i = (sampler.get_1d() * pdfs.len() as f64) as usize;
+                pdfs[i.min(pdfs.len() - 1)].generate(sampler)
+            }
+            Self::Hittable(hittable, ref origin) => hittable.random(origin, sampler.get_2d()),
         }
     }
 }
@@ -0,0 +1,173 @@
+use crate::alias::*;
+use rand::seq::SliceRandom;
+use rand::Rng;
+
+const POINT_COUNT: usize = 256;
+
+/// Gradient noise with random unit vectors on the lattice points, as in
+/// "Ray Tracing: The Next Week". The lattice repeats every 256 units.
+#[derive(Clone)]
+pub struct Perlin {
+    gradients: Vec<Vector>,
+    perm_x: Vec<usize>,
+    perm_y: Vec<usize>,
+    perm_z: Vec<usize>,
+}
+
+impl Perlin {
+    pub fn new<R: Rng + ?Sized>(rng: &mut R) -> Self {
+        let gradients = (0..POINT_COUNT)
+            .map(|_| Vector::random_unit_vector(rng))
+            .collect();
+        let mut permutation = || {
+            let mut p = (0..POINT_COUNT).collect::<Vec<_>>();
+            p.shuffle(rng);
+            p
+        };
+        let perm_x = permutation();
+        let perm_y = permutation();
+        let perm_z = permutation();
+        Self {
+            gradients,
+            perm_x,
+            perm_y,
+            perm_z,
+        }
+    }
+
+    /// Smooth noise in about [-1, 1], zero on every lattice point.
+    pub fn noise(&self, p: &Point) -> f64 {
+        let floor = p.map(|c| c.floor());
+        let f = *p - floor;
+        // Hermite smoothing hides the lattice.
+        let w = f.map(|c| c * c * (3.0 - 2.0 * c));
+        let (i, j, k) = (floor[0] as i64, floor[1] as i64, floor[2] as i64);
+
+        let mut accum = 0.0;
+        for di in 0..2 {
+            for dj in 0..2 {
+                for dk in 0..2 {
+                    let gradient = self.gradients[self.perm_x[((i + di) & 255) as usize]
+                        ^ self.perm_y[((j + dj) & 255) as usize]
+                        ^ self.perm_z[((k + dk) & 255) as usize]];
+                    let (di, dj, dk) = (di as f64, dj as f64, dk as f64);
+                    let weight = Vector::from(f[0] - di, f[1] - dj, f[2] - dk);
+                    accum += (di * w[0] + (1.0 - di) * (1.0 - w[0]))
+                        * (dj * w[1] + (1.0 - dj) * (1.0 - w[1]))
+                        * (dk * w[2] + (1.0 - dk) * (1.0 - w[2]))
+                        * gradient.dot(weight);
+                }
+            }
+        }
+        accum
+    }
+
+    /// Sum of `depth` octaves of absolute noise, each with twice the frequency and half the amplitude.
+    pub fn turbulence(&self, p: &Point, depth: u32) -> f64 {
+        let mut accum = 0.0;
+        let mut p = *p;
+        let mut weight = 1.0;
+        for _ in 0..depth {
+            accum += weight * self.noise(&p);
+            weight *= 0.5;
+            p *= 2.0;
+        }
+        accum.abs()
+    }
+
+    /// Fractional Brownian motion, `octaves` layers of noise normalized to about [-1, 1].
+    /// Every octave multiplies the frequency by `lacunarity` and the amplitude by `gain`.
+    pub fn fbm(&self, p: &Point, octaves: u32, lacunarity: f64, gain: f64) -> f64 {
+        let mut accum = 0.0;
+        let mut norm = 0.0;
+        let mut p = *p;
+        let mut amplitude = 1.0;
+        for _ in 0..octaves {
+            accum += amplitude * self.noise(&p);
+            norm += amplitude;
+            amplitude *= gain;
+            p *= lacunarity;
+        }
+        if norm > 0.0 {
+            accum / norm
+        } else {
+            0.0
+        }
+    }
+
+    /// Musgrave's ridged multifractal in about [0, 1]. Sharp ridges form where the noise crosses
+    /// zero, and every octave is weighted by the previous one, so detail gathers on the ridges.
+    pub fn ridged(&self, p: &Point, octaves: u32, lacunarity: f64, gain: f64, offset: f64) -> f64 {
+        let mut accum = 0.0;
+        let mut norm = 0.0;
+        let mut p = *p;
+        let mut amplitude = 1.0;
+        let mut weight = 1.0;
+        for _ in 0..octaves {
+            let signal = (offset - self.noise(&p).abs()).powi(2) * weight;
+            weight = (2.0 * signal).clamp(0.0, 1.0);
+            accum += amplitude * signal;
+            norm += amplitude * offset * offset;
+            amplitude *= gain;
+            p *= lacunarity;
+        }
+        if norm > 0.0 {
+            accum / norm
+        } else {
+            0.0
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    fn perlin() -> Perlin {
+        Perlin::new(&mut StdRng::seed_from_u64(7))
+    }
+
+    #[test]
+    fn test_noise_vanishes_on_lattice() {
+        let perlin = perlin();
+        for p in [
+            Point::new(),
+            Point::from(1.0, 2.0, 3.0),
+            Point::from(-4.0, 17.0, -300.0),
+        ] {
+            assert!(perlin.noise(&p).abs() < 1e-12);
+        }
+    }
+
+    #[test]
+    fn test_noise_is_seeded() {
+        let p = Point::from(0.3, 1.7, -2.2);
+        assert_eq!(perlin().noise(&p), perlin().noise(&p));
+        let other = Perlin::new(&mut StdRng::seed_from_u64(8));
+        assert_ne!(perlin().noise(&p), other.noise(&p));
+    }
+
+    proptest! {
+        #[test]
+        fn test_noise_is_bounded(x in -100.0f64..100.0, y in -100.0f64..100.0, z in -100.0f64..100.0) {
+            let perlin = perlin();
+            let p = Point::from(x, y, z);
+            // Each gradient term is at most the distance to its corner, sqrt(3).
+            prop_assert!(perlin.noise(&p).abs() <= 3.0f64.sqrt());
+            prop_assert!(perlin.fbm(&p, 6, 2.0, 0.5).abs() <= 3.0f64.sqrt());
+            let ridged = perlin.ridged(&p, 6, 2.0, 0.5, 1.0);
+            prop_assert!((0.0..=1.0).contains(&ridged));
+        }
+
+        #[test]
+        fn test_noise_is_continuous(x in -10.0f64..10.0, y in -10.0f64..10.0, z in -10.0f64..10.0) {
+            let perlin = perlin();
+            let p = Point::from(x, y, z);
+            let q = p + Vector::from(1e-6, -1e-6, 1e-6);
+            prop_assert!((perlin.noise(&p) - perlin.noise(&q)).abs() < 1e-4);
+        }
+    }
+}
@@ -0,0 +1,670 @@
+use crate::aabb::AxisAlignedBoundingBox;
+use crate::alias::*;
+use crate::hittable::*;
+use crate::material::*;
+use crate::ray::*;
+use std::f64::consts::PI;
+use std::sync::Arc;
+
+/// Places a shape with its base at `origin` and its axis of symmetry along the w axis of `frame`.
+#[derive(Clone)]
+struct Placement {
+    origin: Point,
+    frame: ONB,
+}
+
+impl Placement {
+    fn new(origin: Point, axis: &Vector) -> Self {
+        Self {
+            origin,
+            frame: ONB::from_w(axis),
+        }
+    }
+
+    fn to_local(&self, a: &Vector) -> Vector {
+        Vector::from(
+            a.dot(self.frame.u()),
+            a.dot(self.frame.v()),
+            a.dot(self.frame.w()),
+        )
+    }
+
+    fn to_world(&self, a: &Vector) -> Vector {
+        self.frame.local(a)
+    }
+
+    /// The world space box around the local box from `minimum` to `maximum`.
+    fn bounding_box(&self, minimum: Point, maximum: Point) -> AxisAlignedBoundingBox {
+        let corners = AxisAlignedBoundingBox::new(minimum, maximum).corners();
+        AxisAlignedBoundingBox::from_points(
+            corners
+                .iter()
+                .map(|corner| self.origin + self.to_world(corner)),
+        )
+        .unwrap()
+        .pad(0.0001)
+    }
+}
+
+/// A surface of revolution `x² + y² = Q(z)` for `0 <= z <= height`, where `Q` is a quadratic.
+/// Cylinders, cones, paraboloids and hyperboloids only differ in `Q`.
+#[derive(Clone)]
+pub struct Quadric {
+    placement: Placement,
+    /// Coefficients of `Q`, constant term first.
+    profile: [f64; 3],
+    height: f64,
+    /// Closes the ends with disks.
+    capped: bool,
+    material: Arc<Material>,
+}
+
+impl Quadric {
+    fn new(
+        base: Point,
+        axis: Vector,
+        profile: [f64; 3],
+        capped: bool,
+        material: impl Into<Arc<Material>>,
+    ) -> Self {
+        Self {
+            placement: Placement::new(base, &axis),
+            profile,
+            height: axis.length(),
+            capped,
+            material: material.into(),
+        }
+    }
+
+    /// A cylinder of `radius` from `base` to `base + axis`.
+    pub fn new_cylinder(
+        base: Point,
+        axis: Vector,
+        radius: f64,
+        capped: bool,
+        material: impl Into<Arc<Material>>,
+    ) -> Self {
+        Self::new(base, axis, [radius * radius, 0.0, 0.0], capped, material)
+    }
+
+    /// A cone with a base of `radius` and its apex at `base + axis`.
+    pub fn new_cone(
+        base: Point,
+        axis: Vector,
+        radius: f64,
+        capped: bool,
+        material: impl Into<Arc<Material>>,
+    ) -> Self {
+        let h = axis.length();
+        let k = radius * radius / (h * h);
+        Self::new(base, axis, [k * h * h, -2.0 * k * h, k], capped, material)
+    }
+
+    /// A paraboloid with its tip at `base`, opening to a rim of `radius` at `base + axis`.
+    pub fn new_paraboloid(
+        base: Point,
+        axis: Vector,
+        radius: f64,
+        capped: bool,
+        material: impl Into<Arc<Material>>,
+    ) -> Self {
+        let h = axis.length();
+        Self::new(
+            base,
+            axis,
+            [0.0, radius * radius / h, 0.0],
+            capped,
+            material,
+        )
+    }
+
+    /// A hyperboloid of one sheet from `base` to `base + axis`, `radius` at its waist halfway
+    /// along the axis and `end_radius` at both ends. With `end_radius` below `radius` it bulges
+    /// like a barrel instead.
+    pub fn new_hyperboloid(
+        base: Point,
+        axis: Vector,
+        radius: f64,
+        end_radius: f64,
+        capped: bool,
+        material: impl Into<Arc<Material>>,
+    ) -> Self {
+        let m = 0.5 * axis.length();
+        let c = (end_radius * end_radius - radius * radius) / (m * m);
+        Self::new(
+            base,
+            axis,
+            [radius * radius + c * m * m, -2.0 * c * m, c],
+            capped,
+            material,
+        )
+    }
+
+    fn q(&self, z: f64) -> f64 {
+        let [q0, q1, q2] = self.profile;
+        (q2 * z + q1) * z + q0
+    }
+
+    fn dq(&self, z: f64) -> f64 {
+        let [_, q1, q2] = self.profile;
+        2.0 * q2 * z + q1
+    }
+
+    fn radius_at(&self, z: f64) -> f64 {
+        self.q(z).max(0.0).sqrt()
+    }
+
+    pub fn bounding_box(&self) -> AxisAlignedBoundingBox {
+        // The widest point is at one of the ends, or at the vertex of `Q` if it opens downwards.
+        let [_, q1, q2] = self.profile;
+        let vertex = if q2 < 0.0 { -q1 / (2.0 * q2) } else { 0.0 };
+        let radius = [0.0, self.height, vertex.clamp(0.0, self.height)]
+            .iter()
+            .map(|&z| self.radius_at(z))
+            .fold(0.0, f64::max);
+        self.placement.bounding_box(
+            Point::from(-radius, -radius, 0.0),
+            Point::from(radius, radius, self.height),
+        )
+    }
+
+    pub fn hit(&self, r: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord<'_>> {
+        let o = self.placement.to_local(&(r.origin - self.placement.origin));
+        let d = self.placement.to_local(&r.direction);
+        let [_, q1, q2] = self.profile;
+
+        let a = d[0] * d[0] + d[1] * d[1] - q2 * d[2] * d[2];
+        let b = 2.0 * (o[0] * d[0] + o[1] * d[1] - q2 * o[2] * d[2]) - q1 * d[2];
+        let c = o[0] * o[0] + o[1] * o[1] - self.q(o[2]);
+        let side = solve_quadratic(a, b, c)
+            .into_iter()
+            .filter(|&t| (0.0..=self.height).contains(&(o[2] + t * d[2])))
+            .map(|t| (t, None));
+        // Hits on the caps remember the height of their end.
+        let ends = [0.0, self.height];
+        let caps = ends
+            .iter()
+            .copied()
+            .filter(|_| self.capped && d[2] != 0.0)
+            .map(|z| ((z - o[2]) / d[2], Some(z)))
+            .filter(|&(t, z)| {
+                let p = o + t * d;
+                p[0] * p[0] + p[1] * p[1] <= self.q(z.unwrap())
+            });
+        let (t, cap) = side
+            .chain(caps)
+            .filter(|&(t, _)| (t_min..=t_max).contains(&t))
+            .min_by(|a, b| a.0.total_cmp(&b.0))?;
+
+        let p = o + t * d;
+        let (x, y) = (p[0], p[1]);
+        let rho = (x * x + y * y).sqrt();
+        let phi = y.atan2(x).rem_euclid(2.0 * PI);
+        let (sin, cos) = phi.sin_cos();
+        let around = 2.0 * PI * Vector::from(-y, x, 0.0);
+        let (uv, normal, along, uv_per_unit) = if let Some(z) = cap {
+            let cap_radius = self.radius_at(z);
+            let normal = Vector::from(0.0, 0.0, if z == 0.0 { -1.0 } else { 1.0 });
+            (
+                Vec2::from(phi / (2.0 * PI), rho / cap_radius),
+                normal,
+                cap_radius * Vector::from(cos, sin, 0.0),
+                1.0 / cap_radius,
+            )
+        } else {
+            let normal = Vector::from(x, y, -0.5 * self.dq(p[2])).normalize();
+            // d rho / dz, kept finite at the tips of cones and paraboloids.
+            let slope = 0.5 * self.dq(p[2]) / rho.max(1e-9);
+            (
+                Vec2::from(phi / (2.0 * PI), p[2] / self.height),
+                normal,
+                self.height * Vector::from(cos * slope, sin * slope, 1.0),
+                1.0 / (2.0 * PI * rho).min(self.height).max(1e-9),
+            )
+        };
+
+        let placement = &self.placement;
+        Some(
+            HitRecord::new(
+                r,
+                r.at(t),
+                t,
+                uv,
+                placement.to_world(&normal),
+                &self.material,
+            )
+            .with_footprint(r, uv_per_unit)
+            .with_tangents(placement.to_world(&around), placement.to_world(&along)),
+        )
+    }
+}
+
+/// A ring around the axis through `center`, sweeping a tube of `minor_radius`
+/// along a circle of `major_radius`.
+#[derive(Clone)]
+pub struct Torus {
+    placement: Placement,
+    major_radius: f64,
+    minor_radius: f64,
+    material: Arc<Material>,
+}
+
+impl Torus {
+    pub fn new(
+        center: Point,
+        axis: Vector,
+        major_radius: f64,
+        minor_radius: f64,
+        material: impl Into<Arc<Material>>,
+    ) -> Self {
+        Self {
+            placement: Placement::new(center, &axis),
+            major_radius,
+            minor_radius,
+            material: material.into(),
+        }
+    }
+
+    pub fn bounding_box(&self) -> AxisAlignedBoundingBox {
+        let extent = self.major_radius + self.minor_radius;
+        self.placement.bounding_box(
+            Point::from(-extent, -extent, -self.minor_radius),
+            Point::from(extent, extent, self.minor_radius),
+        )
+    }
+
+    pub fn hit(&self, r: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord<'_>> {
+        let (major, minor) = (self.major_radius, self.minor_radius);
+        let length = r.direction.length();
+        let d = self.placement.to_local(&r.direction) / length;
+        let o = self.placement.to_local(&(r.origin - self.placement.origin));
+        // Start from the point closest to the center, which keeps the coefficients small
+        // for rays from far away.
+        let shift = -o.dot(d);
+        let o = o + shift * d;
+
+        let od = o.dot(d);
+        let k = o.dot(o) + major * major - minor * minor;
+        let m = 4.0 * major * major;
+        let coefficients = [
+            k * k - m * (o[0] * o[0] + o[1] * o[1]),
+            4.0 * k * od - 2.0 * m * (o[0] * d[0] + o[1] * d[1]),
+            2.0 * k + 4.0 * od * od - m * (d[0] * d[0] + d[1] * d[1]),
+            4.0 * od,
+            1.0,
+        ];
+        // Roots are distances along the normalized direction from the shifted origin.
+        let t = solve_quartic(coefficients)
+            .into_iter()
+            .map(|s| (s + shift) / length)
+            .filter(|&t| (t_min..=t_max).contains(&t))
+            .min_by(|a, b| a.total_cmp(b))?;
+
+        let p = self.placement.to_local(&(r.at(t) - self.placement.origin));
+        let phi = p[1].atan2(p[0]).rem_euclid(2.0 * PI);
+        let (sin_phi, cos_phi) = phi.sin_cos();
+        let rho = (p[0] * p[0] + p[1] * p[1]).sqrt();
+        let theta = p[2].atan2(rho - major).rem_euclid(2.0 * PI);
+        let (sin_theta, cos_theta) = theta.sin_cos();
+        // The normal points away from the nearest point on the center circle of the tube.
+        let normal = Vector::from(cos_theta * cos_phi, cos_theta * sin_phi, sin_theta);
+        let around = 2.0 * PI * Vector::from(-p[1], p[0], 0.0);
+        let along =
+            2.0 * PI * minor * Vector::from(-sin_theta * cos_phi, -sin_theta * sin_phi, cos_theta);
+
+        let placement = &self.placement;
+        Some(
+            HitRecord::new(
+                r,
+                r.at(t),
+                t,
+                Vec2::from(phi / (2.0 * PI), theta / (2.0 * PI)),
+                placement.to_world(&normal),
+                &self.material,
+            )
+            .with_footprint(r, 1.0 / (2.0 * PI * minor.min(major - minor).max(1e-9)))
+            .with_tangents(placement.to_world(&around), placement.to_world(&along)),
+        )
+    }
+}
+
+/// Real roots of `a t² + b t + c`, which degrades to a line when `a` vanishes.
+fn solve_quadratic(a: f64, b: f64, c: f64) -> Vec<f64> {
+    if a.abs() < 1e-12 {
+        return if b != 0.0 { vec![-c / b] } else { vec![] };
+    }
+    let discriminant = b * b - 4.0 * a * c;
+    if discriminant < 0.0 {
+        return vec![];
+    }
+    // Avoids cancellation between `b` and the root of the discriminant.
+    let q = -0.5 * (b + b.signum() * discriminant.sqrt());
+    if q == 0.0 {
+        return vec![0.0];
+    }
+    vec![q / a, c / q]
+}
+
+/// The largest real root of `t³ + a t² + b t + c`.
+fn largest_cubic_root(a: f64, b: f64, c: f64) -> f64 {
+    // Depress to `s³ + p s + q` with `t = s - a / 3`.
+    let p = b - a * a / 3.0;
+    let q = 2.0 * a * a * a / 27.0 - a * b / 3.0 + c;
+    let discriminant = 0.25 * q * q + p * p * p / 27.0;
+    let s = if discriminant > 0.0 {
+        let root = discriminant.sqrt();
+        (-0.5 * q + root).cbrt() + (-0.5 * q - root).cbrt()
+    } else {
+        // Three real roots, the trigonometric form gives the largest first.
+        let m = 2.0 * (-p / 3.0).sqrt();
+        let angle = (3.0 * q / (p * m)).clamp(-1.0, 1.0).acos() / 3.0;
+        m * angle.cos()
+    };
+    let mut t = s - a / 3.0;
+    // Polish with Newton's method, the closed forms lose precision.
+    for _ in 0..2 {
+        let f = ((t + a) * t + b) * t + c;
+        let df = (3.0 * t + 2.0 * a) * t + b;
+        if df != 0.0 {
+            t -= f / df;
+        }
+    }
+    t
+}
+
+/// Real roots of the quartic with `coefficients`, constant term first, using Ferrari's method.
+pub fn solve_quartic(coefficients: [f64; 5]) -> Vec<f64> {
+    let [e, d, c, b, a] = coefficients;
+    let (b, c, d, e) = (b / a, c / a, d / a, e / a);
+    // Depress to `y⁴ + p y² + q y + r` with `t = y - b / 4`.
+    let p = c - 3.0 * b * b / 8.0;
+    let q = d - b * c / 2.0 + b * b * b / 8.0;
+    let r = e - b * d / 4.0 + b * b * c / 16.0 - 3.0 * b * b * b * b / 256.0;
+
+    let roots = if q.abs() < 1e-12 {
+        // Biquadratic, a quadratic in y².
+        solve_quadratic(1.0, p, r)
+            .into_iter()
+            .filter(|&y2| y2 >= 0.0)
+            .flat_map(|y2| [y2.sqrt(), -y2.sqrt()])
+            .collect()
+    } else {
+        // Complete the square with the positive root of the resolvent cubic,
+        // which splits the quartic into two quadratics.
+        let m = largest_cubic_root(p, 0.25 * p * p - r, -0.125 * q * q);
+        let s = (2.0 * m).max(0.0).sqrt();
+        if s == 0.0 {
+            return vec![];
+        }
+        let mut roots = solve_quadratic(1.0, s, 0.5 * p + m - 0.5 * q / s);
+        roots.extend(solve_quadratic(1.0, -s, 0.5 * p + m + 0.5 * q / s));
+        roots
+    };
+
+    roots
+        .into_iter()
+        .map(|y| {
+            let mut t = y - b / 4.0;
+            for _ in 0..2 {
+                let f = (((t + b) * t + c) * t + d) * t + e;
+                let df = ((4.0 * t + 3.0 * b) * t + 2.0 * c) * t + d;
+                if df != 0.0 {
+                    t -= f / df;
+                }
+            }
+            t
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_case::test_case;
+
+    #[test_case([24.0, -50.0, 35.0, -10.0, 1.0], &[1.0, 2.0, 3.0, 4.0]; "four roots")]
+    #[test_case([4.0, 0.0, -5.0, 0.0, 1.0], &[-2.0, -1.0, 1.0, 2.0]; "biquadratic")]
+    #[test_case([-1.0, 0.0, 0.0, 0.0, 1.0], &[-1.0, 1.0]; "two roots")]
+    #[test_case([1.0, 0.0, 1.0, 0.0, 1.0], &[]; "no roots")]
+    #[test_case([0.0, -6.0, 11.0, -6.0, 1.0], &[0.0, 1.0, 2.0, 3.0]; "root at zero")]
+    #[test_case([-60.0, 56.0, -3.0, -9.0, 2.0], &[-2.5, 2.0, 3.0]; "double root")]
+    fn test_solve_quartic(coefficients: [f64; 5], expected: &[f64]) {
+        let mut roots = solve_quartic(coefficients);
+        roots.sort_by(|a, b| a.total_cmp(b));
+        roots.dedup_by(|a, b| (*a - *b).abs() < 1e-6);
+        assert_eq!(roots.len(), expected.len(), "{:?}", roots);
+        for (root, expected) in roots.iter().zip(expected) {
+            assert!((root - expected).abs() < 1e-6, "{:?}", roots);
+        }
+    }
+
+    fn material() -> Material {
+        Material::new_lambertian(Color::new())
+    }
+
+    /// Shoots a ray from `origin` at `target`, returning uv and normal of the hit.
+    fn hit_from(hittable: &Hittable, origin: Point, target: Point) -> (Vec2<f64>, Vector) {
+        let r = Ray::new(origin, target - origin, 0.0);
+        let hit = hittable
+            .hit(&r, 0.001, f64::INFINITY, &mut rand::thread_rng())
+            .unwrap();
+        (hit.uv, hit.normal)
+    }
+
+    // The frame around the z axis starts u at -x and turns towards +y.
+    #[test_case(
+        Point::from(5.0, 0.0, 0.5),
+        Vec2::from(0.50, 0.25),
+        Vector::from(1.0, 0.0, 0.0)
+    )]
+    #[test_case(
+        Point::from(0.0, 5.0, 1.0),
+        Vec2::from(0.25, 0.50),
+        Vector::from(0.0, 1.0, 0.0)
+    )]
+    #[test_case(Point::from(-5.0, 0.0, 1.5), Vec2::from(0.00, 0.75), Vector::from(-1.0, 0.0, 0.0))]
+    #[test_case(Point::from(0.0, -5.0, 1.0), Vec2::from(0.75, 0.50), Vector::from(0.0, -1.0, 0.0))]
+    fn test_cylinder_uv(origin: Point, uv: Vec2<f64>, normal: Vector) {
+        let cylinder = Hittable::new_cylinder(
+            Point::new(),
+            Vector::from(0.0, 0.0, 2.0),
+            1.0,
+            false,
+            material(),
+        );
+        let target = Point::from(0.0, 0.0, origin[2]);
+        let (hit_uv, hit_normal) = hit_from(&cylinder, origin, target);
+        assert!((hit_uv - uv).near_zero(), "{:?}", hit_uv);
+        assert!((hit_normal - normal).near_zero(), "{:?}", hit_normal);
+    }
+
+    #[test_case(false; "open")]
+    #[test_case(true; "capped")]
+    fn test_cylinder_caps(capped: bool) {
+        let cylinder = Hittable::new_cylinder(
+            Point::new(),
+            Vector::from(0.0, 0.0, 2.0),
+            1.0,
+            capped,
+            material(),
+        );
+        let r = Ray::new(
+            Point::from(0.5, 0.0, 3.0),
+            Vector::from(0.0, 0.0, -1.0),
+            0.0,
+        );
+        match cylinder.hit(&r, 0.001, f64::INFINITY, &mut rand::thread_rng()) {
+            // Rays along the axis pass straight through an open pipe.
+            None => assert!(!capped),
+            Some(hit) => {
+                assert!(capped);
+                assert!((hit.t - 1.0).abs() < 1e-9);
+                assert!((hit.normal - Vector::from(0.0, 0.0, 1.0)).near_zero());
+                assert!((hit.uv - Vec2::from(0.5, 0.5)).near_zero());
+            }
+        }
+    }
+
+    #[test_case(0.1)]
+    #[test_case(0.5)]
+    #[test_case(0.9)]
+    fn test_cone_uv(height: f64) {
+        let cone = Hittable::new_cone(
+            Point::new(),
+            Vector::from(0.0, 0.0, 1.0),
+            1.0,
+            false,
+            material(),
+        );
+        // Approach the side along its normal.
+        let target = Point::from(1.0 - height, 0.0, height);
+        let origin = target + Vector::from(5.0, 0.0, 5.0);
+        let (hit_uv, hit_normal) = hit_from(&cone, origin, target);
+        assert!(
+            (hit_uv - Vec2::from(0.5, height)).near_zero(),
+            "{:?}",
+            hit_uv
+        );
+        let normal = Vector::from(1.0, 0.0, 1.0).normalize();
+        assert!((hit_normal - normal).near_zero(), "{:?}", hit_normal);
+    }
+
+    #[test_case(0.25, Vector::from(-1.0, 0.0, 1.0))]
+    #[test_case(0.64, Vector::from(-1.6, 0.0, 1.0))]
+    fn test_paraboloid_normal(height: f64, normal: Vector) {
+        // The bowl z = x² + y², seen from inside.
+        let paraboloid = Hittable::new_paraboloid(
+            Point::new(),
+            Vector::from(0.0, 0.0, 1.0),
+            1.0,
+            false,
+            material(),
+        );
+        let target = Point::from(height.sqrt(), 0.0, height);
+        let (hit_uv, hit_normal) = hit_from(&paraboloid, Point::from(0.0, 0.0, height), target);
+        assert!(
+            (hit_uv - Vec2::from(0.5, height)).near_zero(),
+            "{:?}",
+            hit_uv
+        );
+        assert!(
+            (hit_normal - normal.normalize()).near_zero(),
+            "{:?}",
+            hit_normal
+        );
+    }
+
+    #[test_case(0.5, 1.75f64.sqrt())]
+    #[test_case(1.0, 1.0)]
+    #[test_case(1.75, 2.6875f64.sqrt())]
+    fn test_hyperboloid_radius(height: f64, radius: f64) {
+        let hyperboloid = Hittable::new_hyperboloid(
+            Point::new(),
+            Vector::from(0.0, 0.0, 2.0),
+            1.0,
+            2.0,
+            false,
+            material(),
+        );
+        let r = Ray::new(
+            Point::from(5.0, 0.0, height),
+            Vector::from(-1.0, 0.0, 0.0),
+            0.0,
+        );
+        let hit = hyperboloid
+            .hit(&r, 0.001, f64::INFINITY, &mut rand::thread_rng())
+            .unwrap();
+        assert!((hit.t - (5.0 - radius)).abs() < 1e-9, "{}", hit.t);
+        assert!((hit.uv - Vec2::from(0.5, height / 2.0)).near_zero());
+    }
+
+    #[test_case(1.0, 2.0; "hourglass")]
+    #[test_case(2.0, 1.0; "barrel")]
+    fn test_hyperboloid_bounding_box(radius: f64, end_radius: f64) {
+        let base = Point::from(1.0, -2.0, 0.5);
+        let axis = Vector::from(1.0, 2.0, 2.0);
+        let hyperboloid =
+            Hittable::new_hyperboloid(base, axis, radius, end_radius, false, material());
+        let aabb = hyperboloid.bounding_box(0.0, 1.0).unwrap();
+        // Shoot rays at the axis from all around, every hit must lie within the box.
+        let u = axis.cross(Vector::from(0.0, 0.0, 1.0)).normalize();
+        let v = axis.normalize().cross(u);
+        let mut rng = rand::thread_rng();
+        for i in 0..20 {
+            for j in 0..16 {
+                let target = base + (i as f64 + 0.5) / 20.0 * axis;
+                let angle = j as f64 / 16.0 * 2.0 * PI;
+                let origin = target + 10.0 * (angle.cos() * u + angle.sin() * v);
+                let r = Ray::new(origin, target - origin, 0.0);
+                let hit = hyperboloid.hit(&r, 0.001, f64::INFINITY, &mut rng).unwrap();
+                assert!(
+                    (0..3).all(|k| (aabb.minimum[k]..=aabb.maximum[k]).contains(&hit.p[k])),
+                    "{:?}",
+                    hit.p
+                );
+            }
+        }
+    }
+
+    #[test_case(
+        Point::from(5.0, 0.0, 0.0),
+        Point::new(),
+        Vec2::from(0.5, 0.0),
+        Vector::from(1.0, 0.0, 0.0)
+    )]
+    #[test_case(
+        Point::from(0.0, 5.0, 0.0),
+        Point::new(),
+        Vec2::from(0.25, 0.0),
+        Vector::from(0.0, 1.0, 0.0)
+    )]
+    #[test_case(
+        Point::from(2.0, 0.0, 5.0),
+        Point::from(2.0, 0.0, 0.0),
+        Vec2::from(0.5, 0.25),
+        Vector::from(0.0, 0.0, 1.0)
+    )]
+    #[test_case(Point::from(1.5, 0.0, 0.0), Point::from(2.0, 0.0, 0.0), Vec2::from(0.5, 0.5), Vector::from(-1.0, 0.0, 0.0))]
+    #[test_case(Point::from(0.0, -2.0, -5.0), Point::from(0.0, -2.0, 0.0), Vec2::from(0.75, 0.75), Vector::from(0.0, 0.0, -1.0))]
+    fn test_torus_uv(origin: Point, target: Point, uv: Vec2<f64>, normal: Vector) {
+        let torus = Hittable::new_torus(
+            Point::new(),
+            Vector::from(0.0, 0.0, 1.0),
+            2.0,
+            0.25,
+            material(),
+        );
+        let (hit_uv, hit_normal) = hit_from(&torus, origin, target);
+        assert!((hit_uv - uv).near_zero(), "{:?}", hit_uv);
+        assert!((hit_normal - normal).near_zero(), "{:?}", hit_normal);
+    }
+
+    #[test]
+    fn test_torus_hole_is_empty() {
+        let torus = Hittable::new_torus(
+            Point::new(),
+            Vector::from(0.0, 0.0, 1.0),
+            2.0,
+            0.5,
+            material(),
+        );
+        let r = Ray::new(
+            Point::from(0.0, 0.0, 10.0),
+            Vector::from(0.0, 0.0, -1.0),
+            0.0,
+        );
+        assert!(torus
+            .hit(&r, 0.001, f64::INFINITY, &mut rand::thread_rng())
+            .is_none());
+        // A ray from far away along the ring's plane crosses the tube twice on each side.
+        let r = Ray::new(
+            Point::from(-1e4, 0.0, 0.0),
+            Vector::from(1.0, 0.0, 0.0),
+            0.0,
+        );
+        let hit = torus
+            .hit(&r, 0.001, f64::INFINITY, &mut rand::thread_rng())
+            .unwrap();
+        assert!((hit.t - (1e4 - 2.5)).abs() < 1e-6, "{}", hit.t);
+    }
+}
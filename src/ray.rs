@@ -1,10 +1,14 @@
 use crate::alias::*;
 
+/// A ray, optionally with a cone around it that estimates the area it stands for.
+/// The cone is `width` wide at the origin and widens by `spread` radians.
 #[derive(Default)]
 pub struct Ray {
     pub origin: Point,
     pub direction: Vector,
     pub time: f64,
+    pub width: f64,
+    pub spread: f64,
 }
 
 impl Ray {
@@ -13,9 +17,24 @@ impl Ray {
             origin,
             direction,
             time,
+            width: 0.0,
+            spread: 0.0,
         }
     }
 
+    pub fn with_cone(self, width: f64, spread: f64) -> Self {
+        Self {
+            width,
+            spread,
+            ..self
+        }
+    }
+
+    /// Width of the ray cone at `time`.
+    pub fn width_at(&self, time: f64) -> f64 {
+        self.width + self.spread * time * self.direction.length()
+    }
+
     pub fn at(&self, time: f64) -> Point {
         self.origin + time * self.direction
     }
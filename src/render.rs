@@ -0,0 +1,230 @@
+use crate::alias::*;
+use crate::bvh::*;
+use crate::hittable::*;
+use crate::material::*;
+use crate::pdf::PDF;
+use crate::ray::*;
+use crate::sampler::*;
+use crate::scene::*;
+use rand::SeedableRng;
+use rand_pcg::Pcg64Mcg;
+use rayon::prelude::*;
+
+/// Smallest spread of the ray cone after a diffuse bounce, in radians. The scattered ray stands in
+/// for a whole lobe, whose lighting blurs texture detail anyway, so its lookups can use coarser mip levels.
+const DIFFUSE_SPREAD: f64 = 0.2;
+
+/// The linear radiance of every pixel of a rendered image, stored row by row from the top.
+pub struct Framebuffer {
+    width: u32,
+    height: u32,
+    pixels: Vec<Color>,
+}
+
+impl Framebuffer {
+    pub fn new(width: u32, height: u32) -> Self {
+        Self {
+            width,
+            height,
+            pixels: vec![Color::new(); (width * height) as usize],
+        }
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    pub fn pixels(&self) -> &[Color] {
+        &self.pixels
+    }
+
+    pub fn get_pixel(&self, x: u32, y: u32) -> Color {
+        self.pixels[(y * self.width + x) as usize]
+    }
+
+    pub fn put_pixel(&mut self, x: u32, y: u32, color: Color) {
+        self.pixels[(y * self.width + x) as usize] = color;
+    }
+}
+
+pub struct Renderer {
+    pub image_width: u32,
+    pub image_height: u32,
+    pub samples_per_pixel: u32,
+    pub max_depth: u32,
+    /// Seeds every random decision, equal seeds render bit-identical images.
+    pub seed: u64,
+    pub sampler: SamplerKind,
+    pub bvh: BvhSplit,
+}
+
+impl Renderer {
+    pub fn new(
+        image_width: u32,
+        image_height: u32,
+        samples_per_pixel: u32,
+        max_depth: u32,
+        seed: u64,
+        sampler: SamplerKind,
+        bvh: BvhSplit,
+    ) -> Self {
+        Self {
+            image_width,
+            image_height,
+            samples_per_pixel,
+            max_depth,
+            seed,
+            sampler,
+            bvh,
+        }
+    }
+
+    /// Renders the scene in parallel, the camera is fitted to the aspect ratio of the image.
+    pub fn render(&self, scene: Scene) -> Framebuffer {
+        self.render_with_stats(scene, |_| {})
+    }
+
+    /// Renders the scene like `render`, handing the statistics of the scene BVH to `report`
+    /// as soon as it is built, before any pixel is rendered.
+    pub fn render_with_stats<F>(&self, scene: Scene, report: F) -> Framebuffer
+    where
+        F: FnOnce(&BvhStats),
+    {
+        let Scene {
+            mut world,
+            mut lights,
+            camera,
+            background,
+            ..
+        } = scene;
+        let (world, stats) = build_bvh(
+            world.as_mut_slice(),
+            camera.time_open,
+            camera.time_close,
+            self.bvh,
+            Some(&mut Pcg64Mcg::seed_from_u64(self.seed)),
+        );
+        report(&stats);
+        let lights = match lights.as_slice() {
+            [] => None,
+            [Hittable::Empty] => None,
+            [_] => lights.pop(),
+            _ => Some(Hittable::List(lights)),
+        };
+
+        let image_width = self.image_width;
+        let image_height = self.image_height;
+        let cam = camera.build(image_width as f64 / image_height as f64);
+        // Ray cones select the level of detail of image textures.
+        let pixel_spread = cam.pixel_spread(image_height);
+
+        let pixels = (0..image_width)
+            .flat_map(move |x| (0..image_height).map(move |y| (x, y)))
+            .collect::<Vec<_>>()
+            .par_iter()
+            .map(|(x, y)| {
+                let mut pixel_color = Color::new();
+                // Samples only depend on the pixel, not on the thread that renders it.
+                let mut sampler = Sampler::new(self.sampler, self.samples_per_pixel, self.seed);
+                for sample in 0..self.samples_per_pixel {
+                    sampler.start_pixel_sample(*x, *y, sample);
+                    let offset = sampler.get_2d();
+                    let u = ((*x) as f64 + offset[0]) / (image_width - 1) as f64;
+                    let v = ((image_height - y - 1) as f64 + offset[1]) / (image_height - 1) as f64;
+                    let r = cam.get_ray(u, v, &mut sampler).with_cone(0.0, pixel_spread);
+                    pixel_color += ray_color(
+                        &r,
+                        background,
+                        &world,
+                        &lights,
+                        self.max_depth,
+                        &mut sampler,
+                    );
+                }
+                pixel_color /= self.samples_per_pixel as f64;
+                ((*x, *y), pixel_color)
+            })
+            .collect::<Vec<_>>();
+
+        let mut framebuffer = Framebuffer::new(image_width, image_height);
+        for pixel in pixels {
+            framebuffer.put_pixel(pixel.0 .0, pixel.0 .1, pixel.1);
+        }
+        framebuffer
+    }
+}
+
+fn ray_color(
+    r: &Ray,
+    background: Color,
+    world: &Hittable,
+    lights: &Option<Hittable>,
+    depth: u32,
+    sampler: &mut Sampler,
+) -> Color {
+    // If we've exceeded the ray bounce limit, no more light is gathered.
+    if depth == 0 {
+        return Color::new();
+    }
+
+    if let Some(hit) = world.hit(r, 0.001, f64::INFINITY, sampler) {
+        let hit = hit.material.shade(hit);
+        let emitted = hit.material.emit(&hit);
+        if let Some(scatter) = hit.material.scatter(r, &hit, sampler) {
+            match scatter {
+                ScatterRecord::Specular { ray, attenuation } => {
+                    return attenuation
+                        * ray_color(&ray, background, world, lights, depth - 1, sampler)
+                }
+                ScatterRecord::PDF { pdf, attenuation } => {
+                    let mut pdf = pdf;
+                    if let Some(lights) = lights {
+                        let light = PDF::Hittable(lights, hit.p);
+                        pdf = PDF::Mixture(vec![light, pdf]);
+                    }
+                    let scattered = Ray::new(hit.p, pdf.generate(sampler), r.time)
+                        .with_cone(r.width_at(hit.t), r.spread.max(DIFFUSE_SPREAD));
+                    let pdf_value = pdf.value(&scattered.direction, sampler);
+
+                    return emitted
+                        + attenuation
+                            * hit.material.scattering_pdf(r, &hit, &scattered)
+                            * ray_color(&scattered, background, world, lights, depth - 1, sampler)
+                            / pdf_value;
+                }
+            }
+        }
+        return emitted;
+    }
+
+    background
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scenes::*;
+
+    fn render_with_threads(threads: usize, seed: u64) -> Vec<Color> {
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .build()
+            .unwrap();
+        let (world, lights) = random_scene();
+        let renderer = Renderer::new(24, 16, 4, 5, seed, SamplerKind::Sobol, BvhSplit::Sah);
+        pool.install(|| renderer.render(Scene::new(world, lights)))
+            .pixels()
+            .to_vec()
+    }
+
+    #[test]
+    fn test_render_is_independent_of_thread_count() {
+        let single = render_with_threads(1, 7);
+        assert_eq!(single, render_with_threads(4, 7));
+        assert_ne!(single, render_with_threads(4, 8));
+    }
+}
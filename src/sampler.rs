@@ -0,0 +1,508 @@
+use crate::alias::*;
+use rand::{Rng, RngCore, SeedableRng};
+use rand_pcg::Pcg64Mcg;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::OnceLock;
+
+/// How the samples of a pixel are distributed over the sample space.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SamplerKind {
+    /// Independent uniform random numbers.
+    Random,
+    /// Jittered samples in a shuffled grid of strata per dimension.
+    Stratified,
+    /// The Halton sequence with random digit permutations per pixel.
+    Halton,
+    /// The padded Sobol (0,2)-sequence with Owen scrambling per pixel.
+    Sobol,
+    /// Sobol samples shared by all pixels, offset by a blue-noise mask.
+    /// The remaining error is pushed into high frequencies, where it is less visible.
+    BlueNoise,
+}
+
+impl FromStr for SamplerKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "random" => Ok(Self::Random),
+            "stratified" => Ok(Self::Stratified),
+            "halton" => Ok(Self::Halton),
+            "sobol" => Ok(Self::Sobol),
+            "blue_noise" => Ok(Self::BlueNoise),
+            _ => Err(format!("unknown sampler '{}'", s)),
+        }
+    }
+}
+
+impl fmt::Display for SamplerKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            Self::Random => "random",
+            Self::Stratified => "stratified",
+            Self::Halton => "halton",
+            Self::Sobol => "sobol",
+            Self::BlueNoise => "blue_noise",
+        })
+    }
+}
+
+const PRIMES: [u32; 32] = [
+    2, 3, 5, 7, 11, 13, 17, 19, 23, 29, 31, 37, 41, 43, 47, 53, 59, 61, 67, 71, 73, 79, 83, 89, 97,
+    101, 103, 107, 109, 113, 127, 131,
+];
+
+const ONE_MINUS_EPSILON: f64 = 1.0 - f64::EPSILON / 2.0;
+
+const BLUE_NOISE_SIZE: usize = 64;
+
+/// Generates the samples of one pixel sample after another.
+///
+/// Every call to `get_1d` or `get_2d` consumes the next dimension of the current sample.
+/// Decisions that don't benefit from well distributed samples can use the sampler as a
+/// plain random number generator, which is seeded per sample as well.
+pub struct Sampler {
+    kind: SamplerKind,
+    samples_per_pixel: u32,
+    seed: u64,
+    x: u32,
+    y: u32,
+    index: u32,
+    dimension: u32,
+    rng: Pcg64Mcg,
+}
+
+impl Sampler {
+    pub fn new(kind: SamplerKind, samples_per_pixel: u32, seed: u64) -> Self {
+        Self {
+            kind,
+            samples_per_pixel: samples_per_pixel.max(1),
+            seed,
+            x: 0,
+            y: 0,
+            index: 0,
+            dimension: 0,
+            rng: Pcg64Mcg::seed_from_u64(seed),
+        }
+    }
+
+    /// Starts the `index`th sample of the pixel at (x, y).
+    /// The samples only depend on the seed, the pixel and the index.
+    pub fn start_pixel_sample(&mut self, x: u32, y: u32, index: u32) {
+        self.x = x;
+        self.y = y;
+        self.index = index;
+        self.dimension = 0;
+        let state = hash(&[self.seed, x as u64, y as u64, index as u64]);
+        self.rng = Pcg64Mcg::new(((state as u128) << 64) | mix(state) as u128);
+    }
+
+    pub fn get_1d(&mut self) -> f64 {
+        let dimension = self.dimension;
+        self.dimension += 1;
+        match self.kind {
+            SamplerKind::Random => self.rng.gen(),
+            SamplerKind::Stratified => {
+                let n = self.samples_per_pixel;
+                let stratum = permutation_element(self.index, n, self.pixel_hash(dimension) as u32);
+                (stratum as f64 + self.rng.gen::<f64>()) / n as f64
+            }
+            SamplerKind::Halton => self.halton(dimension),
+            SamplerKind::Sobol => {
+                let hash = self.pixel_hash(dimension);
+                let index = owen_scramble(self.index, hash as u32);
+                to_unit(owen_scramble(index.reverse_bits(), (hash >> 32) as u32))
+            }
+            SamplerKind::BlueNoise => {
+                let hash = hash(&[self.seed, dimension as u64]);
+                let index = owen_scramble(self.index, hash as u32);
+                let base = to_unit(owen_scramble(index.reverse_bits(), (hash >> 32) as u32));
+                (base + self.blue_noise_offset(dimension)).fract()
+            }
+        }
+    }
+
+    pub fn get_2d(&mut self) -> Vec2<f64> {
+        let dimension = self.dimension;
+        self.dimension += 2;
+        match self.kind {
+            SamplerKind::Random => Vec2::from(self.rng.gen(), self.rng.gen()),
+            SamplerKind::Stratified => {
+                let n = self.samples_per_pixel;
+                let nx = (n as f64).sqrt().ceil() as u32;
+                let ny = n.div_ceil(nx);
+                let hash = self.pixel_hash(dimension) as u32;
+                let stratum = permutation_element(self.index, nx * ny, hash);
+                Vec2::from(
+                    ((stratum % nx) as f64 + self.rng.gen::<f64>()) / nx as f64,
+                    ((stratum / nx) as f64 + self.rng.gen::<f64>()) / ny as f64,
+                )
+            }
+            SamplerKind::Halton => Vec2::from(self.halton(dimension), self.halton(dimension + 1)),
+            SamplerKind::Sobol => sobol_2d(self.index, self.pixel_hash(dimension)),
+            SamplerKind::BlueNoise => {
+                let base = sobol_2d(self.index, hash(&[self.seed, dimension as u64]));
+                Vec2::from(
+                    (base[0] + self.blue_noise_offset(dimension)).fract(),
+                    (base[1] + self.blue_noise_offset(dimension + 1)).fract(),
+                )
+            }
+        }
+    }
+
+    fn pixel_hash(&self, dimension: u32) -> u64 {
+        hash(&[self.seed, self.x as u64, self.y as u64, dimension as u64])
+    }
+
+    fn halton(&mut self, dimension: u32) -> f64 {
+        match PRIMES.get(dimension as usize) {
+            Some(&base) => scrambled_radical_inverse(base, self.index, self.pixel_hash(dimension)),
+            None => self.rng.gen(),
+        }
+    }
+
+    /// A toroidally shifted lookup into the blue-noise mask, shifted differently per dimension.
+    fn blue_noise_offset(&self, dimension: u32) -> f64 {
+        let shift = hash(&[self.seed, dimension as u64, BLUE_NOISE_SIZE as u64]);
+        let x = (self.x as u64 + shift) as usize % BLUE_NOISE_SIZE;
+        let y = (self.y as u64 + (shift >> 32)) as usize % BLUE_NOISE_SIZE;
+        blue_noise_mask()[y * BLUE_NOISE_SIZE + x]
+    }
+}
+
+impl RngCore for Sampler {
+    fn next_u32(&mut self) -> u32 {
+        self.rng.next_u32()
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.rng.next_u64()
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        self.rng.fill_bytes(dest)
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand::Error> {
+        self.rng.try_fill_bytes(dest)
+    }
+}
+
+/// The SplitMix64 finalizer, decorrelates nearby values.
+fn mix(x: u64) -> u64 {
+    let x = x.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    let x = (x ^ (x >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    let x = (x ^ (x >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    x ^ (x >> 31)
+}
+
+pub(crate) fn hash(values: &[u64]) -> u64 {
+    values.iter().fold(0, |hash, &value| mix(hash ^ value))
+}
+
+fn to_unit(x: u32) -> f64 {
+    x as f64 / (1u64 << 32) as f64
+}
+
+/// The `i`th element of a random permutation of `0..l` selected by `p`, after Kensler.
+fn permutation_element(mut i: u32, l: u32, p: u32) -> u32 {
+    let mut w = l - 1;
+    w |= w >> 1;
+    w |= w >> 2;
+    w |= w >> 4;
+    w |= w >> 8;
+    w |= w >> 16;
+    loop {
+        i ^= p;
+        i = i.wrapping_mul(0xe170893d);
+        i ^= p >> 16;
+        i ^= (i & w) >> 4;
+        i ^= p >> 8;
+        i = i.wrapping_mul(0x0929eb3f);
+        i ^= p >> 23;
+        i ^= (i & w) >> 1;
+        i = i.wrapping_mul(1 | p >> 27);
+        i = i.wrapping_mul(0x6935fa69);
+        i ^= (i & w) >> 11;
+        i = i.wrapping_mul(0x74dcb303);
+        i ^= (i & w) >> 2;
+        i = i.wrapping_mul(0x9e501cc3);
+        i ^= (i & w) >> 2;
+        i = i.wrapping_mul(0xc860a3df);
+        i &= w;
+        i ^= i >> 5;
+        if i < l {
+            return i.wrapping_add(p) % l;
+        }
+    }
+}
+
+/// The radical inverse of `index` with a random permutation of the digits at every position.
+fn scrambled_radical_inverse(base: u32, mut index: u32, mut hash: u64) -> f64 {
+    let inv_base = 1.0 / base as f64;
+    let mut scale = inv_base;
+    let mut result = 0.0;
+    // Permuted digits don't end with the index, so continue up to the precision of an f64.
+    while scale > 1e-16 {
+        let digit = index % base;
+        index /= base;
+        hash = mix(hash);
+        result += permutation_element(digit, base, hash as u32) as f64 * scale;
+        scale *= inv_base;
+    }
+    result.min(ONE_MINUS_EPSILON)
+}
+
+/// The second dimension of the Sobol sequence, the first is the bit reversed index.
+fn sobol_2(mut index: u32) -> u32 {
+    let mut v = 1 << 31;
+    let mut result = 0;
+    while index != 0 {
+        if index & 1 != 0 {
+            result ^= v;
+        }
+        index >>= 1;
+        v ^= v >> 1;
+    }
+    result
+}
+
+/// An Owen scrambled (0,2)-sequence sample with a shuffled index, after Burley 2020.
+fn sobol_2d(index: u32, hash: u64) -> Vec2<f64> {
+    let index = owen_scramble(index, hash as u32);
+    Vec2::from(
+        to_unit(owen_scramble(index.reverse_bits(), (hash >> 32) as u32)),
+        to_unit(owen_scramble(sobol_2(index), mix(hash) as u32)),
+    )
+}
+
+fn laine_karras_permutation(mut x: u32, seed: u32) -> u32 {
+    x = x.wrapping_add(seed);
+    x ^= x.wrapping_mul(0x6c50b47c);
+    x ^= x.wrapping_mul(0xb82f1e52);
+    x ^= x.wrapping_mul(0xc7afe638);
+    x ^= x.wrapping_mul(0x8d22f6e6);
+    x
+}
+
+/// Hash based nested uniform scrambling, each bit is flipped depending on the bits above it.
+fn owen_scramble(x: u32, seed: u32) -> u32 {
+    laine_karras_permutation(x.reverse_bits(), seed).reverse_bits()
+}
+
+fn blue_noise_mask() -> &'static [f64] {
+    static MASK: OnceLock<Vec<f64>> = OnceLock::new();
+    MASK.get_or_init(|| void_and_cluster(BLUE_NOISE_SIZE, 1.5))
+}
+
+/// Ulichney's void-and-cluster method, ranks every pixel of a tileable `size` x `size` mask.
+fn void_and_cluster(size: usize, sigma: f64) -> Vec<f64> {
+    let n = size * size;
+    // The energy a pixel contributes at every toroidal offset.
+    let kernel = (0..n)
+        .map(|i| {
+            let dx = (i % size).min(size - i % size) as f64;
+            let dy = (i / size).min(size - i / size) as f64;
+            (-(dx * dx + dy * dy) / (2.0 * sigma * sigma)).exp()
+        })
+        .collect::<Vec<_>>();
+    let update = |energy: &mut [f64], p: usize, sign: f64| {
+        let (px, py) = (p % size, p / size);
+        for y in 0..size {
+            for x in 0..size {
+                let offset = (y + size - py) % size * size + (x + size - px) % size;
+                energy[y * size + x] += sign * kernel[offset];
+            }
+        }
+    };
+    let tightest_cluster = |pattern: &[bool], energy: &[f64]| {
+        (0..n)
+            .filter(|&i| pattern[i])
+            .max_by(|&a, &b| energy[a].total_cmp(&energy[b]))
+            .unwrap()
+    };
+    let largest_void = |pattern: &[bool], energy: &[f64]| {
+        (0..n)
+            .filter(|&i| !pattern[i])
+            .min_by(|&a, &b| energy[a].total_cmp(&energy[b]))
+            .unwrap()
+    };
+
+    // Start with a tenth of the pixels set at random.
+    let mut rng = Pcg64Mcg::seed_from_u64(0);
+    let mut pattern = vec![false; n];
+    let mut energy = vec![0.0; n];
+    let initial = n / 10;
+    let mut count = 0;
+    while count < initial {
+        let i = rng.gen_range(0..n);
+        if !pattern[i] {
+            pattern[i] = true;
+            update(&mut energy, i, 1.0);
+            count += 1;
+        }
+    }
+
+    // Move pixels from the tightest cluster into the largest void until that stops changing.
+    loop {
+        let cluster = tightest_cluster(&pattern, &energy);
+        pattern[cluster] = false;
+        update(&mut energy, cluster, -1.0);
+        let void = largest_void(&pattern, &energy);
+        pattern[void] = true;
+        update(&mut energy, void, 1.0);
+        if void == cluster {
+            break;
+        }
+    }
+
+    let mut ranks = vec![0; n];
+    let (prototype, prototype_energy) = (pattern.clone(), energy.clone());
+    // Rank the pixels of the prototype by removing them cluster by cluster.
+    for rank in (0..initial).rev() {
+        let cluster = tightest_cluster(&pattern, &energy);
+        pattern[cluster] = false;
+        update(&mut energy, cluster, -1.0);
+        ranks[cluster] = rank;
+    }
+    // Fill the remaining pixels void by void. Past the half this is the same as
+    // picking the tightest cluster of unset pixels, as the total energy is constant.
+    let (mut pattern, mut energy) = (prototype, prototype_energy);
+    for rank in initial..n {
+        let void = largest_void(&pattern, &energy);
+        pattern[void] = true;
+        update(&mut energy, void, 1.0);
+        ranks[void] = rank;
+    }
+
+    ranks
+        .into_iter()
+        .map(|rank| (rank as f64 + 0.5) / n as f64)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_case::test_case;
+
+    fn samples(kind: SamplerKind, n: u32, dimension: u32) -> Vec<Vec2<f64>> {
+        let mut sampler = Sampler::new(kind, n, 3);
+        (0..n)
+            .map(|i| {
+                sampler.start_pixel_sample(5, 7, i);
+                for _ in 0..dimension {
+                    sampler.get_1d();
+                }
+                sampler.get_2d()
+            })
+            .collect()
+    }
+
+    #[test_case(SamplerKind::Random)]
+    #[test_case(SamplerKind::Stratified)]
+    #[test_case(SamplerKind::Halton)]
+    #[test_case(SamplerKind::Sobol)]
+    #[test_case(SamplerKind::BlueNoise)]
+    fn test_samples_are_in_unit_square(kind: SamplerKind) {
+        for dimension in 0..40 {
+            for u in samples(kind, 32, dimension) {
+                assert!(u.map(|c| (0.0..1.0).contains(&c)).all());
+            }
+        }
+    }
+
+    #[test_case(SamplerKind::Stratified, 0)]
+    #[test_case(SamplerKind::Stratified, 3)]
+    #[test_case(SamplerKind::Halton, 0)]
+    #[test_case(SamplerKind::Sobol, 0)]
+    #[test_case(SamplerKind::Sobol, 3)]
+    fn test_samples_are_stratified(kind: SamplerKind, dimension: u32) {
+        // Every 1/16 interval holds exactly one of 16 samples.
+        let mut sampler = Sampler::new(kind, 16, 3);
+        let mut strata = (0..16)
+            .map(|i| {
+                sampler.start_pixel_sample(5, 7, i);
+                for _ in 0..dimension {
+                    sampler.get_1d();
+                }
+                (sampler.get_1d() * 16.0) as usize
+            })
+            .collect::<Vec<_>>();
+        strata.sort_unstable();
+        assert_eq!(strata, (0..16).collect::<Vec<_>>());
+    }
+
+    #[test_case(SamplerKind::Stratified)]
+    #[test_case(SamplerKind::Sobol)]
+    fn test_2d_samples_cover_grid(kind: SamplerKind) {
+        let mut cells = samples(kind, 16, 1)
+            .iter()
+            .map(|u| (u[1] * 4.0) as usize * 4 + (u[0] * 4.0) as usize)
+            .collect::<Vec<_>>();
+        cells.sort_unstable();
+        assert_eq!(cells, (0..16).collect::<Vec<_>>());
+    }
+
+    /// Mean squared error of estimating the area of a quarter disk with `n` samples,
+    /// over many pixels. The disk's edge stands in for a geometric edge within a pixel.
+    fn quarter_disk_error(kind: SamplerKind, n: u32) -> f64 {
+        let mut sampler = Sampler::new(kind, n, 3);
+        let pixels = 256;
+        let mut error = 0.0;
+        for pixel in 0..pixels {
+            let mut inside = 0;
+            for i in 0..n {
+                sampler.start_pixel_sample(pixel % 16, pixel / 16, i);
+                // Skip the camera dimensions, like a bounce deeper in a path.
+                sampler.get_2d();
+                let u = sampler.get_2d();
+                if u[0] * u[0] + u[1] * u[1] < 1.0 {
+                    inside += 1;
+                }
+            }
+            error += (inside as f64 / n as f64 - std::f64::consts::FRAC_PI_4).powi(2);
+        }
+        error / pixels as f64
+    }
+
+    #[test_case(SamplerKind::Stratified)]
+    #[test_case(SamplerKind::Halton)]
+    #[test_case(SamplerKind::Sobol)]
+    #[test_case(SamplerKind::BlueNoise)]
+    fn test_lower_variance_than_random(kind: SamplerKind) {
+        let random = quarter_disk_error(SamplerKind::Random, 64);
+        let error = quarter_disk_error(kind, 64);
+        // 64 samples cut the error by 4x (Halton) to 8x (Sobol) for these seeds.
+        assert!(
+            error < random / 3.0,
+            "{}: {:e}, random: {:e}",
+            kind,
+            error,
+            random
+        );
+    }
+
+    #[test]
+    fn test_permutation_element_is_permutation() {
+        for l in [1, 7, 16, 100] {
+            let mut elements = (0..l)
+                .map(|i| permutation_element(i, l, 0x1234_5678))
+                .collect::<Vec<_>>();
+            elements.sort_unstable();
+            assert_eq!(elements, (0..l).collect::<Vec<_>>());
+        }
+    }
+
+    #[test]
+    fn test_blue_noise_mask_ranks_every_pixel_once() {
+        let n = BLUE_NOISE_SIZE * BLUE_NOISE_SIZE;
+        let mut ranks = blue_noise_mask()
+            .iter()
+            .map(|v| (v * n as f64) as usize)
+            .collect::<Vec<_>>();
+        ranks.sort_unstable();
+        assert_eq!(ranks, (0..n).collect::<Vec<_>>());
+    }
+}
@@ -0,0 +1,1932 @@
+use crate::aabb::AxisAlignedBoundingBox;
+use crate::alias::*;
+use crate::cam::*;
+use crate::csg::*;
+use crate::hittable::*;
+use crate::input::load_image;
+use crate::material::*;
+use crate::mipmap::*;
+use crate::obj::*;
+use crate::sdf::*;
+use crate::texture::*;
+use crate::tonemap::*;
+use crate::worley::*;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use toml::Spanned;
+
+/// Everything needed to render an image, apart from the output settings.
+pub struct Scene {
+    pub world: Vec<Hittable>,
+    /// Objects that are sampled directly, they need to be part of the world too.
+    pub lights: Vec<Hittable>,
+    pub camera: CameraSettings,
+    pub background: Color,
+    pub tone_mapping: ToneMapping,
+}
+
+impl Scene {
+    /// Creates a scene with the default camera and a sky blue background.
+    pub fn new(world: Vec<Hittable>, lights: Vec<Hittable>) -> Self {
+        Self {
+            world,
+            lights,
+            camera: CameraSettings::default(),
+            background: default_background(),
+            tone_mapping: ToneMapping::default(),
+        }
+    }
+
+    /// Loads a scene from a TOML scene file.
+    /// Relative paths in the file are resolved against the directory of the file.
+    pub fn load<P>(path: P) -> SceneResult<Self>
+    where
+        P: AsRef<Path>,
+    {
+        let path = path.as_ref();
+        let source = fs::read_to_string(path).map_err(|e| SceneError::Io(path.to_path_buf(), e))?;
+        Self::parse(&source, path)
+    }
+
+    /// Parses the contents of a scene file, `path` is used for error messages
+    /// and to resolve relative paths.
+    pub fn parse(source: &str, path: &Path) -> SceneResult<Self> {
+        let builder = SceneBuilder {
+            source,
+            path,
+            directory: path.parent().unwrap_or_else(|| Path::new("")),
+        };
+        let desc: SceneDesc = toml::from_str(source).map_err(|e| {
+            builder.error_at(e.span().unwrap_or(0..0), e.message().trim().to_string())
+        })?;
+        builder.build(desc)
+    }
+}
+
+fn default_background() -> Color {
+    Color::from(0.50, 0.70, 1.00)
+}
+
+#[derive(Debug)]
+pub enum SceneError {
+    Io(PathBuf, io::Error),
+    Parse {
+        path: PathBuf,
+        line: usize,
+        column: usize,
+        message: String,
+    },
+    Obj(ObjError),
+}
+
+impl fmt::Display for SceneError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Self::Io(ref path, ref error) => write!(f, "{}: {}", path.display(), error),
+            Self::Parse {
+                ref path,
+                line,
+                column,
+                ref message,
+            } => write!(f, "{}:{}:{}: {}", path.display(), line, column, message),
+            Self::Obj(ref error) => error.fmt(f),
+        }
+    }
+}
+
+impl Error for SceneError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match *self {
+            Self::Io(_, ref error) => Some(error),
+            Self::Parse { .. } => None,
+            Self::Obj(ref error) => Some(error),
+        }
+    }
+}
+
+impl From<ObjError> for SceneError {
+    fn from(error: ObjError) -> Self {
+        Self::Obj(error)
+    }
+}
+
+pub type SceneResult<T> = Result<T, SceneError>;
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SceneDesc {
+    #[serde(default)]
+    camera: CameraDesc,
+    #[serde(default)]
+    background: Option<[f64; 3]>,
+    #[serde(default)]
+    tone_mapping: ToneMappingDesc,
+    #[serde(default)]
+    textures: HashMap<String, Spanned<TextureDesc>>,
+    #[serde(default)]
+    materials: HashMap<String, Spanned<MaterialDesc>>,
+    /// Named objects that are built once and placed any number of times by `instance` objects.
+    #[serde(default)]
+    geometry: HashMap<String, Spanned<ObjectDesc>>,
+    #[serde(default)]
+    objects: Vec<Spanned<ObjectDesc>>,
+    /// Objects that are added to the world and also sampled directly.
+    #[serde(default)]
+    lights: Vec<Spanned<ObjectDesc>>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct CameraDesc {
+    lookfrom: Option<[f64; 3]>,
+    lookat: Option<[f64; 3]>,
+    vup: Option<[f64; 3]>,
+    vfov: Option<f64>,
+    aperture: Option<f64>,
+    focus_distance: Option<f64>,
+    time_open: Option<f64>,
+    time_close: Option<f64>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct ToneMappingDesc {
+    exposure: Option<f64>,
+    operator: Option<ToneMapOperator>,
+    white_point: Option<f64>,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+enum TextureDesc {
+    Solid {
+        color: [f64; 3],
+    },
+    Checker {
+        odd: String,
+        even: String,
+    },
+    /// An image file. The texture coordinates are scaled, rotated by `rotation` degrees
+    /// and offset before the lookup.
+    Image {
+        path: String,
+        filter: Option<ImageFilter>,
+        wrap: Option<WrapMode>,
+        border: Option<[f64; 3]>,
+        scale: Option<[f64; 2]>,
+        rotation: Option<f64>,
+        offset: Option<[f64; 2]>,
+        #[serde(default)]
+        linear: bool,
+        #[serde(default)]
+        alpha: bool,
+    },
+    Normal,
+    Uv,
+    Noise {
+        scale: Option<f64>,
+        #[serde(default)]
+        seed: u64,
+    },
+    Turbulence {
+        scale: Option<f64>,
+        depth: Option<u32>,
+        #[serde(default)]
+        seed: u64,
+    },
+    Marble {
+        scale: Option<f64>,
+        depth: Option<u32>,
+        #[serde(default)]
+        seed: u64,
+    },
+    Fbm {
+        scale: Option<f64>,
+        octaves: Option<u32>,
+        lacunarity: Option<f64>,
+        gain: Option<f64>,
+        #[serde(default)]
+        seed: u64,
+    },
+    Ridged {
+        scale: Option<f64>,
+        octaves: Option<u32>,
+        lacunarity: Option<f64>,
+        gain: Option<f64>,
+        offset: Option<f64>,
+        #[serde(default)]
+        seed: u64,
+    },
+    Cells {
+        scale: Option<f64>,
+        feature: Option<CellFeature>,
+        distance: Option<CellDistance>,
+        #[serde(default)]
+        seed: u64,
+    },
+    Wood {
+        rings: Option<f64>,
+        scale: Option<f64>,
+        distortion: Option<f64>,
+        light: String,
+        dark: String,
+        #[serde(default)]
+        seed: u64,
+    },
+    Ramp {
+        input: String,
+        stops: Vec<RampStopDesc>,
+    },
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RampStopDesc {
+    at: f64,
+    color: [f64; 3],
+}
+
+/// Turbulence depth used when a scene file doesn't give one.
+const DEFAULT_TURBULENCE_DEPTH: u32 = 7;
+
+fn fractal(octaves: Option<u32>, lacunarity: Option<f64>, gain: Option<f64>) -> Fractal {
+    let defaults = Fractal::default();
+    Fractal {
+        octaves: octaves.unwrap_or(defaults.octaves),
+        lacunarity: lacunarity.unwrap_or(defaults.lacunarity),
+        gain: gain.unwrap_or(defaults.gain),
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+enum MaterialDesc {
+    Lambertian {
+        albedo: Option<[f64; 3]>,
+        texture: Option<String>,
+    },
+    Metal {
+        albedo: [f64; 3],
+        #[serde(default)]
+        fuzz: f64,
+    },
+    Dielectric {
+        ir: f64,
+    },
+    DiffuseLight {
+        emit: Option<[f64; 3]>,
+        texture: Option<String>,
+    },
+    Isotropic {
+        albedo: [f64; 3],
+    },
+    /// Perturbs the normals of `material` with a tangent space normal map.
+    NormalMap {
+        material: String,
+        texture: String,
+    },
+    /// Perturbs the normals of `material` as if displaced by `scale` times the texture luminance.
+    BumpMap {
+        material: String,
+        texture: String,
+        scale: f64,
+    },
+    /// Cuts holes into `material` where the luminance of `opacity` is below `threshold`,
+    /// or lets rays through with a probability of one minus the opacity when `stochastic`.
+    Cutout {
+        material: String,
+        opacity: String,
+        threshold: Option<f64>,
+        #[serde(default)]
+        stochastic: bool,
+    },
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+enum ObjectDesc {
+    Sphere {
+        center: [f64; 3],
+        radius: f64,
+        material: String,
+    },
+    MovingSphere {
+        center_start: [f64; 3],
+        center_end: [f64; 3],
+        time_start: f64,
+        time_end: f64,
+        radius: f64,
+        material: String,
+    },
+    Cube {
+        min: [f64; 3],
+        max: [f64; 3],
+        material: String,
+    },
+    Triangle {
+        vertices: [[f64; 3]; 3],
+        material: String,
+    },
+    /// The parallelogram spanned by the edges `u` and `v` from `origin`.
+    Quad {
+        origin: [f64; 3],
+        u: [f64; 3],
+        v: [f64; 3],
+        material: String,
+    },
+    Disk {
+        center: [f64; 3],
+        normal: [f64; 3],
+        radius: f64,
+        material: String,
+    },
+    /// An infinite plane through `point`.
+    Plane {
+        point: [f64; 3],
+        normal: [f64; 3],
+        material: String,
+    },
+    /// A cylinder from `base` to `base + axis`, `capped` closes its ends.
+    Cylinder {
+        base: [f64; 3],
+        axis: [f64; 3],
+        radius: f64,
+        #[serde(default)]
+        capped: bool,
+        material: String,
+    },
+    /// A cone with a base of `radius` and its apex at `base + axis`.
+    Cone {
+        base: [f64; 3],
+        axis: [f64; 3],
+        radius: f64,
+        #[serde(default)]
+        capped: bool,
+        material: String,
+    },
+    /// A paraboloid with its tip at `base` and a rim of `radius` at `base + axis`.
+    Paraboloid {
+        base: [f64; 3],
+        axis: [f64; 3],
+        radius: f64,
+        #[serde(default)]
+        capped: bool,
+        material: String,
+    },
+    /// A hyperboloid from `base` to `base + axis`, `radius` at its waist and `end_radius` at the ends.
+    Hyperboloid {
+        base: [f64; 3],
+        axis: [f64; 3],
+        radius: f64,
+        end_radius: f64,
+        #[serde(default)]
+        capped: bool,
+        material: String,
+    },
+    /// A ring around `axis` through `center`.
+    Torus {
+        center: [f64; 3],
+        axis: [f64; 3],
+        major_radius: f64,
+        minor_radius: f64,
+        material: String,
+    },
+    ConstantMedium {
+        boundary: Box<ObjectDesc>,
+        density: f64,
+        color: [f64; 3],
+    },
+    Translate {
+        offset: [f64; 3],
+        object: Box<ObjectDesc>,
+    },
+    /// Rotates `object` by `angle` degrees around `axis`.
+    Rotate {
+        angle: f64,
+        axis: [f64; 3],
+        object: Box<ObjectDesc>,
+    },
+    /// Places `object` with an affine transform. `object` is scaled first, then rotated by
+    /// `angle` degrees around `axis`, translated and finally multiplied by the row-major `matrix`.
+    Transform {
+        translate: Option<[f64; 3]>,
+        angle: Option<f64>,
+        axis: Option<[f64; 3]>,
+        scale: Option<[f64; 3]>,
+        matrix: Option<[[f64; 4]; 4]>,
+        object: Box<ObjectDesc>,
+    },
+    /// Places the named `geometry`, with the same transform fields as `transform`.
+    Instance {
+        geometry: String,
+        translate: Option<[f64; 3]>,
+        angle: Option<f64>,
+        axis: Option<[f64; 3]>,
+        scale: Option<[f64; 3]>,
+        matrix: Option<[[f64; 4]; 4]>,
+    },
+    /// All meshes of a Wavefront OBJ file.
+    Obj {
+        path: String,
+    },
+    List {
+        objects: Vec<ObjectDesc>,
+    },
+    /// The solid inside any of `objects`.
+    Union {
+        objects: Vec<ObjectDesc>,
+    },
+    /// The solid inside all of `objects`.
+    Intersection {
+        objects: Vec<ObjectDesc>,
+    },
+    /// The first of `objects` with the others cut away.
+    Difference {
+        objects: Vec<ObjectDesc>,
+    },
+    /// A signed distance `shape`, sphere traced within the box from `min` to `max`.
+    Sdf {
+        min: [f64; 3],
+        max: [f64; 3],
+        shape: SdfDesc,
+        material: String,
+    },
+    /// A terrain from the luminance of a grayscale image, spanning `size` from `min`.
+    Heightfield {
+        path: String,
+        min: [f64; 3],
+        size: [f64; 3],
+        material: String,
+    },
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+enum SdfDesc {
+    Sphere {
+        radius: f64,
+    },
+    Box {
+        half_size: [f64; 3],
+    },
+    RoundBox {
+        half_size: [f64; 3],
+        radius: f64,
+    },
+    Torus {
+        major_radius: f64,
+        minor_radius: f64,
+    },
+    Capsule {
+        a: [f64; 3],
+        b: [f64; 3],
+        radius: f64,
+    },
+    SmoothUnion {
+        left: Box<SdfDesc>,
+        right: Box<SdfDesc>,
+        smoothness: f64,
+    },
+    /// Cuts `right` out of `left`.
+    SmoothSubtraction {
+        left: Box<SdfDesc>,
+        right: Box<SdfDesc>,
+        smoothness: f64,
+    },
+    Translate {
+        offset: [f64; 3],
+        shape: Box<SdfDesc>,
+    },
+    Repeat {
+        period: [f64; 3],
+        shape: Box<SdfDesc>,
+    },
+    /// Twists `shape` around the y axis by `angle` degrees per unit of height.
+    Twist {
+        angle: f64,
+        shape: Box<SdfDesc>,
+    },
+    /// Bends `shape` in the xy plane by `angle` degrees per unit along x.
+    Bend {
+        angle: f64,
+        shape: Box<SdfDesc>,
+    },
+}
+
+impl SdfDesc {
+    fn sdf(&self) -> Sdf {
+        match *self {
+            Self::Sphere { radius } => Sdf::new_sphere(radius),
+            Self::Box { half_size } => Sdf::new_box(Vector::from_array(half_size)),
+            Self::RoundBox { half_size, radius } => {
+                Sdf::new_round_box(Vector::from_array(half_size), radius)
+            }
+            Self::Torus {
+                major_radius,
+                minor_radius,
+            } => Sdf::new_torus(major_radius, minor_radius),
+            Self::Capsule { a, b, radius } => {
+                Sdf::new_capsule(Point::from_array(a), Point::from_array(b), radius)
+            }
+            Self::SmoothUnion {
+                ref left,
+                ref right,
+                smoothness,
+            } => Sdf::new_smooth_union(left.sdf(), right.sdf(), smoothness),
+            Self::SmoothSubtraction {
+                ref left,
+                ref right,
+                smoothness,
+            } => Sdf::new_smooth_subtraction(left.sdf(), right.sdf(), smoothness),
+            Self::Translate { offset, ref shape } => {
+                Sdf::new_translate(shape.sdf(), Vector::from_array(offset))
+            }
+            Self::Repeat { period, ref shape } => {
+                Sdf::new_repeat(shape.sdf(), Vector::from_array(period))
+            }
+            Self::Twist { angle, ref shape } => Sdf::new_twist(shape.sdf(), angle.to_radians()),
+            Self::Bend { angle, ref shape } => Sdf::new_bend(shape.sdf(), angle.to_radians()),
+        }
+    }
+}
+
+struct SceneBuilder<'a> {
+    source: &'a str,
+    path: &'a Path,
+    directory: &'a Path,
+}
+
+impl<'a> SceneBuilder<'a> {
+    fn error_at(&self, span: Range<usize>, message: String) -> SceneError {
+        let before = &self.source[..span.start.min(self.source.len())];
+        SceneError::Parse {
+            path: self.path.to_path_buf(),
+            line: before.matches('\n').count() + 1,
+            column: before.chars().rev().take_while(|&c| c != '\n').count() + 1,
+            message,
+        }
+    }
+
+    fn build(&self, desc: SceneDesc) -> SceneResult<Scene> {
+        let defaults = CameraSettings::default();
+        let camera = CameraSettings {
+            lookfrom: desc
+                .camera
+                .lookfrom
+                .map_or(defaults.lookfrom, Vector::from_array),
+            lookat: desc
+                .camera
+                .lookat
+                .map_or(defaults.lookat, Vector::from_array),
+            vup: desc.camera.vup.map_or(defaults.vup, Vector::from_array),
+            vfov: desc.camera.vfov.unwrap_or(defaults.vfov),
+            aperture: desc.camera.aperture.unwrap_or(defaults.aperture),
+            focus_dist: desc.camera.focus_distance.unwrap_or(defaults.focus_dist),
+            time_open: desc.camera.time_open.unwrap_or(defaults.time_open),
+            time_close: desc.camera.time_close.unwrap_or(defaults.time_close),
+        };
+
+        let tone_mapping_defaults = ToneMapping::default();
+        let tone_mapping = ToneMapping {
+            exposure: desc
+                .tone_mapping
+                .exposure
+                .unwrap_or(tone_mapping_defaults.exposure),
+            operator: desc
+                .tone_mapping
+                .operator
+                .unwrap_or(tone_mapping_defaults.operator),
+            white_point: desc
+                .tone_mapping
+                .white_point
+                .unwrap_or(tone_mapping_defaults.white_point),
+        };
+
+        let mut textures = HashMap::new();
+        for name in desc.textures.keys() {
+            self.texture(name, &desc.textures, &mut textures, &mut Vec::new())?;
+        }
+
+        let mut materials = HashMap::new();
+        for name in desc.materials.keys() {
+            self.material(
+                name,
+                &desc.materials,
+                &textures,
+                &mut materials,
+                &mut Vec::new(),
+            )?;
+        }
+
+        let mut geometry = HashMap::new();
+        for name in desc.geometry.keys() {
+            self.geometry(
+                name,
+                &desc.geometry,
+                &materials,
+                &mut geometry,
+                &mut Vec::new(),
+                (camera.time_open, camera.time_close),
+            )?;
+        }
+
+        // Instances in the world share one top level BVH, which the renderer can rebuild
+        // without touching their geometry.
+        let mut world = Vec::new();
+        let mut instances = Vec::new();
+        for object in &desc.objects {
+            match *object.get_ref() {
+                ObjectDesc::Instance {
+                    geometry: ref name,
+                    translate,
+                    angle,
+                    axis,
+                    scale,
+                    matrix,
+                } => {
+                    let span = object.span();
+                    let shared = self.shared_geometry(name, span.clone(), &geometry)?;
+                    let matrix = self.transform(span, translate, angle, axis, scale, matrix)?;
+                    instances.push((shared, matrix));
+                }
+                ref desc => {
+                    world.append(&mut self.object(desc, object.span(), &materials, &geometry)?)
+                }
+            }
+        }
+        if !instances.is_empty() {
+            world.push(Hittable::new_instance_bvh(
+                instances,
+                camera.time_open,
+                camera.time_close,
+            ));
+        }
+        let mut lights = Vec::new();
+        for light in &desc.lights {
+            lights.append(&mut self.object(
+                light.get_ref(),
+                light.span(),
+                &materials,
+                &geometry,
+            )?);
+        }
+        // Lights still need to be a part of the world too.
+        world.extend(lights.iter().cloned());
+
+        Ok(Scene {
+            world,
+            lights,
+            camera,
+            background: desc
+                .background
+                .map_or_else(default_background, Color::from_array),
+            tone_mapping,
+        })
+    }
+
+    /// Resolves the texture `name`, `visiting` holds the textures currently
+    /// being resolved to detect cycles between checker textures.
+    fn texture(
+        &self,
+        name: &str,
+        descs: &HashMap<String, Spanned<TextureDesc>>,
+        textures: &mut HashMap<String, Texture>,
+        visiting: &mut Vec<String>,
+    ) -> SceneResult<Texture> {
+        if let Some(texture) = textures.get(name) {
+            return Ok(texture.clone());
+        }
+        let desc = match descs.get(name) {
+            Some(desc) => desc,
+            None => return Err(self.error_at(0..0, format!("unknown texture '{}'", name))),
+        };
+        if visiting.iter().any(|n| n == name) {
+            return Err(self.error_at(desc.span(), format!("texture '{}' references itself", name)));
+        }
+
+        visiting.push(name.to_string());
+        let mut resolve = |other: &str| {
+            if !descs.contains_key(other) {
+                return Err(self.error_at(desc.span(), format!("unknown texture '{}'", other)));
+            }
+            self.texture(other, descs, textures, visiting)
+        };
+        let texture = match *desc.get_ref() {
+            TextureDesc::Solid { color } => Texture::Solid(Color::from_array(color)),
+            TextureDesc::Checker { ref odd, ref even } => {
+                let odd = resolve(odd)?;
+                let even = resolve(even)?;
+                Texture::new_checker(odd, even)
+            }
+            TextureDesc::Image {
+                ref path,
+                filter,
+                wrap,
+                border,
+                scale,
+                rotation,
+                offset,
+                linear,
+                alpha,
+            } => {
+                let defaults = ImageOptions::default();
+                let options = ImageOptions {
+                    filter: filter.unwrap_or(defaults.filter),
+                    wrap: wrap.unwrap_or(defaults.wrap),
+                    border: border.map_or(defaults.border, Color::from_array),
+                    transform: UvTransform {
+                        scale: scale.map_or(defaults.transform.scale, Vec2::from_array),
+                        rotation: rotation.map_or(defaults.transform.rotation, f64::to_radians),
+                        offset: offset.map_or(defaults.transform.offset, Vec2::from_array),
+                    },
+                    linear,
+                    alpha,
+                };
+                let file = self.directory.join(path);
+                Texture::new_image_with_options(&file, options).map_err(|e| {
+                    self.error_at(
+                        desc.span(),
+                        format!("failed to load '{}': {}", file.display(), e),
+                    )
+                })?
+            }
+            TextureDesc::Normal => Texture::Normal,
+            TextureDesc::Uv => Texture::UV,
+            TextureDesc::Noise { scale, seed } => Texture::new_noise(scale.unwrap_or(1.0), seed),
+            TextureDesc::Turbulence { scale, depth, seed } => Texture::new_turbulence(
+                scale.unwrap_or(1.0),
+                depth.unwrap_or(DEFAULT_TURBULENCE_DEPTH),
+                seed,
+            ),
+            TextureDesc::Marble { scale, depth, seed } => Texture::new_marble(
+                scale.unwrap_or(1.0),
+                depth.unwrap_or(DEFAULT_TURBULENCE_DEPTH),
+                seed,
+            ),
+            TextureDesc::Fbm {
+                scale,
+                octaves,
+                lacunarity,
+                gain,
+                seed,
+            } => Texture::new_fbm(
+                scale.unwrap_or(1.0),
+                fractal(octaves, lacunarity, gain),
+                seed,
+            ),
+            TextureDesc::Ridged {
+                scale,
+                octaves,
+                lacunarity,
+                gain,
+                offset,
+                seed,
+            } => Texture::new_ridged(
+                scale.unwrap_or(1.0),
+                fractal(octaves, lacunarity, gain),
+                offset.unwrap_or(1.0),
+                seed,
+            ),
+            TextureDesc::Cells {
+                scale,
+                feature,
+                distance,
+                seed,
+            } => Texture::new_cells(
+                scale.unwrap_or(1.0),
+                feature.unwrap_or(CellFeature::F1),
+                distance.unwrap_or(CellDistance::Euclidean),
+                seed,
+            ),
+            TextureDesc::Wood {
+                rings,
+                scale,
+                distortion,
+                ref light,
+                ref dark,
+                seed,
+            } => {
+                let light = resolve(light)?;
+                let dark = resolve(dark)?;
+                Texture::new_wood(
+                    rings.unwrap_or(10.0),
+                    scale.unwrap_or(1.0),
+                    distortion.unwrap_or(0.5),
+                    light,
+                    dark,
+                    seed,
+                )
+            }
+            TextureDesc::Ramp {
+                ref input,
+                ref stops,
+            } => {
+                if stops.is_empty() {
+                    return Err(
+                        self.error_at(desc.span(), "a ramp needs at least one stop".to_string())
+                    );
+                }
+                let stops = stops
+                    .iter()
+                    .map(|stop| (stop.at, Color::from_array(stop.color)))
+                    .collect();
+                Texture::new_ramp(resolve(input)?, stops)
+            }
+        };
+        visiting.pop();
+
+        textures.insert(name.to_string(), texture.clone());
+        Ok(texture)
+    }
+
+    /// Resolves the material `name` like `texture`, materials may wrap other materials.
+    fn material<'d>(
+        &self,
+        name: &str,
+        descs: &'d HashMap<String, Spanned<MaterialDesc>>,
+        textures: &HashMap<String, Texture>,
+        materials: &mut HashMap<&'d str, Arc<Material>>,
+        visiting: &mut Vec<String>,
+    ) -> SceneResult<Arc<Material>> {
+        if let Some(material) = materials.get(name) {
+            return Ok(material.clone());
+        }
+        let (name, desc) = match descs.get_key_value(name) {
+            Some(entry) => entry,
+            None => return Err(self.error_at(0..0, format!("unknown material '{}'", name))),
+        };
+        let span = desc.span();
+        if visiting.iter().any(|n| n == name) {
+            return Err(self.error_at(span, format!("material '{}' references itself", name)));
+        }
+
+        visiting.push(name.to_string());
+        let texture = |name: &str| {
+            textures
+                .get(name)
+                .cloned()
+                .ok_or_else(|| self.error_at(span.clone(), format!("unknown texture '{}'", name)))
+        };
+        // Materials accept either a constant color or a named texture.
+        let color_or_texture =
+            |color: Option<[f64; 3]>, name: &Option<String>, field| match (color, name) {
+                (Some(color), None) => Ok(Texture::Solid(Color::from_array(color))),
+                (None, Some(name)) => texture(name),
+                _ => Err(self.error_at(
+                    span.clone(),
+                    format!("expected either '{}' or 'texture'", field),
+                )),
+            };
+        let mut resolve = |other: &str| {
+            if !descs.contains_key(other) {
+                return Err(self.error_at(span.clone(), format!("unknown material '{}'", other)));
+            }
+            self.material(other, descs, textures, materials, visiting)
+        };
+        let material = match *desc.get_ref() {
+            MaterialDesc::Lambertian {
+                albedo,
+                texture: ref name,
+            } => Material::new_lambertian_with_texture(color_or_texture(albedo, name, "albedo")?),
+            MaterialDesc::Metal { albedo, fuzz } => {
+                Material::new_metal(Color::from_array(albedo), fuzz)
+            }
+            MaterialDesc::Dielectric { ir } => Material::new_dielectric(ir),
+            MaterialDesc::DiffuseLight {
+                emit,
+                texture: ref name,
+            } => Material::DiffuseLight(color_or_texture(emit, name, "emit")?),
+            MaterialDesc::Isotropic { albedo } => {
+                Material::new_isotropic(Color::from_array(albedo))
+            }
+            MaterialDesc::NormalMap {
+                ref material,
+                texture: ref name,
+            } => Material::new_normal_mapped(resolve(material)?, texture(name)?),
+            MaterialDesc::BumpMap {
+                ref material,
+                texture: ref name,
+                scale,
+            } => Material::new_bump_mapped(resolve(material)?, texture(name)?, scale),
+            MaterialDesc::Cutout {
+                ref material,
+                ref opacity,
+                threshold,
+                stochastic,
+            } => {
+                let alpha = match (threshold, stochastic) {
+                    (threshold, false) => AlphaMode::Threshold(threshold.unwrap_or(0.5)),
+                    (None, true) => AlphaMode::Stochastic,
+                    (Some(_), true) => {
+                        return Err(self.error_at(
+                            span,
+                            "expected either 'threshold' or 'stochastic'".to_string(),
+                        ))
+                    }
+                };
+                Material::new_cutout(resolve(material)?, texture(opacity)?, alpha)
+            }
+        };
+        visiting.pop();
+
+        let material = Arc::new(material);
+        materials.insert(name.as_str(), material.clone());
+        Ok(material)
+    }
+
+    /// Combines the fields of a `transform` or `instance` into one affine matrix,
+    /// `matrix` is applied after scale, rotation and translation.
+    fn transform(
+        &self,
+        span: Range<usize>,
+        translate: Option<[f64; 3]>,
+        angle: Option<f64>,
+        axis: Option<[f64; 3]>,
+        scale: Option<[f64; 3]>,
+        matrix: Option<[[f64; 4]; 4]>,
+    ) -> SceneResult<Matrix> {
+        let trs = Matrix::from_trs(
+            Vector::from_array(translate.unwrap_or([0.0; 3])),
+            angle.unwrap_or(0.0).to_radians(),
+            Vector::from_array(axis.unwrap_or([0.0, 1.0, 0.0])),
+            Vector::from_array(scale.unwrap_or([1.0; 3])),
+        );
+        let matrix = matrix.map_or(trs, |rows| Matrix::from_rows(rows).dot(trs));
+        if matrix.det().abs() < 1e-12 {
+            return Err(self.error_at(span, "transform is not invertible".to_string()));
+        }
+        if matrix[(0, 3)] != 0.0 || matrix[(1, 3)] != 0.0 || matrix[(2, 3)] != 0.0 {
+            return Err(self.error_at(span, "transform is not affine".to_string()));
+        }
+        Ok(matrix)
+    }
+
+    /// Builds the shared geometry `name` like `material`, after the shared geometry it instances.
+    fn geometry<'d>(
+        &self,
+        name: &str,
+        descs: &'d HashMap<String, Spanned<ObjectDesc>>,
+        materials: &HashMap<&str, Arc<Material>>,
+        geometry: &mut HashMap<&'d str, Arc<Hittable>>,
+        visiting: &mut Vec<String>,
+        (time_open, time_close): (f64, f64),
+    ) -> SceneResult<Arc<Hittable>> {
+        if let Some(shared) = geometry.get(name) {
+            return Ok(shared.clone());
+        }
+        let (name, desc) = match descs.get_key_value(name) {
+            Some(entry) => entry,
+            None => return Err(self.error_at(0..0, format!("unknown geometry '{}'", name))),
+        };
+        let span = desc.span();
+        if visiting.iter().any(|n| n == name) {
+            return Err(self.error_at(span, format!("geometry '{}' instances itself", name)));
+        }
+
+        visiting.push(name.to_string());
+        let mut instanced = Vec::new();
+        instanced_geometry(desc.get_ref(), &mut instanced);
+        // Unknown names are reported by `object`, at the instance.
+        for other in instanced
+            .into_iter()
+            .filter(|other| descs.contains_key(*other))
+        {
+            self.geometry(
+                other,
+                descs,
+                materials,
+                geometry,
+                visiting,
+                (time_open, time_close),
+            )?;
+        }
+        visiting.pop();
+
+        let mut objects = self.object(desc.get_ref(), span.clone(), materials, geometry)?;
+        let shared = Arc::new(match objects.len() {
+            0 => return Err(self.error_at(span, "object has no geometry".to_string())),
+            1 => objects.pop().unwrap(),
+            _ => Hittable::new_bvh_sah(&mut objects, time_open, time_close),
+        });
+        geometry.insert(name.as_str(), shared.clone());
+        Ok(shared)
+    }
+
+    /// Looks up the shared geometry placed by an `instance` object.
+    fn shared_geometry(
+        &self,
+        name: &str,
+        span: Range<usize>,
+        geometry: &HashMap<&str, Arc<Hittable>>,
+    ) -> SceneResult<Arc<Hittable>> {
+        geometry
+            .get(name)
+            .cloned()
+            .ok_or_else(|| self.error_at(span, format!("unknown geometry '{}'", name)))
+    }
+
+    fn object(
+        &self,
+        desc: &ObjectDesc,
+        span: Range<usize>,
+        materials: &HashMap<&str, Arc<Material>>,
+        geometry: &HashMap<&str, Arc<Hittable>>,
+    ) -> SceneResult<Vec<Hittable>> {
+        let lookup = |name: &String| {
+            materials
+                .get(name.as_str())
+                .cloned()
+                .ok_or_else(|| self.error_at(span.clone(), format!("unknown material '{}'", name)))
+        };
+        // Degenerate shapes produce NaN normals and frames, which would poison whole pixels.
+        let positive = |value: f64, field: &str| {
+            if value > 0.0 {
+                Ok(value)
+            } else {
+                Err(self.error_at(span.clone(), format!("'{}' must be positive", field)))
+            }
+        };
+        let direction = |vector: [f64; 3], field: &str| {
+            let vector = Vector::from_array(vector);
+            if vector.length() > 0.0 {
+                Ok(vector)
+            } else {
+                Err(self.error_at(span.clone(), format!("'{}' must not be zero", field)))
+            }
+        };
+        // Wrappers need exactly one child, several objects are combined into a list.
+        let single = |desc: &ObjectDesc| {
+            let mut objects = self.object(desc, span.clone(), materials, geometry)?;
+            match objects.len() {
+                0 => Err(self.error_at(span.clone(), "object has no geometry".to_string())),
+                1 => Ok(objects.pop().unwrap()),
+                _ => Ok(Hittable::List(objects)),
+            }
+        };
+        // Boolean operations fold over their objects from the left.
+        let csg = |operation, objects: &[ObjectDesc]| {
+            if objects.len() < 2 {
+                return Err(
+                    self.error_at(span.clone(), "expected at least two objects".to_string())
+                );
+            }
+            let mut solid = single(&objects[0])?;
+            for object in &objects[1..] {
+                solid = Hittable::new_csg(operation, solid, single(object)?);
+            }
+            Ok(vec![solid])
+        };
+
+        Ok(match *desc {
+            ObjectDesc::Sphere {
+                center,
+                radius,
+                ref material,
+            } => vec![Hittable::new_sphere(
+                Point::from_array(center),
+                radius,
+                lookup(material)?,
+            )],
+            ObjectDesc::MovingSphere {
+                center_start,
+                center_end,
+                time_start,
+                time_end,
+                radius,
+                ref material,
+            } => vec![Hittable::new_moving_sphere(
+                Point::from_array(center_start),
+                Point::from_array(center_end),
+                time_start,
+                time_end,
+                radius,
+                lookup(material)?,
+            )],
+            ObjectDesc::Cube {
+                min,
+                max,
+                ref material,
+            } => vec![Hittable::new_cube(
+                Point::from_array(min),
+                Point::from_array(max),
+                lookup(material)?,
+            )],
+            ObjectDesc::Triangle {
+                vertices,
+                ref material,
+            } => vec![Hittable::new_triangle(
+                Point::from_array(vertices[0]),
+                Point::from_array(vertices[1]),
+                Point::from_array(vertices[2]),
+                lookup(material)?,
+            )],
+            ObjectDesc::Quad {
+                origin,
+                u,
+                v,
+                ref material,
+            } => {
+                let (u, v) = (Vector::from_array(u), Vector::from_array(v));
+                if u.cross(v).length() <= 1e-12 * u.length() * v.length() {
+                    return Err(self.error_at(
+                        span,
+                        "'u' and 'v' must be non-zero and not parallel".to_string(),
+                    ));
+                }
+                vec![Hittable::new_quad(
+                    Point::from_array(origin),
+                    u,
+                    v,
+                    lookup(material)?,
+                )]
+            }
+            ObjectDesc::Disk {
+                center,
+                normal,
+                radius,
+                ref material,
+            } => vec![Hittable::new_disk(
+                Point::from_array(center),
+                direction(normal, "normal")?,
+                positive(radius, "radius")?,
+                lookup(material)?,
+            )],
+            ObjectDesc::Plane {
+                point,
+                normal,
+                ref material,
+            } => vec![Hittable::new_plane(
+                Point::from_array(point),
+                direction(normal, "normal")?,
+                lookup(material)?,
+            )],
+            ObjectDesc::Cylinder {
+                base,
+                axis,
+                radius,
+                capped,
+                ref material,
+            } => vec![Hittable::new_cylinder(
+                Point::from_array(base),
+                direction(axis, "axis")?,
+                positive(radius, "radius")?,
+                capped,
+                lookup(material)?,
+            )],
+            ObjectDesc::Cone {
+                base,
+                axis,
+                radius,
+                capped,
+                ref material,
+            } => vec![Hittable::new_cone(
+                Point::from_array(base),
+                direction(axis, "axis")?,
+                positive(radius, "radius")?,
+                capped,
+                lookup(material)?,
+            )],
+            ObjectDesc::Paraboloid {
+                base,
+                axis,
+                radius,
+                capped,
+                ref material,
+            } => vec![Hittable::new_paraboloid(
+                Point::from_array(base),
+                direction(axis, "axis")?,
+                positive(radius, "radius")?,
+                capped,
+                lookup(material)?,
+            )],
+            ObjectDesc::Hyperboloid {
+                base,
+                axis,
+                radius,
+                end_radius,
+                capped,
+                ref material,
+            } => vec![Hittable::new_hyperboloid(
+                Point::from_array(base),
+                direction(axis, "axis")?,
+                positive(radius, "radius")?,
+                positive(end_radius, "end_radius")?,
+                capped,
+                lookup(material)?,
+            )],
+            ObjectDesc::Torus {
+                center,
+                axis,
+                major_radius,
+                minor_radius,
+                ref material,
+            } => {
+                if minor_radius >= major_radius {
+                    return Err(self.error_at(
+                        span,
+                        "'minor_radius' must be less than 'major_radius'".to_string(),
+                    ));
+                }
+                vec![Hittable::new_torus(
+                    Point::from_array(center),
+                    direction(axis, "axis")?,
+                    major_radius,
+                    positive(minor_radius, "minor_radius")?,
+                    lookup(material)?,
+                )]
+            }
+            ObjectDesc::ConstantMedium {
+                ref boundary,
+                density,
+                color,
+            } => vec![Hittable::new_constant_medium(
+                single(boundary)?,
+                density,
+                Color::from_array(color),
+            )],
+            ObjectDesc::Translate { offset, ref object } => vec![Hittable::new_translate(
+                single(object)?,
+                Vector::from_array(offset),
+            )],
+            ObjectDesc::Rotate {
+                angle,
+                axis,
+                ref object,
+            } => vec![Hittable::new_rotate(
+                single(object)?,
+                angle.to_radians(),
+                Vector::from_array(axis),
+            )],
+            ObjectDesc::Transform {
+                translate,
+                angle,
+                axis,
+                scale,
+                matrix,
+                ref object,
+            } => {
+                let matrix = self.transform(span.clone(), translate, angle, axis, scale, matrix)?;
+                vec![Hittable::new_transform(single(object)?, matrix)]
+            }
+            ObjectDesc::Instance {
+                geometry: ref name,
+                translate,
+                angle,
+                axis,
+                scale,
+                matrix,
+            } => {
+                let shared = self.shared_geometry(name, span.clone(), geometry)?;
+                let matrix = self.transform(span, translate, angle, axis, scale, matrix)?;
+                vec![Hittable::new_instance(shared, matrix)]
+            }
+            ObjectDesc::Obj { ref path } => load_obj(self.directory.join(path))?,
+            ObjectDesc::List { ref objects } => {
+                let mut list = Vec::new();
+                for object in objects {
+                    list.append(&mut self.object(object, span.clone(), materials, geometry)?);
+                }
+                list
+            }
+            ObjectDesc::Union { ref objects } => csg(CsgOperation::Union, objects)?,
+            ObjectDesc::Intersection { ref objects } => csg(CsgOperation::Intersection, objects)?,
+            ObjectDesc::Difference { ref objects } => csg(CsgOperation::Difference, objects)?,
+            ObjectDesc::Sdf {
+                min,
+                max,
+                ref shape,
+                ref material,
+            } => vec![Hittable::new_sdf(
+                shape.sdf(),
+                AxisAlignedBoundingBox::new(Point::from_array(min), Point::from_array(max)),
+                lookup(material)?,
+            )],
+            ObjectDesc::Heightfield {
+                ref path,
+                min,
+                size,
+                ref material,
+            } => {
+                if size.iter().any(|&s| s <= 0.0) {
+                    return Err(
+                        self.error_at(span, "heightfield size must be positive".to_string())
+                    );
+                }
+                let file = self.directory.join(path);
+                let image = load_image(&file, true).map_err(|e| {
+                    self.error_at(
+                        span.clone(),
+                        format!("failed to load '{}': {}", file.display(), e),
+                    )
+                })?;
+                if image.width() < 2 || image.height() < 2 {
+                    return Err(self.error_at(
+                        span,
+                        "heightfield image needs at least 2x2 pixels".to_string(),
+                    ));
+                }
+                vec![Hittable::new_heightfield(
+                    &image,
+                    Point::from_array(min),
+                    Vector::from_array(size),
+                    lookup(material)?,
+                )]
+            }
+        })
+    }
+}
+
+/// Collects the names of the shared geometry instanced anywhere within `desc`.
+fn instanced_geometry<'d>(desc: &'d ObjectDesc, names: &mut Vec<&'d str>) {
+    match *desc {
+        ObjectDesc::Instance { ref geometry, .. } => names.push(geometry),
+        ObjectDesc::ConstantMedium { ref boundary, .. } => instanced_geometry(boundary, names),
+        ObjectDesc::Translate { ref object, .. }
+        | ObjectDesc::Rotate { ref object, .. }
+        | ObjectDesc::Transform { ref object, .. } => instanced_geometry(object, names),
+        ObjectDesc::List { ref objects }
+        | ObjectDesc::Union { ref objects }
+        | ObjectDesc::Intersection { ref objects }
+        | ObjectDesc::Difference { ref objects } => {
+            for object in objects {
+                instanced_geometry(object, names);
+            }
+        }
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::input::TempFile;
+    use crate::ray::Ray;
+    use test_case::test_case;
+
+    #[test]
+    fn test_parse_scene() {
+        let source = r#"
+            background = [0.0, 0.0, 0.0]
+
+            [tone_mapping]
+            exposure = -1.5
+            operator = "aces"
+
+            [camera]
+            lookfrom = [0.0, 0.0, 5.0]
+            vfov = 40
+
+            [textures.white]
+            type = "solid"
+            color = [0.9, 0.9, 0.9]
+
+            [textures.checker]
+            type = "checker"
+            odd = "white"
+            even = "white"
+
+            [materials.ground]
+            type = "lambertian"
+            texture = "checker"
+
+            [materials.lamp]
+            type = "diffuse_light"
+            emit = [4.0, 4.0, 4.0]
+
+            [[objects]]
+            type = "rotate"
+            angle = 45
+            axis = [0.0, 1.0, 0.0]
+            object = { type = "cube", min = [-1, -1, -1], max = [1, 1, 1], material = "ground" }
+
+            [[lights]]
+            type = "sphere"
+            center = [0.0, 3.0, 0.0]
+            radius = 0.5
+            material = "lamp"
+        "#;
+        let scene = Scene::parse(source, Path::new("test.toml")).unwrap();
+        assert_eq!(scene.world.len(), 2);
+        assert_eq!(scene.lights.len(), 1);
+        assert_eq!(scene.camera.vfov, 40.0);
+        assert_eq!(scene.camera.lookfrom, Point::from(0.0, 0.0, 5.0));
+        assert_eq!(scene.tone_mapping.operator, ToneMapOperator::Aces);
+        assert_eq!(scene.tone_mapping.exposure, -1.5);
+    }
+
+    #[test]
+    fn test_unknown_material_reports_position() {
+        let source = r#"[materials.red]
+type = "lambertian"
+albedo = [1, 0, 0]
+
+[[objects]]
+type = "sphere"
+center = [0, 0, 0]
+radius = 1
+material = "blue"
+"#;
+        match Scene::parse(source, Path::new("test.toml")) {
+            Err(SceneError::Parse {
+                line,
+                column,
+                message,
+                ..
+            }) => {
+                assert_eq!((line, column), (5, 1));
+                assert_eq!(message, "unknown material 'blue'");
+            }
+            _ => panic!("expected a parse error"),
+        }
+    }
+
+    #[test]
+    fn test_transform_composes_matrix_and_trs() {
+        let source = r#"[materials.red]
+type = "lambertian"
+albedo = [1, 0, 0]
+
+[[objects]]
+type = "transform"
+scale = [2, 1, 1]
+angle = 90
+axis = [0, 0, 1]
+matrix = [[1, 0, 0, 5], [0, 1, 0, 0], [0, 0, 1, 0], [0, 0, 0, 1]]
+object = { type = "sphere", center = [0, 0, 0], radius = 1, material = "red" }
+"#;
+        let scene = Scene::parse(source, Path::new("test.toml")).unwrap();
+        let aabb = scene.world[0].bounding_box(0.0, 1.0).unwrap();
+        assert!((aabb.minimum - Point::from(4.0, -2.0, -1.0)).near_zero());
+        assert!((aabb.maximum - Point::from(6.0, 2.0, 1.0)).near_zero());
+    }
+
+    #[test]
+    fn test_instances_share_geometry() {
+        let source = r#"[materials.red]
+type = "lambertian"
+albedo = [1, 0, 0]
+
+[geometry.pair]
+type = "list"
+objects = [
+    { type = "sphere", center = [-1, 0, 0], radius = 1, material = "red" },
+    { type = "sphere", center = [1, 0, 0], radius = 1, material = "red" },
+]
+
+[[objects]]
+type = "instance"
+geometry = "pair"
+
+[[objects]]
+type = "instance"
+geometry = "pair"
+translate = [0, 5, 0]
+scale = [0.5, 0.5, 0.5]
+"#;
+        let scene = Scene::parse(source, Path::new("test.toml")).unwrap();
+        // Both instances end up in one top level BVH.
+        assert_eq!(scene.world.len(), 1);
+        let aabb = scene.world[0].bounding_box(0.0, 1.0).unwrap();
+        assert!((aabb.minimum - Point::from(-2.0, -1.0, -1.0)).near_zero());
+        assert!((aabb.maximum - Point::from(2.0, 5.5, 1.0)).near_zero());
+        match scene.world[0] {
+            Hittable::Instances(ref instances) => {
+                let [ref a, ref b] = instances.instances() else {
+                    panic!("expected two instances")
+                };
+                assert!(Arc::ptr_eq(a.geometry(), b.geometry()))
+            }
+            _ => panic!("expected an instance BVH"),
+        }
+    }
+
+    #[test]
+    fn test_flat_primitives() {
+        let source = r#"[materials.white]
+type = "lambertian"
+albedo = [1, 1, 1]
+
+[[objects]]
+type = "quad"
+origin = [0, 0, 0]
+u = [2, 0, 0]
+v = [0, 0, 1]
+material = "white"
+
+[[objects]]
+type = "disk"
+center = [0, 1, 0]
+normal = [0, 0, 1]
+radius = 0.5
+material = "white"
+
+[[objects]]
+type = "plane"
+point = [0, -1, 0]
+normal = [0, 1, 0]
+material = "white"
+"#;
+        let scene = Scene::parse(source, Path::new("test.toml")).unwrap();
+        let aabb = scene.world[0].bounding_box(0.0, 1.0).unwrap();
+        // Flat primitives pad their bounds.
+        assert!((aabb.maximum - Point::from(2.0, 0.0, 1.0)).length() < 1e-3);
+        let aabb = scene.world[1].bounding_box(0.0, 1.0).unwrap();
+        assert!((aabb.minimum - Point::from(-0.5, 0.5, 0.0)).length() < 1e-3);
+        assert!(scene.world[2].bounding_box(0.0, 1.0).is_none());
+    }
+
+    #[test_case("type = \"quad\"\norigin = [0, 0, 0]\nu = [1, 0, 0]\nv = [2, 0, 0]", "'u' and 'v' must be non-zero and not parallel"; "parallel quad")]
+    #[test_case("type = \"quad\"\norigin = [0, 0, 0]\nu = [0, 0, 0]\nv = [0, 1, 0]", "'u' and 'v' must be non-zero and not parallel"; "zero quad")]
+    #[test_case("type = \"disk\"\ncenter = [0, 0, 0]\nnormal = [0, 0, 0]\nradius = 1", "'normal' must not be zero"; "disk normal")]
+    #[test_case("type = \"disk\"\ncenter = [0, 0, 0]\nnormal = [0, 1, 0]\nradius = 0", "'radius' must be positive"; "disk radius")]
+    #[test_case("type = \"plane\"\npoint = [0, 0, 0]\nnormal = [0, 0, 0]", "'normal' must not be zero"; "plane normal")]
+    fn test_degenerate_flat_primitives(object: &str, error: &str) {
+        // As lights, degenerate shapes would be sampled with infinite or NaN densities.
+        let source = format!(
+            "[materials.white]\ntype = \"lambertian\"\nalbedo = [1, 1, 1]\n\n[[lights]]\n{}\nmaterial = \"white\"\n",
+            object
+        );
+        match Scene::parse(&source, Path::new("test.toml")) {
+            Err(SceneError::Parse { line, message, .. }) => {
+                assert_eq!(line, 5);
+                assert_eq!(message, error);
+            }
+            _ => panic!("expected a parse error"),
+        }
+    }
+
+    #[test_case("type = \"cylinder\"\nbase = [0, 0, 0]\naxis = [0, 0, 0]\nradius = 1", "'axis' must not be zero"; "cylinder axis")]
+    #[test_case("type = \"cone\"\nbase = [0, 0, 0]\naxis = [0, 0, 0]\nradius = 1", "'axis' must not be zero"; "cone axis")]
+    #[test_case("type = \"paraboloid\"\nbase = [0, 0, 0]\naxis = [0, 1, 0]\nradius = -1", "'radius' must be positive"; "paraboloid radius")]
+    #[test_case("type = \"hyperboloid\"\nbase = [0, 0, 0]\naxis = [0, 1, 0]\nradius = 1\nend_radius = 0", "'end_radius' must be positive"; "hyperboloid end radius")]
+    #[test_case("type = \"torus\"\ncenter = [0, 0, 0]\naxis = [0, 1, 0]\nmajor_radius = 1\nminor_radius = 2", "'minor_radius' must be less than 'major_radius'"; "torus radii")]
+    #[test_case("type = \"torus\"\ncenter = [0, 0, 0]\naxis = [0, 1, 0]\nmajor_radius = 1\nminor_radius = 0", "'minor_radius' must be positive"; "torus minor radius")]
+    fn test_degenerate_quadrics(object: &str, error: &str) {
+        let source = format!(
+            "[materials.white]\ntype = \"lambertian\"\nalbedo = [1, 1, 1]\n\n[[objects]]\n{}\nmaterial = \"white\"\n",
+            object
+        );
+        match Scene::parse(&source, Path::new("test.toml")) {
+            Err(SceneError::Parse { line, message, .. }) => {
+                assert_eq!(line, 5);
+                assert_eq!(message, error);
+            }
+            _ => panic!("expected a parse error"),
+        }
+    }
+
+    #[test_case("difference", 2, Ok(1.0))]
+    #[test_case("union", 3, Ok(3.0))]
+    #[test_case("intersection", 1, Err("expected at least two objects"))]
+    fn test_csg(operation: &str, count: usize, expected: Result<f64, &str>) {
+        let spheres = (0..count)
+            .map(|i| {
+                format!(
+                    "{{ type = \"sphere\", center = [{}, 0, 0], radius = 1, material = \"white\" }}",
+                    i
+                )
+            })
+            .collect::<Vec<_>>()
+            .join(", ");
+        let source = format!(
+            "[materials.white]\ntype = \"lambertian\"\nalbedo = [1, 1, 1]\n\n\
+             [[objects]]\ntype = \"{}\"\nobjects = [{}]\n",
+            operation, spheres
+        );
+        match (Scene::parse(&source, Path::new("test.toml")), expected) {
+            // The objects combine into a single solid, spanning to `maximum` along x.
+            (Ok(scene), Ok(maximum)) => {
+                assert_eq!(scene.world.len(), 1);
+                let aabb = scene.world[0].bounding_box(0.0, 1.0).unwrap();
+                assert!((aabb.maximum[0] - maximum).abs() < 1e-9);
+            }
+            (Err(SceneError::Parse { message, .. }), Err(error)) => assert_eq!(message, error),
+            _ => panic!("unexpected result"),
+        }
+    }
+
+    #[test]
+    fn test_sdf_shape_tree() {
+        let source = r#"[materials.white]
+type = "lambertian"
+albedo = [1, 1, 1]
+
+[[objects]]
+type = "sdf"
+min = [-2, -1, -1]
+max = [2, 1, 1]
+material = "white"
+
+[objects.shape]
+type = "smooth_union"
+smoothness = 0.2
+left = { type = "sphere", radius = 0.5 }
+
+[objects.shape.right]
+type = "translate"
+offset = [1, 0, 0]
+shape = { type = "twist", angle = 90, shape = { type = "box", half_size = [0.5, 0.5, 0.5] } }
+"#;
+        let scene = Scene::parse(source, Path::new("test.toml")).unwrap();
+        let aabb = scene.world[0].bounding_box(0.0, 1.0).unwrap();
+        assert!((aabb.minimum - Point::from(-2.0, -1.0, -1.0)).near_zero());
+        let r = Ray::new(
+            Point::from(1.0, 0.0, 5.0),
+            Vector::from(0.0, 0.0, -1.0),
+            0.0,
+        );
+        let hit = scene.world[0]
+            .hit(&r, 0.001, f64::INFINITY, &mut rand::thread_rng())
+            .unwrap();
+        assert!((hit.t - 4.5).abs() < 1e-3);
+    }
+
+    #[test]
+    fn test_heightfield() {
+        let source = r#"[materials.white]
+type = "lambertian"
+albedo = [1, 1, 1]
+
+[[objects]]
+type = "heightfield"
+path = "../assets/terrain.png"
+min = [-5, 1, -5]
+size = [10, 2, 10]
+material = "white"
+"#;
+        let scene = Scene::parse(source, Path::new("scenes/test.toml")).unwrap();
+        let aabb = scene.world[0].bounding_box(0.0, 1.0).unwrap();
+        assert!((aabb.minimum - Point::from(-5.0, 1.0, -5.0)).length() < 1e-3);
+        assert!((aabb.maximum - Point::from(5.0, 3.0, 5.0)).length() < 1e-3);
+
+        let source = source.replace("terrain.png", "missing.png");
+        match Scene::parse(&source, Path::new("scenes/test.toml")) {
+            Err(SceneError::Parse { message, .. }) => {
+                assert!(message.starts_with("failed to load"), "{}", message)
+            }
+            _ => panic!("expected a parse error"),
+        }
+
+        let source = source.replace("size = [10, 2, 10]", "size = [10, 2, 0]");
+        match Scene::parse(&source, Path::new("scenes/test.toml")) {
+            Err(SceneError::Parse { message, .. }) => {
+                assert_eq!(message, "heightfield size must be positive")
+            }
+            _ => panic!("expected a parse error"),
+        }
+
+        // A single row of pixels has no cells.
+        let row = TempFile::new("test_heightfield.png");
+        image::GrayImage::new(4, 1).save(&row.0).unwrap();
+        let source = format!(
+            "[materials.white]\ntype = \"lambertian\"\nalbedo = [1, 1, 1]\n\n[[objects]]\ntype = \"heightfield\"\npath = {:?}\nmin = [0, 0, 0]\nsize = [1, 1, 1]\nmaterial = \"white\"\n",
+            row.0.display().to_string()
+        );
+        match Scene::parse(&source, Path::new("test.toml")) {
+            Err(SceneError::Parse { message, .. }) => {
+                assert_eq!(message, "heightfield image needs at least 2x2 pixels")
+            }
+            _ => panic!("expected a parse error"),
+        }
+    }
+
+    #[test]
+    fn test_example_scenes_load() {
+        for entry in fs::read_dir("scenes").unwrap() {
+            let path = entry.unwrap().path();
+            if path.extension().is_some_and(|e| e == "toml") {
+                let scene = Scene::load(&path).unwrap_or_else(|e| panic!("{}", e));
+                assert!(!scene.world.is_empty(), "{}", path.display());
+            }
+        }
+    }
+
+    #[test]
+    fn test_geometry_instances_geometry() {
+        let source = r#"[materials.red]
+type = "lambertian"
+albedo = [1, 0, 0]
+
+[geometry.pair]
+type = "list"
+objects = [
+    { type = "instance", geometry = "ball", translate = [-1, 0, 0] },
+    { type = "instance", geometry = "ball", translate = [1, 0, 0] },
+]
+
+[geometry.ball]
+type = "sphere"
+center = [0, 0, 0]
+radius = 1
+material = "red"
+
+[[objects]]
+type = "instance"
+geometry = "pair"
+translate = [0, 5, 0]
+"#;
+        // Geometry is built in dependency order, whatever order the map iterates in.
+        for _ in 0..8 {
+            let scene = Scene::parse(source, Path::new("test.toml")).unwrap();
+            let aabb = scene.world[0].bounding_box(0.0, 1.0).unwrap();
+            assert!((aabb.minimum - Point::from(-2.0, 4.0, -1.0)).near_zero());
+            assert!((aabb.maximum - Point::from(2.0, 6.0, 1.0)).near_zero());
+        }
+
+        let source = source.replace(
+            "type = \"sphere\"",
+            "type = \"instance\"\ngeometry = \"pair\"",
+        );
+        let source = source.replace("center = [0, 0, 0]\nradius = 1\nmaterial = \"red\"\n", "");
+        match Scene::parse(&source, Path::new("test.toml")) {
+            Err(SceneError::Parse { message, .. }) => {
+                assert!(message.ends_with("instances itself"), "{}", message)
+            }
+            _ => panic!("expected a parse error"),
+        }
+    }
+
+    #[test]
+    fn test_unknown_geometry() {
+        let source = "[[objects]]\ntype = \"instance\"\ngeometry = \"tree\"\n";
+        match Scene::parse(source, Path::new("test.toml")) {
+            Err(SceneError::Parse { message, .. }) => {
+                assert_eq!(message, "unknown geometry 'tree'")
+            }
+            _ => panic!("expected a parse error"),
+        }
+    }
+
+    #[test_case("scale = [1, 0, 1]", "transform is not invertible")]
+    #[test_case(
+        "matrix = [[1, 0, 0, 0], [0, 1, 0, 0], [0, 0, 1, 0], [0, 0, 1, 1]]",
+        "transform is not affine"
+    )]
+    fn test_invalid_transform(field: &str, error: &str) {
+        let source = format!(
+            r#"[materials.red]
+type = "lambertian"
+albedo = [1, 0, 0]
+
+[[objects]]
+type = "transform"
+{}
+object = {{ type = "sphere", center = [0, 0, 0], radius = 1, material = "red" }}
+"#,
+            field
+        );
+        match Scene::parse(&source, Path::new("test.toml")) {
+            Err(SceneError::Parse { line, message, .. }) => {
+                assert_eq!(line, 5);
+                assert_eq!(message, error);
+            }
+            _ => panic!("expected a parse error"),
+        }
+    }
+
+    #[test]
+    fn test_procedural_textures_nest() {
+        let source = r#"[textures.cracks]
+type = "cells"
+feature = "f2_minus_f1"
+distance = "manhattan"
+
+[textures.mud]
+type = "ramp"
+input = "cracks"
+stops = [{ at = 0.0, color = [0.1, 0.05, 0.0] }, { at = 0.2, color = [0.6, 0.4, 0.2] }]
+
+[textures.pine]
+type = "solid"
+color = [0.8, 0.6, 0.4]
+
+[textures.wood]
+type = "wood"
+light = "pine"
+dark = "mud"
+
+[textures.floor]
+type = "checker"
+odd = "wood"
+even = "mud"
+"#;
+        let scene = Scene::parse(source, Path::new("test.toml")).unwrap();
+        assert!(scene.world.is_empty());
+    }
+
+    #[test]
+    fn test_texture_cycle_is_an_error() {
+        let source = r#"[textures.a]
+type = "ramp"
+input = "b"
+stops = [{ at = 0.0, color = [0, 0, 0] }]
+
+[textures.b]
+type = "wood"
+light = "a"
+dark = "a"
+"#;
+        match Scene::parse(source, Path::new("test.toml")) {
+            Err(SceneError::Parse { message, .. }) => {
+                assert!(message.ends_with("references itself"))
+            }
+            _ => panic!("expected a parse error"),
+        }
+    }
+
+    #[test]
+    fn test_bump_maps_wrap_materials() {
+        let source = r#"[textures.normals]
+type = "solid"
+color = [0.5, 0.5, 1.0]
+
+[materials.red]
+type = "lambertian"
+albedo = [1, 0, 0]
+
+[materials.bumpy]
+type = "bump_map"
+material = "red"
+texture = "normals"
+scale = 0.1
+
+[materials.detailed]
+type = "normal_map"
+material = "bumpy"
+texture = "normals"
+
+[[objects]]
+type = "sphere"
+center = [0, 0, 0]
+radius = 1
+material = "detailed"
+"#;
+        let scene = Scene::parse(source, Path::new("test.toml")).unwrap();
+        assert_eq!(scene.world.len(), 1);
+    }
+
+    #[test_case("threshold = 0.3", true)]
+    #[test_case("stochastic = true", true)]
+    #[test_case("threshold = 0.3\nstochastic = true", false)]
+    fn test_cutout_alpha_mode(alpha: &str, valid: bool) {
+        let source = format!(
+            r#"[textures.holes]
+type = "cells"
+
+[materials.red]
+type = "lambertian"
+albedo = [1, 0, 0]
+
+[materials.leaf]
+type = "cutout"
+material = "red"
+opacity = "holes"
+{}
+"#,
+            alpha
+        );
+        let result = Scene::parse(&source, Path::new("test.toml"));
+        assert_eq!(result.is_ok(), valid);
+    }
+
+    #[test]
+    fn test_material_cycle_is_an_error() {
+        let source = r#"[textures.normals]
+type = "solid"
+color = [0.5, 0.5, 1.0]
+
+[materials.a]
+type = "normal_map"
+material = "b"
+texture = "normals"
+
+[materials.b]
+type = "bump_map"
+material = "a"
+texture = "normals"
+scale = 1.0
+"#;
+        match Scene::parse(source, Path::new("test.toml")) {
+            Err(SceneError::Parse { message, .. }) => {
+                assert!(message.ends_with("references itself"))
+            }
+            _ => panic!("expected a parse error"),
+        }
+    }
+
+    #[test]
+    fn test_syntax_error_reports_position() {
+        let source = "[camera]\nvfov = \"wide\"\n";
+        match Scene::parse(source, Path::new("test.toml")) {
+            Err(SceneError::Parse { line, column, .. }) => assert_eq!((line, column), (2, 8)),
+            _ => panic!("expected a parse error"),
+        }
+    }
+}
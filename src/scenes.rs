@@ -1,6 +1,9 @@
 use crate::alias::*;
+use crate::csg::*;
 use crate::hittable::*;
 use crate::material::*;
+use crate::mesh::*;
+use crate::obj::*;
 use crate::texture::*;
 
 use rand::rngs::StdRng;
@@ -27,6 +30,23 @@ pub fn two_spheres() -> (Vec<Hittable>, Vec<Hittable>) {
     (world, vec![])
 }
 
+pub fn two_perlin_spheres() -> (Vec<Hittable>, Vec<Hittable>) {
+    let world = vec![
+        Hittable::new_sphere(
+            Point::from(0.0, -1000.0, 0.0),
+            1000.0,
+            Material::new_lambertian_with_texture(Texture::new_noise(4.0, 0)),
+        ),
+        Hittable::new_sphere(
+            Point::from(0.0, 2.0, 0.0),
+            2.0,
+            Material::new_lambertian_with_texture(Texture::new_marble(4.0, 7, 0)),
+        ),
+    ];
+
+    (world, vec![])
+}
+
 pub fn small_scene() -> (Vec<Hittable>, Vec<Hittable>) {
     let material_ground = Material::new_lambertian(Color::from(0.8, 0.8, 0.0));
     let material_center = Material::new_lambertian(Color::from(0.1, 0.2, 0.5));
@@ -36,18 +56,15 @@ pub fn small_scene() -> (Vec<Hittable>, Vec<Hittable>) {
     let sphere_ground =
         Hittable::new_sphere(Point::from(0.0, -100.5, -1.0), 100.0, material_ground);
     let sphere_center = Hittable::new_sphere(Point::from(0.0, 0.0, -1.0), 0.5, material_center);
-    let sphere_left =
-        Hittable::new_sphere(Point::from(-1.0, 0.0, -1.0), 0.5, material_left.clone());
-    let sphere_left_inner = Hittable::new_sphere(Point::from(-1.0, 0.0, -1.0), -0.4, material_left);
+    // A hollow glass sphere, the inner sphere is cut out of the outer one.
+    let sphere_left = Hittable::new_csg(
+        CsgOperation::Difference,
+        Hittable::new_sphere(Point::from(-1.0, 0.0, -1.0), 0.5, material_left.clone()),
+        Hittable::new_sphere(Point::from(-1.0, 0.0, -1.0), 0.4, material_left),
+    );
     let sphere_right = Hittable::new_sphere(Point::from(1.0, 0.0, -1.0), 0.5, material_right);
 
-    let world = vec![
-        sphere_ground,
-        sphere_center,
-        sphere_left,
-        sphere_left_inner,
-        sphere_right,
-    ];
+    let world = vec![sphere_ground, sphere_center, sphere_left, sphere_right];
 
     (world, vec![])
 }
@@ -74,7 +91,7 @@ pub fn random_scene() -> (Vec<Hittable>, Vec<Hittable>) {
             if (center - Point::from(4.0, 0.2, 0.0)).length() > 0.9 {
                 let sphere_material = if choose_mat < 0.8 {
                     // diffuse
-                    let albedo = Vector::random_vector() * Vector::random_vector();
+                    let albedo = Vector::random_vector(&mut rng) * Vector::random_vector(&mut rng);
                     Material::new_lambertian(albedo)
                 } else if choose_mat < 0.95 {
                     // metal
@@ -190,3 +207,68 @@ pub fn cube_scene() -> (Vec<Hittable>, Vec<Hittable>) {
     )];
     (world, vec![])
 }
+
+pub fn mesh_scene() -> (Vec<Hittable>, Vec<Hittable>) {
+    // Tessellate a sphere into a triangle mesh, sharing vertices between faces.
+    let rings = 16;
+    let segments = 32;
+    let radius = 1.5;
+    let mut positions = Vec::new();
+    let mut normals = Vec::new();
+    let mut uvs = Vec::new();
+    for i in 0..=rings {
+        for j in 0..=segments {
+            let uv = Vec2::from(j as f64 / segments as f64, i as f64 / rings as f64);
+            let theta = uv[1] * std::f64::consts::PI;
+            let phi = uv[0] * 2.0 * std::f64::consts::PI;
+            let normal = Vector::from(
+                -phi.cos() * theta.sin(),
+                -theta.cos(),
+                phi.sin() * theta.sin(),
+            );
+            positions.push(radius * normal);
+            normals.push(normal);
+            uvs.push(uv);
+        }
+    }
+
+    let index = |i: usize, j: usize| {
+        let index = i * (segments + 1) + j;
+        VertexIndex::new(index, Some(index), Some(index))
+    };
+    let mut faces = Vec::new();
+    for i in 0..rings {
+        for j in 0..segments {
+            faces.push([index(i, j), index(i, j + 1), index(i + 1, j + 1)]);
+            faces.push([index(i, j), index(i + 1, j + 1), index(i + 1, j)]);
+        }
+    }
+
+    let earth_texture = Texture::new_image("assets/earthmap.jpg").unwrap();
+    let globe = Hittable::new_mesh(
+        positions,
+        normals,
+        uvs,
+        faces,
+        Material::new_lambertian_with_texture(earth_texture),
+    );
+
+    let ground = Material::new_lambertian(Color::from(0.5, 0.5, 0.5));
+    let corners = [
+        Point::from(-10.0, -1.5, -10.0),
+        Point::from(10.0, -1.5, -10.0),
+        Point::from(10.0, -1.5, 10.0),
+        Point::from(-10.0, -1.5, 10.0),
+    ];
+    let world = vec![
+        globe,
+        Hittable::new_triangle(corners[0], corners[2], corners[1], ground.clone()),
+        Hittable::new_triangle(corners[0], corners[3], corners[2], ground),
+    ];
+    (world, vec![])
+}
+
+pub fn obj_scene() -> (Vec<Hittable>, Vec<Hittable>) {
+    let world = load_obj("assets/icosahedron.obj").unwrap();
+    (world, vec![])
+}
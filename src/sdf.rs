@@ -0,0 +1,459 @@
+use crate::aabb::AxisAlignedBoundingBox;
+use crate::alias::*;
+use crate::hittable::*;
+use crate::material::*;
+use crate::ray::*;
+use std::sync::Arc;
+
+/// Sphere tracing gives up after this many steps.
+const MAX_STEPS: usize = 512;
+
+/// Distance to the surface at which sphere tracing reports a hit.
+const EPSILON: f64 = 1e-5;
+
+/// Step of the central differences that estimate the normal.
+const NORMAL_STEP: f64 = 1e-5;
+
+/// A tree of signed distance functions, negative inside the shape.
+/// Primitives are centered at the origin, with the y axis as their axis of symmetry.
+#[derive(Clone, Debug)]
+pub enum Sdf {
+    Sphere {
+        radius: f64,
+    },
+    Box {
+        half_size: Vector,
+    },
+    /// A box whose edges are rounded off by `radius`, within the same `half_size`.
+    RoundBox {
+        half_size: Vector,
+        radius: f64,
+    },
+    Torus {
+        major_radius: f64,
+        minor_radius: f64,
+    },
+    /// The points within `radius` of the segment from `a` to `b`.
+    Capsule {
+        a: Point,
+        b: Point,
+        radius: f64,
+    },
+    /// Blends two shapes over a distance of `smoothness`.
+    SmoothUnion {
+        left: Box<Sdf>,
+        right: Box<Sdf>,
+        smoothness: f64,
+    },
+    /// Cuts `right` out of `left`, rounding the cut over `smoothness`.
+    SmoothSubtraction {
+        left: Box<Sdf>,
+        right: Box<Sdf>,
+        smoothness: f64,
+    },
+    Translate {
+        offset: Vector,
+        sdf: Box<Sdf>,
+    },
+    /// Repeats the shape in cells of `period` around the origin, a zero component leaves that axis alone.
+    /// The shape has to fit into one cell.
+    Repeat {
+        period: Vector,
+        sdf: Box<Sdf>,
+    },
+    /// Twists the shape around the y axis by `rate` radians per unit of height.
+    Twist {
+        rate: f64,
+        sdf: Box<Sdf>,
+    },
+    /// Bends the shape in the xy plane by `rate` radians per unit along x.
+    Bend {
+        rate: f64,
+        sdf: Box<Sdf>,
+    },
+}
+
+impl Sdf {
+    pub fn new_sphere(radius: f64) -> Self {
+        Self::Sphere { radius }
+    }
+
+    pub fn new_box(half_size: Vector) -> Self {
+        Self::Box { half_size }
+    }
+
+    pub fn new_round_box(half_size: Vector, radius: f64) -> Self {
+        Self::RoundBox { half_size, radius }
+    }
+
+    pub fn new_torus(major_radius: f64, minor_radius: f64) -> Self {
+        Self::Torus {
+            major_radius,
+            minor_radius,
+        }
+    }
+
+    pub fn new_capsule(a: Point, b: Point, radius: f64) -> Self {
+        Self::Capsule { a, b, radius }
+    }
+
+    pub fn new_smooth_union(left: Sdf, right: Sdf, smoothness: f64) -> Self {
+        Self::SmoothUnion {
+            left: Box::new(left),
+            right: Box::new(right),
+            smoothness,
+        }
+    }
+
+    pub fn new_smooth_subtraction(left: Sdf, right: Sdf, smoothness: f64) -> Self {
+        Self::SmoothSubtraction {
+            left: Box::new(left),
+            right: Box::new(right),
+            smoothness,
+        }
+    }
+
+    pub fn new_translate(sdf: Sdf, offset: Vector) -> Self {
+        Self::Translate {
+            offset,
+            sdf: Box::new(sdf),
+        }
+    }
+
+    pub fn new_repeat(sdf: Sdf, period: Vector) -> Self {
+        Self::Repeat {
+            period,
+            sdf: Box::new(sdf),
+        }
+    }
+
+    pub fn new_twist(sdf: Sdf, rate: f64) -> Self {
+        Self::Twist {
+            rate,
+            sdf: Box::new(sdf),
+        }
+    }
+
+    pub fn new_bend(sdf: Sdf, rate: f64) -> Self {
+        Self::Bend {
+            rate,
+            sdf: Box::new(sdf),
+        }
+    }
+
+    pub fn distance(&self, p: &Point) -> f64 {
+        match *self {
+            Self::Sphere { radius } => p.length() - radius,
+            Self::Box { half_size } => box_distance(p, &half_size),
+            Self::RoundBox { half_size, radius } => {
+                box_distance(p, &(half_size - Vector::from_scalar(radius))) - radius
+            }
+            Self::Torus {
+                major_radius,
+                minor_radius,
+            } => {
+                let ring = (p[0] * p[0] + p[2] * p[2]).sqrt() - major_radius;
+                (ring * ring + p[1] * p[1]).sqrt() - minor_radius
+            }
+            Self::Capsule { a, b, radius } => {
+                let (pa, ba) = (*p - a, b - a);
+                let h = (pa.dot(ba) / ba.sqrlen()).clamp(0.0, 1.0);
+                (pa - h * ba).length() - radius
+            }
+            Self::SmoothUnion {
+                ref left,
+                ref right,
+                smoothness,
+            } => {
+                let (a, b) = (left.distance(p), right.distance(p));
+                let h = (0.5 + 0.5 * (b - a) / smoothness).clamp(0.0, 1.0);
+                b + h * (a - b) - smoothness * h * (1.0 - h)
+            }
+            Self::SmoothSubtraction {
+                ref left,
+                ref right,
+                smoothness,
+            } => {
+                let (a, b) = (left.distance(p), right.distance(p));
+                let h = (0.5 - 0.5 * (a + b) / smoothness).clamp(0.0, 1.0);
+                a + h * (-b - a) + smoothness * h * (1.0 - h)
+            }
+            Self::Translate { offset, ref sdf } => sdf.distance(&(*p - offset)),
+            Self::Repeat { period, ref sdf } => {
+                let q = Point::from_map(|i| {
+                    if period[i] > 0.0 {
+                        p[i] - period[i] * (p[i] / period[i]).round()
+                    } else {
+                        p[i]
+                    }
+                });
+                sdf.distance(&q)
+            }
+            Self::Twist { rate, ref sdf } => {
+                let (sin, cos) = (rate * p[1]).sin_cos();
+                let q = Point::from(cos * p[0] - sin * p[2], p[1], sin * p[0] + cos * p[2]);
+                sdf.distance(&q)
+            }
+            Self::Bend { rate, ref sdf } => {
+                let (sin, cos) = (rate * p[0]).sin_cos();
+                let q = Point::from(cos * p[0] - sin * p[1], sin * p[0] + cos * p[1], p[2]);
+                sdf.distance(&q)
+            }
+        }
+    }
+
+    /// An upper bound on how fast the distance changes within `extent` of the origin.
+    /// Twists and bends stretch space, so sphere tracing has to take shorter steps through them.
+    fn lipschitz(&self, extent: f64) -> f64 {
+        match *self {
+            Self::SmoothUnion {
+                ref left,
+                ref right,
+                ..
+            }
+            | Self::SmoothSubtraction {
+                ref left,
+                ref right,
+                ..
+            } => left.lipschitz(extent).max(right.lipschitz(extent)),
+            Self::Translate { offset, ref sdf } => sdf.lipschitz(extent + offset.length()),
+            Self::Repeat { ref sdf, .. } => sdf.lipschitz(extent),
+            Self::Twist { rate, ref sdf } | Self::Bend { rate, ref sdf } => {
+                (1.0 + (rate * extent).powi(2)).sqrt() * sdf.lipschitz(extent)
+            }
+            _ => 1.0,
+        }
+    }
+
+    /// The gradient of the distance by central differences, which is the outward normal.
+    fn normal(&self, p: &Point) -> Vector {
+        Vector::from_map(|i| {
+            let mut h = Vector::new();
+            h[i] = NORMAL_STEP;
+            self.distance(&(*p + h)) - self.distance(&(*p - h))
+        })
+        .normalize()
+    }
+}
+
+fn box_distance(p: &Point, half_size: &Vector) -> f64 {
+    let q = p.map(f64::abs) - *half_size;
+    let outside = q.map(|c| c.max(0.0)).length();
+    let inside = q[0].max(q[1]).max(q[2]).min(0.0);
+    outside + inside
+}
+
+/// A shape given by a signed distance function, found by sphere tracing within declared `bounds`.
+#[derive(Clone)]
+pub struct SdfObject {
+    sdf: Sdf,
+    bounds: AxisAlignedBoundingBox,
+    lipschitz: f64,
+    material: Arc<Material>,
+}
+
+impl SdfObject {
+    pub fn new(
+        sdf: Sdf,
+        bounds: AxisAlignedBoundingBox,
+        material: impl Into<Arc<Material>>,
+    ) -> Self {
+        let extent = bounds
+            .corners()
+            .iter()
+            .map(|corner| corner.length())
+            .fold(0.0, f64::max);
+        Self {
+            lipschitz: sdf.lipschitz(extent),
+            sdf,
+            bounds,
+            material: material.into(),
+        }
+    }
+
+    pub fn bounding_box(&self) -> AxisAlignedBoundingBox {
+        self.bounds.clone()
+    }
+
+    pub fn hit(&self, r: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord<'_>> {
+        // Only the part of the ray within the bounds is traced.
+        let (t_near, t_far) = self.bounds.clip(r, t_min, t_max)?;
+
+        // Steps cover the distance to the surface, so rays from inside find the way out too.
+        let step = 1.0 / (self.lipschitz * r.direction.length());
+        let mut t = t_near;
+        // Rays scattered off the surface start on it, they only hit it once they got away from it.
+        // Rays entering the bounds from outside can't start on the surface.
+        let mut left_start = t_near > t_min;
+        for _ in 0..MAX_STEPS {
+            let p = r.at(t);
+            let distance = self.sdf.distance(&p).abs();
+            if distance < EPSILON && !left_start {
+                t += EPSILON / r.direction.length();
+                if t > t_far {
+                    return None;
+                }
+                continue;
+            }
+            left_start = true;
+            if distance < EPSILON {
+                let normal = self.sdf.normal(&p);
+                return Some(HitRecord::new(
+                    r,
+                    p,
+                    t,
+                    // Without a parametrization, textures are mapped by the direction of the normal.
+                    get_sphere_uv(&normal),
+                    normal,
+                    &self.material,
+                ));
+            }
+            t += distance * step;
+            if t > t_far {
+                return None;
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_case::test_case;
+
+    #[test_case(Sdf::new_sphere(1.0), Point::from(0.0, 3.0, 0.0), 2.0; "sphere")]
+    #[test_case(Sdf::new_box(Vector::from(1.0, 2.0, 3.0)), Point::from(4.0, 6.0, 0.0), 5.0; "box corner")]
+    #[test_case(Sdf::new_box(Vector::from(1.0, 2.0, 3.0)), Point::new(), -1.0; "box inside")]
+    #[test_case(Sdf::new_round_box(Vector::from_scalar(1.0), 0.5), Point::from(2.0, 2.0, 0.0), 4.5f64.sqrt() - 0.5; "round box")]
+    #[test_case(Sdf::new_torus(2.0, 0.5), Point::from(0.0, 1.0, 2.0), 0.5; "torus")]
+    #[test_case(Sdf::new_capsule(Point::new(), Point::from(0.0, 2.0, 0.0), 0.5), Point::from(0.0, 4.0, 0.0), 1.5; "capsule")]
+    #[test_case(Sdf::new_translate(Sdf::new_sphere(1.0), Vector::from(5.0, 0.0, 0.0)), Point::from(5.0, 0.0, 0.0), -1.0; "translate")]
+    #[test_case(Sdf::new_repeat(Sdf::new_sphere(1.0), Vector::from(4.0, 0.0, 0.0)), Point::from(8.0, 2.0, 0.0), 1.0; "repeat")]
+    #[test_case(Sdf::new_twist(Sdf::new_box(Vector::from(2.0, 1.0, 0.5)), std::f64::consts::PI), Point::from(1.5, 0.5, 0.0), 1.0; "twist")]
+    fn test_distance(sdf: Sdf, p: Point, distance: f64) {
+        assert!(
+            (sdf.distance(&p) - distance).abs() < 1e-9,
+            "{}",
+            sdf.distance(&p)
+        );
+    }
+
+    #[test]
+    fn test_smooth_operations() {
+        let a = Sdf::new_sphere(1.0);
+        let b = Sdf::new_translate(Sdf::new_sphere(1.0), Vector::from(1.5, 0.0, 0.0));
+        let union = Sdf::new_smooth_union(a.clone(), b.clone(), 0.5);
+        let subtraction = Sdf::new_smooth_subtraction(a.clone(), b.clone(), 0.5);
+        for x in [-2.0, -0.5, 0.75, 1.0, 3.0] {
+            let p = Point::from(x, 0.3, 0.0);
+            let (a, b) = (a.distance(&p), b.distance(&p));
+            // The blend only ever adds material between the shapes, and only removes it near the cut.
+            assert!(union.distance(&p) <= a.min(b) + 1e-12);
+            assert!(subtraction.distance(&p) >= a.max(-b) - 1e-12);
+        }
+        // Far from the other shape, the blend leaves a shape unchanged.
+        let p = Point::from(-3.0, 0.0, 0.0);
+        assert!((union.distance(&p) - 2.0).abs() < 1e-12);
+        assert!((subtraction.distance(&p) - 2.0).abs() < 1e-12);
+    }
+
+    fn sdf_sphere() -> Hittable {
+        let bounds = AxisAlignedBoundingBox::new(Point::from_scalar(-1.0), Point::from_scalar(1.0));
+        Hittable::new_sdf(
+            Sdf::new_sphere(1.0),
+            bounds,
+            Material::new_lambertian(Color::new()),
+        )
+    }
+
+    #[test_case(Point::from(0.0, 0.0, 5.0), Vector::from(0.0, 0.0, -2.0))]
+    #[test_case(Point::from(3.0, 4.0, 0.0), Vector::from(-0.6, -0.8, 0.1))]
+    #[test_case(Point::from(-2.0, 0.5, 0.5), Vector::from(1.0, 0.0, 0.0))]
+    fn test_sphere_tracing_matches_sphere(origin: Point, direction: Vector) {
+        let mut rng = rand::thread_rng();
+        let r = Ray::new(origin, direction, 0.0);
+        let sphere =
+            Hittable::new_sphere(Point::new(), 1.0, Material::new_lambertian(Color::new()));
+        let expected = sphere.hit(&r, 0.001, f64::INFINITY, &mut rng).unwrap();
+        let sdf = sdf_sphere();
+        let hit = sdf.hit(&r, 0.001, f64::INFINITY, &mut rng).unwrap();
+        assert!((hit.p - expected.p).length() < 1e-4);
+        assert!((hit.normal - expected.normal).length() < 1e-4);
+        assert!(hit.front_face);
+    }
+
+    #[test]
+    fn test_ray_from_inside_leaves() {
+        let r = Ray::new(Point::new(), Vector::from(0.0, 1.0, 0.0), 0.0);
+        let sdf = sdf_sphere();
+        let hit = sdf
+            .hit(&r, 0.001, f64::INFINITY, &mut rand::thread_rng())
+            .unwrap();
+        assert!((hit.t - 1.0).abs() < 1e-4);
+        assert!(!hit.front_face);
+        assert!((hit.normal - Vector::from(0.0, -1.0, 0.0)).length() < 1e-4);
+    }
+
+    #[test]
+    fn test_grazing_ray_from_the_surface_escapes() {
+        let mut rng = rand::thread_rng();
+        let bounds = AxisAlignedBoundingBox::new(Point::from_scalar(-2.0), Point::from_scalar(2.0));
+        let sdf = Hittable::new_sdf(
+            Sdf::new_sphere(1.0),
+            bounds,
+            Material::new_lambertian(Color::new()),
+        );
+        let origin = Point::from(0.0, 1.0, 0.0);
+        let r = Ray::new(origin, Vector::from(1.0, 0.001, 0.0), 0.0);
+        assert!(sdf.hit(&r, 0.001, f64::INFINITY, &mut rng).is_none());
+
+        // A ray into the object still finds its other side.
+        let r = Ray::new(origin, Vector::from(0.0, -1.0, 0.0), 0.0);
+        let hit = sdf.hit(&r, 0.001, f64::INFINITY, &mut rng).unwrap();
+        assert!((hit.t - 2.0).abs() < 1e-4, "{}", hit.t);
+    }
+
+    #[test]
+    fn test_bounds_clip_the_shape() {
+        // Repeated spheres only show up within the bounds.
+        let bounds =
+            AxisAlignedBoundingBox::new(Point::from(-1.0, -1.0, -1.0), Point::from(5.0, 1.0, 1.0));
+        let spheres = Hittable::new_sdf(
+            Sdf::new_repeat(Sdf::new_sphere(0.5), Vector::from(2.0, 0.0, 0.0)),
+            bounds,
+            Material::new_lambertian(Color::new()),
+        );
+        let mut rng = rand::thread_rng();
+        for (x, hit) in [(-2.0, false), (0.0, true), (4.0, true), (6.0, false)] {
+            let r = Ray::new(Point::from(x, 5.0, 0.0), Vector::from(0.0, -1.0, 0.0), 0.0);
+            let result = spheres.hit(&r, 0.001, f64::INFINITY, &mut rng);
+            assert_eq!(result.is_some(), hit, "{}", x);
+        }
+        let aabb = spheres.bounding_box(0.0, 1.0).unwrap();
+        assert_eq!(aabb.maximum[0], 5.0);
+    }
+
+    #[test]
+    fn test_twist_steps_are_conservative() {
+        // A strongly twisted slab is thin at its edges, overshooting steps would tunnel through it.
+        let slab = Sdf::new_twist(Sdf::new_box(Vector::from(2.0, 2.0, 0.05)), 2.0);
+        let bounds = AxisAlignedBoundingBox::new(Point::from_scalar(-2.5), Point::from_scalar(2.5));
+        let hittable =
+            Hittable::new_sdf(slab.clone(), bounds, Material::new_lambertian(Color::new()));
+        let mut rng = rand::thread_rng();
+        for i in 0..200 {
+            // Rays climb along the twist, where it stretches space the most.
+            let x = -2.0 + 4.0 * i as f64 / 199.0;
+            let r = Ray::new(Point::from(x, -2.5, 0.5), Vector::from(0.0, 1.0, -0.2), 0.0);
+            let t_end = hittable
+                .hit(&r, 0.001, f64::INFINITY, &mut rng)
+                .map_or(5.0, |hit| hit.t);
+            // No point along the ray before the hit lies inside the slab.
+            let inside = (0..1000)
+                .map(|j| t_end * j as f64 / 1000.0)
+                .any(|t| slab.distance(&r.at(t)) < -1e-3);
+            assert!(!inside, "{}", x);
+        }
+    }
+}
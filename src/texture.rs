@@ -1,21 +1,168 @@
 use crate::alias::*;
-use image::io::Reader as ImageReader;
+use crate::input::*;
+use crate::mipmap::*;
+use crate::perlin::*;
+use crate::worley::*;
 use image::ImageResult;
-use image::RgbImage;
+use rand::SeedableRng;
+use rand_pcg::Pcg64Mcg;
 use std::path::Path;
+use std::sync::Arc;
 
+/// Octaves of a fractal noise, every octave multiplies the frequency by `lacunarity`
+/// and the amplitude by `gain`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Fractal {
+    pub octaves: u32,
+    pub lacunarity: f64,
+    pub gain: f64,
+}
+
+impl Default for Fractal {
+    fn default() -> Self {
+        Self {
+            octaves: 6,
+            lacunarity: 2.0,
+            gain: 0.5,
+        }
+    }
+}
+
+/// Maps texture coordinates before an image lookup. They are scaled first,
+/// then rotated counterclockwise by `rotation` radians and finally offset.
+#[derive(Clone, Copy, Debug)]
+pub struct UvTransform {
+    pub scale: Vec2<f64>,
+    pub rotation: f64,
+    pub offset: Vec2<f64>,
+}
+
+impl Default for UvTransform {
+    fn default() -> Self {
+        Self {
+            scale: Vec2::from(1.0, 1.0),
+            rotation: 0.0,
+            offset: Vec2::from(0.0, 0.0),
+        }
+    }
+}
+
+impl UvTransform {
+    pub fn apply(&self, uv: &Vec2<f64>) -> Vec2<f64> {
+        self.apply_vector(uv) + self.offset
+    }
+
+    /// Transforms a difference of texture coordinates, which ignores the offset.
+    pub fn apply_vector(&self, uv: &Vec2<f64>) -> Vec2<f64> {
+        let (sin, cos) = self.rotation.sin_cos();
+        let scaled = *uv * self.scale;
+        Vec2::from(
+            cos * scaled[0] - sin * scaled[1],
+            sin * scaled[0] + cos * scaled[1],
+        )
+    }
+}
+
+/// How an image texture is sampled.
+#[derive(Clone, Copy, Debug)]
+pub struct ImageOptions {
+    pub filter: ImageFilter,
+    pub wrap: WrapMode,
+    /// Color outside of the image with `WrapMode::Border`.
+    pub border: Color,
+    pub transform: UvTransform,
+    /// The image holds data such as roughness or normals instead of colors and is not sRGB decoded.
+    pub linear: bool,
+    /// Loads the alpha channel as a gray image instead of the colors, for opacity textures.
+    pub alpha: bool,
+}
+
+impl Default for ImageOptions {
+    fn default() -> Self {
+        Self {
+            filter: ImageFilter::Trilinear,
+            wrap: WrapMode::Clamp,
+            border: Color::new(),
+            transform: UvTransform::default(),
+            linear: false,
+            alpha: false,
+        }
+    }
+}
+
+/// Textures are cheap to clone, nested textures and images are shared.
 #[derive(Clone)]
 pub enum Texture {
     Solid(Color),
-    Checker((Box<Texture>, Box<Texture>)),
-    Image(RgbImage),
+    Checker((Arc<Texture>, Arc<Texture>)),
+    Image {
+        mipmap: Arc<MipMap>,
+        filter: ImageFilter,
+        transform: UvTransform,
+    },
     Normal,
     UV,
+    /// Gray Perlin noise, `scale` is the frequency of the noise.
+    Noise {
+        perlin: Arc<Perlin>,
+        scale: f64,
+    },
+    Turbulence {
+        perlin: Arc<Perlin>,
+        scale: f64,
+        depth: u32,
+    },
+    /// Sine bands along the z axis, distorted by turbulence.
+    Marble {
+        perlin: Arc<Perlin>,
+        scale: f64,
+        depth: u32,
+    },
+    /// Fractional Brownian motion, soft cloud like noise.
+    Fbm {
+        perlin: Arc<Perlin>,
+        scale: f64,
+        fractal: Fractal,
+    },
+    /// Ridged multifractal, sharp mountain ridge or crack like noise.
+    Ridged {
+        perlin: Arc<Perlin>,
+        scale: f64,
+        fractal: Fractal,
+        offset: f64,
+    },
+    /// Gray Worley noise, `scale` is the number of cells per unit.
+    Cells {
+        worley: Worley,
+        scale: f64,
+        feature: CellFeature,
+        distance: CellDistance,
+    },
+    /// Growth rings around the y axis, blending from `light` early wood to `dark` late wood.
+    /// `distortion` is the amplitude of the noise bending the rings, in rings.
+    Wood {
+        perlin: Arc<Perlin>,
+        rings: f64,
+        scale: f64,
+        distortion: f64,
+        light: Arc<Texture>,
+        dark: Arc<Texture>,
+    },
+    /// Maps the luminance of `input` onto a gradient through the color `stops`, sorted by position.
+    Ramp {
+        input: Arc<Texture>,
+        stops: Arc<[(f64, Color)]>,
+    },
+}
+
+/// Perlin noise with a fixed seed, so every render of a scene shows the same pattern.
+fn seeded_perlin(seed: u64) -> Arc<Perlin> {
+    Arc::new(Perlin::new(&mut Pcg64Mcg::seed_from_u64(seed)))
 }
 
 impl Texture {
     pub fn new_checker(odd: Texture, even: Texture) -> Self {
-        Self::Checker((Box::new(odd), Box::new(even)))
+        Self::Checker((Arc::new(odd), Arc::new(even)))
     }
 
     pub fn new_checker_color(odd: Color, even: Color) -> Self {
@@ -26,45 +173,310 @@ impl Texture {
     where
         P: AsRef<Path>,
     {
-        let img = ImageReader::open(path)?.decode()?;
-        Ok(Self::Image(img.to_rgb8()))
+        Self::new_image_with_options(path, ImageOptions::default())
+    }
+
+    pub fn new_image_with_options<P>(path: P, options: ImageOptions) -> ImageResult<Self>
+    where
+        P: AsRef<Path>,
+    {
+        let image = if options.alpha {
+            load_alpha(path)?
+        } else {
+            load_image(path, options.linear)?
+        };
+        Ok(Self::from_image(image, options))
     }
 
-    pub fn value(&self, uv: &Vec2<f64>, p: &Point, normal: &Vector) -> Color {
+    pub fn from_image(image: LinearImage, options: ImageOptions) -> Self {
+        Self::Image {
+            mipmap: Arc::new(MipMap::new(image, options.wrap, options.border)),
+            filter: options.filter,
+            transform: options.transform,
+        }
+    }
+
+    pub fn new_noise(scale: f64, seed: u64) -> Self {
+        Self::Noise {
+            perlin: seeded_perlin(seed),
+            scale,
+        }
+    }
+
+    pub fn new_turbulence(scale: f64, depth: u32, seed: u64) -> Self {
+        Self::Turbulence {
+            perlin: seeded_perlin(seed),
+            scale,
+            depth,
+        }
+    }
+
+    pub fn new_marble(scale: f64, depth: u32, seed: u64) -> Self {
+        Self::Marble {
+            perlin: seeded_perlin(seed),
+            scale,
+            depth,
+        }
+    }
+
+    pub fn new_fbm(scale: f64, fractal: Fractal, seed: u64) -> Self {
+        Self::Fbm {
+            perlin: seeded_perlin(seed),
+            scale,
+            fractal,
+        }
+    }
+
+    /// Ridged multifractal noise, an `offset` of 1 puts the ridges at the zero crossings of the noise.
+    pub fn new_ridged(scale: f64, fractal: Fractal, offset: f64, seed: u64) -> Self {
+        Self::Ridged {
+            perlin: seeded_perlin(seed),
+            scale,
+            fractal,
+            offset,
+        }
+    }
+
+    pub fn new_cells(scale: f64, feature: CellFeature, distance: CellDistance, seed: u64) -> Self {
+        Self::Cells {
+            worley: Worley::new(seed),
+            scale,
+            feature,
+            distance,
+        }
+    }
+
+    pub fn new_wood(
+        rings: f64,
+        scale: f64,
+        distortion: f64,
+        light: Texture,
+        dark: Texture,
+        seed: u64,
+    ) -> Self {
+        Self::Wood {
+            perlin: seeded_perlin(seed),
+            rings,
+            scale,
+            distortion,
+            light: Arc::new(light),
+            dark: Arc::new(dark),
+        }
+    }
+
+    /// `stops` pairs positions with colors, values outside of the stops take the nearest color.
+    pub fn new_ramp(input: Texture, mut stops: Vec<(f64, Color)>) -> Self {
+        assert!(!stops.is_empty(), "a color ramp needs at least one stop");
+        stops.sort_by(|a, b| a.0.total_cmp(&b.0));
+        Self::Ramp {
+            input: Arc::new(input),
+            stops: stops.into(),
+        }
+    }
+
+    /// The color at a hit, `footprint` is the width of the area covered by the lookup
+    /// in texture coordinates, zero for a point sample.
+    pub fn value(&self, uv: &Vec2<f64>, p: &Point, normal: &Vector, footprint: f64) -> Color {
         match *self {
             Self::Solid(ref color) => *color,
             Self::Checker((ref odd, ref even)) => {
                 let scale = 10.0;
                 let sines = (scale * p[0]).sin() * (scale * p[1]).sin() * (scale * p[2]).sin();
                 if sines < 0.0 {
-                    odd.value(uv, p, normal)
+                    odd.value(uv, p, normal, footprint)
                 } else {
-                    even.value(uv, p, normal)
+                    even.value(uv, p, normal, footprint)
                 }
             }
-            Self::Image(ref image) => {
-                // Clamp input texture coordinates to [0,1] x [1,0]
-                let mut uv = uv.map(|i| i.max(0.0).min(1.0));
-                uv[1] = 1.0 - uv[1]; // Flip V to image coordinates
-
-                let x = (uv[0] * image.width() as f64) as u32;
-                let y = (uv[1] * image.height() as f64) as u32;
-
-                // Clamp integer mapping, since actual coordinates should be less than 1.0
-                let x = x.min(image.width() - 1);
-                let y = y.min(image.height() - 1);
-
-                let color_scale = 1.0 / 255.0;
-                let pixel = image.get_pixel(x, y);
-
-                Color::from(
-                    pixel[0] as f64 * color_scale,
-                    pixel[1] as f64 * color_scale,
-                    pixel[2] as f64 * color_scale,
-                )
+            Self::Image {
+                ref mipmap,
+                filter,
+                ref transform,
+            } => {
+                let uv = transform.apply(uv);
+                // Images are stored top to bottom, v runs bottom to top.
+                let st = Vec2::from(uv[0], 1.0 - uv[1]);
+                // The footprint of a ray cone is a circle, the texture transform
+                // turns it into an ellipse.
+                let radius = 0.5 * footprint;
+                let axes = [Vec2::from(radius, 0.0), Vec2::from(0.0, radius)].map(|axis| {
+                    let axis = transform.apply_vector(&axis);
+                    Vec2::from(axis[0], -axis[1])
+                });
+                mipmap.lookup(st, axes, filter)
             }
             Self::Normal => normal.map(|i| i.abs()),
             Self::UV => Vector::from(uv[0], uv[1], 0.0),
+            Self::Noise { ref perlin, scale } => {
+                Color::from_scalar(0.5 * (1.0 + perlin.noise(&(scale * *p))))
+            }
+            Self::Turbulence {
+                ref perlin,
+                scale,
+                depth,
+            } => Color::from_scalar(perlin.turbulence(&(scale * *p), depth)),
+            Self::Marble {
+                ref perlin,
+                scale,
+                depth,
+            } => Color::from_scalar(
+                0.5 * (1.0 + (scale * p[2] + 10.0 * perlin.turbulence(p, depth)).sin()),
+            ),
+            Self::Fbm {
+                ref perlin,
+                scale,
+                fractal,
+            } => {
+                let value = perlin.fbm(
+                    &(scale * *p),
+                    fractal.octaves,
+                    fractal.lacunarity,
+                    fractal.gain,
+                );
+                Color::from_scalar(0.5 * (1.0 + value))
+            }
+            Self::Ridged {
+                ref perlin,
+                scale,
+                fractal,
+                offset,
+            } => Color::from_scalar(perlin.ridged(
+                &(scale * *p),
+                fractal.octaves,
+                fractal.lacunarity,
+                fractal.gain,
+                offset,
+            )),
+            Self::Cells {
+                ref worley,
+                scale,
+                feature,
+                distance,
+            } => Color::from_scalar(worley.value(&(scale * *p), feature, distance)),
+            Self::Wood {
+                ref perlin,
+                rings,
+                scale,
+                distortion,
+                ref light,
+                ref dark,
+            } => {
+                let radius = (p[0] * p[0] + p[2] * p[2]).sqrt();
+                let ring = rings * radius + distortion * perlin.noise(&(scale * *p));
+                let t = ring - ring.floor();
+                (1.0 - t) * light.value(uv, p, normal, footprint)
+                    + t * dark.value(uv, p, normal, footprint)
+            }
+            Self::Ramp {
+                ref input,
+                ref stops,
+            } => ramp(stops, luminance(&input.value(uv, p, normal, footprint))),
+        }
+    }
+}
+
+pub(crate) fn luminance(color: &Color) -> f64 {
+    0.2126 * color[0] + 0.7152 * color[1] + 0.0722 * color[2]
+}
+
+/// Interpolates linearly between the two stops around `x`.
+fn ramp(stops: &[(f64, Color)], x: f64) -> Color {
+    let next = stops.partition_point(|&(position, _)| position <= x);
+    if next == 0 {
+        return stops[0].1;
+    }
+    if next == stops.len() {
+        return stops[next - 1].1;
+    }
+    let (x0, c0) = stops[next - 1];
+    let (x1, c1) = stops[next];
+    let t = (x - x0) / (x1 - x0);
+    (1.0 - t) * c0 + t * c1
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_case::test_case;
+
+    #[test_case(-1.0, Color::from(1.0, 0.0, 0.0))]
+    #[test_case(0.0, Color::from(1.0, 0.0, 0.0))]
+    #[test_case(0.25, Color::from(0.5, 0.5, 0.0))]
+    #[test_case(0.5, Color::from(0.0, 1.0, 0.0))]
+    #[test_case(0.75, Color::from(0.0, 0.5, 0.5))]
+    #[test_case(2.0, Color::from(0.0, 0.0, 1.0))]
+    fn test_ramp(x: f64, result: Color) {
+        let stops = [
+            (0.0, Color::from(1.0, 0.0, 0.0)),
+            (0.5, Color::from(0.0, 1.0, 0.0)),
+            (1.0, Color::from(0.0, 0.0, 1.0)),
+        ];
+        assert!((ramp(&stops, x) - result).near_zero());
+    }
+
+    #[test]
+    fn test_ramp_maps_nested_textures() {
+        let checker = Texture::new_checker_color(Color::from_scalar(0.0), Color::from_scalar(1.0));
+        let stops = vec![
+            (1.0, Color::from(0.0, 0.0, 1.0)),
+            (0.0, Color::from(1.0, 0.0, 0.0)),
+        ];
+        let ramp = Texture::new_ramp(checker, stops);
+        let uv = Vec2::from(0.0, 0.0);
+        let normal = Vector::from(0.0, 1.0, 0.0);
+        // sin(1) * sin(1) * sin(-1) < 0 picks the odd, black texture.
+        let odd = ramp.value(&uv, &Point::from(0.1, 0.1, -0.1), &normal, 0.0);
+        let even = ramp.value(&uv, &Point::from(0.1, 0.1, 0.1), &normal, 0.0);
+        assert!((odd - Color::from(1.0, 0.0, 0.0)).near_zero());
+        assert!((even - Color::from(0.0, 0.0, 1.0)).near_zero());
+    }
+
+    #[test]
+    fn test_uv_transform() {
+        let transform = UvTransform {
+            scale: Vec2::from(2.0, 3.0),
+            rotation: 90.0f64.to_radians(),
+            offset: Vec2::from(0.5, 0.0),
+        };
+        let uv = transform.apply(&Vec2::from(0.25, 0.0));
+        assert!((uv - Vec2::from(0.5, 0.5)).length() < 1e-12);
+        let uv = transform.apply_vector(&Vec2::from(0.0, 1.0));
+        assert!((uv - Vec2::from(-3.0, 0.0)).length() < 1e-12);
+    }
+
+    #[test_case(WrapMode::Repeat, 0.0)]
+    #[test_case(WrapMode::Mirror, 1.0)]
+    #[test_case(WrapMode::Clamp, 1.0)]
+    fn test_scaled_image_tiles(wrap: WrapMode, result: f64) {
+        // Black on the left, white on the right.
+        let image = LinearImage::from_fn(2, 2, |x, _| image::Rgb([x as f32; 3]));
+        let options = ImageOptions {
+            filter: ImageFilter::Nearest,
+            wrap,
+            transform: UvTransform {
+                scale: Vec2::from(4.0, 4.0),
+                ..UvTransform::default()
+            },
+            ..ImageOptions::default()
+        };
+        let texture = Texture::from_image(image, options);
+        let normal = Vector::from(0.0, 1.0, 0.0);
+        // The left quarter of the second tile.
+        let color = texture.value(&Vec2::from(0.3, 0.5), &Point::new(), &normal, 0.0);
+        assert!((color - Color::from_scalar(result)).near_zero());
+    }
+
+    #[test]
+    fn test_wood_rings() {
+        let light = Texture::Solid(Color::from_scalar(1.0));
+        let dark = Texture::Solid(Color::from_scalar(0.0));
+        let wood = Texture::new_wood(4.0, 1.0, 0.0, light, dark, 0);
+        let uv = Vec2::from(0.0, 0.0);
+        let normal = Vector::from(0.0, 1.0, 0.0);
+        // A quarter into the second ring, independent of the height.
+        for y in [-3.0, 0.0, 5.0] {
+            let color = wood.value(&uv, &Point::from(0.0, y, 0.3125), &normal, 0.0);
+            assert!((color - Color::from_scalar(0.75)).near_zero());
         }
     }
 }
@@ -0,0 +1,186 @@
+use crate::alias::*;
+use serde::Deserialize;
+use std::fmt;
+use std::str::FromStr;
+
+/// Curves that compress scene radiance into the displayable range [0,1].
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ToneMapOperator {
+    /// Cuts off everything above 1.
+    Clamp,
+    /// `c / (1 + c)`, never reaches white.
+    Reinhard,
+    /// Reinhard scaled so that `white_point` maps to 1.
+    ExtendedReinhard,
+    /// John Hable's filmic curve from Uncharted 2.
+    Hable,
+    /// Stephen Hill's fit of the ACES reference rendering and output transforms.
+    Aces,
+}
+
+impl FromStr for ToneMapOperator {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "clamp" => Ok(Self::Clamp),
+            "reinhard" => Ok(Self::Reinhard),
+            "extended_reinhard" => Ok(Self::ExtendedReinhard),
+            "hable" => Ok(Self::Hable),
+            "aces" => Ok(Self::Aces),
+            _ => Err(format!("unknown tone mapping operator '{}'", s)),
+        }
+    }
+}
+
+impl fmt::Display for ToneMapOperator {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            Self::Clamp => "clamp",
+            Self::Reinhard => "reinhard",
+            Self::ExtendedReinhard => "extended_reinhard",
+            Self::Hable => "hable",
+            Self::Aces => "aces",
+        })
+    }
+}
+
+/// Turns linear radiance into display values: exposure, tone curve and sRGB encoding.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ToneMapping {
+    /// Exposure adjustment in stops (EV), every stop doubles the brightness.
+    pub exposure: f64,
+    pub operator: ToneMapOperator,
+    /// The smallest radiance mapped to white by `ExtendedReinhard`.
+    pub white_point: f64,
+}
+
+impl Default for ToneMapping {
+    fn default() -> Self {
+        Self {
+            exposure: 0.0,
+            operator: ToneMapOperator::Clamp,
+            white_point: 4.0,
+        }
+    }
+}
+
+impl ToneMapping {
+    /// Maps linear radiance to linear display values in [0,1].
+    pub fn tone_map(&self, color: Color) -> Color {
+        let color = color * 2.0f64.powf(self.exposure);
+        let mapped = match self.operator {
+            ToneMapOperator::Clamp => color,
+            ToneMapOperator::Reinhard => color.map(|c| c / (1.0 + c)),
+            ToneMapOperator::ExtendedReinhard => {
+                let white_squared = self.white_point * self.white_point;
+                color.map(|c| c * (1.0 + c / white_squared) / (1.0 + c))
+            }
+            ToneMapOperator::Hable => {
+                let exposure_bias = 2.0;
+                let white_scale = 1.0 / hable_partial(11.2);
+                color.map(|c| hable_partial(c * exposure_bias) * white_scale)
+            }
+            ToneMapOperator::Aces => aces_fitted(color),
+        };
+        mapped.map(|c| c.clamp(0.0, 1.0))
+    }
+
+    /// Maps linear radiance to sRGB encoded display values in [0,1].
+    pub fn apply(&self, color: Color) -> Color {
+        self.tone_map(color).map(srgb_oetf)
+    }
+}
+
+fn hable_partial(x: f64) -> f64 {
+    let a = 0.15; // Shoulder strength
+    let b = 0.50; // Linear strength
+    let c = 0.10; // Linear angle
+    let d = 0.20; // Toe strength
+    let e = 0.02; // Toe numerator
+    let f = 0.30; // Toe denominator
+    ((x * (a * x + c * b) + d * e) / (x * (a * x + b) + d * f)) - e / f
+}
+
+fn aces_fitted(color: Color) -> Color {
+    // sRGB => XYZ => D65_2_D60 => AP1 => RRT_SAT
+    let input = [
+        [0.59719, 0.35458, 0.04823],
+        [0.07600, 0.90834, 0.01566],
+        [0.02840, 0.13383, 0.83777],
+    ];
+    // ODT_SAT => XYZ => D60_2_D65 => sRGB
+    let output = [
+        [1.60475, -0.53108, -0.07367],
+        [-0.10208, 1.10813, -0.00605],
+        [-0.00327, -0.07276, 1.07602],
+    ];
+    let multiply = |m: &[[f64; 3]; 3], c: Color| {
+        Color::from_map(|i| m[i][0] * c[0] + m[i][1] * c[1] + m[i][2] * c[2])
+    };
+
+    // Reference rendering and output device transforms.
+    let rrt_and_odt_fit = |v: f64| {
+        let a = v * (v + 0.0245786) - 0.000090537;
+        let b = v * (0.983729 * v + 0.4329510) + 0.238081;
+        a / b
+    };
+    multiply(&output, multiply(&input, color).map(rrt_and_odt_fit))
+}
+
+/// The sRGB opto-electronic transfer function, encodes a linear value in [0,1].
+pub fn srgb_oetf(linear: f64) -> f64 {
+    if linear <= 0.0031308 {
+        12.92 * linear
+    } else {
+        1.055 * linear.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+/// The sRGB electro-optical transfer function, decodes an encoded value in [0,1] to linear light.
+pub fn srgb_eotf(encoded: f64) -> f64 {
+    if encoded <= 0.04045 {
+        encoded / 12.92
+    } else {
+        ((encoded + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_case::test_case;
+
+    #[test_case(0.0, 0.0)]
+    #[test_case(0.0031308, 0.040449936)]
+    #[test_case(0.5, 0.7353569830524495)]
+    #[test_case(1.0, 1.0)]
+    fn test_srgb_oetf(linear: f64, encoded: f64) {
+        assert!((srgb_oetf(linear) - encoded).abs() < 1e-6);
+        assert!((srgb_eotf(srgb_oetf(linear)) - linear).abs() < 1e-12);
+    }
+
+    #[test_case(ToneMapOperator::Clamp)]
+    #[test_case(ToneMapOperator::Reinhard)]
+    #[test_case(ToneMapOperator::ExtendedReinhard)]
+    #[test_case(ToneMapOperator::Hable)]
+    #[test_case(ToneMapOperator::Aces)]
+    fn test_tone_map_is_monotonic(operator: ToneMapOperator) {
+        let tone_mapping = ToneMapping {
+            operator,
+            ..ToneMapping::default()
+        };
+        assert!(tone_mapping
+            .tone_map(Color::new())
+            .map(|c| c.abs() < 1e-2)
+            .all());
+
+        let mut previous = 0.0;
+        for i in 1..100 {
+            let gray = tone_mapping.tone_map(Color::from_scalar(i as f64 * 0.1))[1];
+            assert!(gray >= previous && gray <= 1.0);
+            previous = gray;
+        }
+    }
+}
@@ -0,0 +1,159 @@
+use crate::alias::*;
+use crate::sampler::hash;
+use serde::Deserialize;
+
+/// How the distance to a feature point is measured.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CellDistance {
+    /// Round cells.
+    Euclidean,
+    /// Diamond shaped cells.
+    Manhattan,
+    /// Square cells.
+    Chebyshev,
+}
+
+impl CellDistance {
+    fn measure(&self, d: Vector) -> f64 {
+        match *self {
+            Self::Euclidean => d.length(),
+            Self::Manhattan => d[0].abs() + d[1].abs() + d[2].abs(),
+            Self::Chebyshev => d[0].abs().max(d[1].abs()).max(d[2].abs()),
+        }
+    }
+}
+
+/// Which distances of a Worley noise make up its value.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CellFeature {
+    /// Distance to the nearest feature point, dark spots in bright cells.
+    F1,
+    /// Distance to the second nearest feature point.
+    F2,
+    /// Zero on the cell borders, a network of cracks.
+    F2MinusF1,
+}
+
+/// Worley (cellular) noise with one random feature point in every unit cell.
+/// The points are hashed from the cell and the seed, so no tables are stored.
+#[derive(Clone, Copy, Debug)]
+pub struct Worley {
+    seed: u64,
+}
+
+impl Worley {
+    pub fn new(seed: u64) -> Self {
+        Self { seed }
+    }
+
+    fn feature_point(&self, cell: [i64; 3]) -> Point {
+        let h = hash(&[self.seed, cell[0] as u64, cell[1] as u64, cell[2] as u64]);
+        // 21 bits for each coordinate.
+        let unit = |shift: u32| ((h >> shift) & 0x1f_ffff) as f64 / (1u64 << 21) as f64;
+        Point::from(
+            cell[0] as f64 + unit(0),
+            cell[1] as f64 + unit(21),
+            cell[2] as f64 + unit(42),
+        )
+    }
+
+    /// Distances from `p` to the nearest and the second nearest feature point.
+    pub fn distances(&self, p: &Point, distance: CellDistance) -> (f64, f64) {
+        let cell = Point::from_map(|i| p[i].floor());
+        // Every metric is at least the largest coordinate difference, so the points in the
+        // cells `ring` steps away are at least `ring - 1` plus the gap to the nearest cell face away.
+        let gap = (0..3)
+            .map(|i| (p[i] - cell[i]).min(cell[i] + 1.0 - p[i]))
+            .fold(f64::INFINITY, f64::min);
+        let cell = cell.map(|c| c as i64);
+        let mut f1 = f64::INFINITY;
+        let mut f2 = f64::INFINITY;
+        let mut ring = 0i64;
+        while ring == 0 || (ring - 1) as f64 + gap < f2 {
+            for i in -ring..=ring {
+                for j in -ring..=ring {
+                    for k in -ring..=ring {
+                        // Only the shell, the inner cells were visited before.
+                        if i.abs().max(j.abs()).max(k.abs()) != ring {
+                            continue;
+                        }
+                        let point = self.feature_point([cell[0] + i, cell[1] + j, cell[2] + k]);
+                        let d = distance.measure(point - *p);
+                        if d < f1 {
+                            f2 = f1;
+                            f1 = d;
+                        } else if d < f2 {
+                            f2 = d;
+                        }
+                    }
+                }
+            }
+            ring += 1;
+        }
+        (f1, f2)
+    }
+
+    pub fn value(&self, p: &Point, feature: CellFeature, distance: CellDistance) -> f64 {
+        let (f1, f2) = self.distances(p, distance);
+        match feature {
+            CellFeature::F1 => f1,
+            CellFeature::F2 => f2,
+            CellFeature::F2MinusF1 => f2 - f1,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+    use test_case::test_case;
+
+    #[test_case(CellDistance::Euclidean)]
+    #[test_case(CellDistance::Manhattan)]
+    #[test_case(CellDistance::Chebyshev)]
+    fn test_feature_points_have_zero_distance(distance: CellDistance) {
+        let worley = Worley::new(3);
+        for cell in [[0, 0, 0], [-2, 5, 1], [7, -3, -9]] {
+            let p = worley.feature_point(cell);
+            assert_eq!(worley.value(&p, CellFeature::F1, distance), 0.0);
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn test_distances_are_ordered(x in -50.0f64..50.0, y in -50.0f64..50.0, z in -50.0f64..50.0) {
+            let worley = Worley::new(3);
+            let p = Point::from(x, y, z);
+            for distance in [CellDistance::Euclidean, CellDistance::Manhattan, CellDistance::Chebyshev] {
+                let (f1, f2) = worley.distances(&p, distance);
+                prop_assert!(0.0 <= f1 && f1 <= f2);
+            }
+            // The feature point of the cell containing `p` is at most a cell diagonal away.
+            prop_assert!(worley.distances(&p, CellDistance::Euclidean).0 <= 3.0f64.sqrt());
+        }
+
+        #[test]
+        fn test_distances_match_brute_force(x in -50.0f64..50.0, y in -50.0f64..50.0, z in -50.0f64..50.0) {
+            let worley = Worley::new(3);
+            let p = Point::from(x, y, z);
+            let cell = [x.floor() as i64, y.floor() as i64, z.floor() as i64];
+            for distance in [CellDistance::Euclidean, CellDistance::Manhattan, CellDistance::Chebyshev] {
+                // The second nearest point is never more than four cells away.
+                let mut all = Vec::new();
+                for i in -5..=5 {
+                    for j in -5..=5 {
+                        for k in -5..=5 {
+                            let point = worley.feature_point([cell[0] + i, cell[1] + j, cell[2] + k]);
+                            all.push(distance.measure(point - p));
+                        }
+                    }
+                }
+                all.sort_by(|a, b| a.total_cmp(b));
+                prop_assert_eq!(worley.distances(&p, distance), (all[0], all[1]));
+            }
+        }
+    }
+}